@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use transactions::block::Block;
+use transactions::blockchain::Blockchain;
+use transactions::chain_params::ChainParams;
+use transactions::global_utxos::GlobalUtxos;
+use transactions::miner::Miner;
+use transactions::sig_cache::SignatureCache;
+use transactions::transactions::Tx;
+use transactions::wallet::Wallet;
+
+const PAYERS: usize = 200;
+const BLOCKS: usize = 4;
+
+fn mine(chain: &mut Blockchain, address: [u8;32], transactions: Vec<Tx>) {
+    let miner = Miner { address, threads: 1, extra_data: vec![] };
+    let block = miner.build_block(chain.get_height() + 1, chain.get_current_hash(), transactions, 0, chain);
+    chain.connect_block(block).unwrap();
+}
+
+// a regtest chain where every payer mines a coinbase, followed by blocks of payments spending them
+// returns the chain and the blocks of payments
+fn chain_of_payments() -> (Blockchain, Vec<Block>) {
+    let mut chain = Blockchain::new(ChainParams { coinbase_maturity: 1, ..ChainParams::regtest() });
+    let mut payers: Vec<Wallet> = (0..PAYERS).map(|_| Wallet::new()).collect();
+    payers.iter().for_each(|payer| mine(&mut chain, payer.address(), vec![]));
+    let mut utxos = GlobalUtxos::new();
+    utxos.find_utxos(&chain);
+    let recipient = Wallet::new();
+    for payers in payers.chunks_mut(PAYERS / BLOCKS) {
+        let height = chain.get_height() + 1;
+        let payments = payers.iter_mut().map(|payer| {
+            let coins = utxos.get_utxos(&payer.script_pubkey()).cloned().unwrap_or_default();
+            payer.send_to_script(1000, 0, recipient.script_pubkey(), &coins, height, chain.params.coinbase_maturity).unwrap()
+        }).collect();
+        mine(&mut chain, recipient.address(), payments);
+    }
+    let blocks = chain.chain[chain.chain.len() - BLOCKS..].to_vec();
+    (chain, blocks)
+}
+
+// validates the scripts of the payment blocks one signature at a time, in batches, and in batches with every signature cached
+fn script_validation(c: &mut Criterion) {
+    let (mut chain, blocks) = chain_of_payments();
+    let spent: Vec<_> = blocks.iter().map(|block| chain.spent_by_tx(block)).collect();
+    let individually = |chain: &Blockchain| blocks.iter().zip(&spent).all(|(block, spent)| chain.validate_scripts_individually(block, spent).is_ok());
+    let batched = |chain: &Blockchain| blocks.iter().zip(&spent).all(|(block, spent)| chain.validate_scripts(block, spent).is_ok());
+    chain.signature_cache = Arc::new(SignatureCache::new(0));
+    assert!(individually(&chain) && batched(&chain));
+    let mut group = c.benchmark_group("script validation");
+    group.bench_function("individual", |b| b.iter(|| individually(&chain)));
+    group.bench_function("batched", |b| b.iter(|| batched(&chain)));
+    // checking signatures one at a time is what fills the cache
+    chain.signature_cache = Arc::new(SignatureCache::new(PAYERS));
+    individually(&chain);
+    assert_eq!(chain.signature_cache.len(), PAYERS);
+    group.bench_function("cached", |b| b.iter(|| batched(&chain)));
+    group.finish();
+}
+
+criterion_group!(benches, script_validation);
+criterion_main!(benches);
@@ -0,0 +1,244 @@
+use std::fmt;
+
+// bech32m addresses: a network prefix, the separator 1, then the version and key in base32 followed by a 6 character checksum
+const CHARSET: &[u8;32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32;5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const BECH32M_CONST: u32 = 0x2bc830a3;
+const CHECKSUM_LENGTH: usize = 6;
+const MAX_LENGTH: usize = 90;
+// version 0 addresses stand for a pay to pubkey output, other versions are left for new output types
+const ADDRESS_VERSION: u8 = 0;
+
+pub fn encode(prefix: &str, key: &[u8;32]) -> String {
+    let mut data = vec![ADDRESS_VERSION];
+    data.extend(convert_bits(key, 8, 5, true).unwrap());
+    encode_data(prefix, data)
+}
+
+fn encode_data(prefix: &str, mut data: Vec<u8>) -> String {
+    let checksum = polymod(&[expand_prefix(prefix), data.clone(), vec![0; CHECKSUM_LENGTH]].concat()) ^ BECH32M_CONST;
+    data.extend((0..CHECKSUM_LENGTH).map(|i| ((checksum >> (5 * (5 - i))) & 31) as u8));
+    let encoded: String = data.iter().map(|value| CHARSET[*value as usize] as char).collect();
+    format!("{}1{}", prefix, encoded)
+}
+
+// returns the key behind an address of the given network, a mistyped character is pointed out where it can be found
+pub fn decode(prefix: &str, address: &str) -> Result<[u8;32], AddressError> {
+    let (found_prefix, data) = decode_data(address)?;
+    if found_prefix != prefix {
+        return Err(AddressError::WrongNetwork(found_prefix));
+    }
+    if data.first() != Some(&ADDRESS_VERSION) {
+        return Err(data.first().map_or(AddressError::BadLength, |version| AddressError::UnknownVersion(*version)));
+    }
+    let key = convert_bits(&data[1..], 5, 8, false).ok_or(AddressError::BadLength)?;
+    key.try_into().map_err(|_| AddressError::BadLength)
+}
+
+// splits any bech32m string into its lower case prefix and the 5 bit values before the checksum
+// positions in errors count characters from the start of the string
+fn decode_data(address: &str) -> Result<(String, Vec<u8>), AddressError> {
+    if let Some((position, c)) = address.chars().enumerate().find(|(_, c)| !(33..=126).contains(&(*c as u32))) {
+        return Err(AddressError::InvalidChar(position, c));
+    }
+    if address.chars().any(|c| c.is_ascii_lowercase()) && address.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(AddressError::MixedCase);
+    }
+    if address.len() > MAX_LENGTH {
+        return Err(AddressError::BadLength);
+    }
+    let address = address.to_ascii_lowercase();
+    // every character is ascii by now, so byte offsets are character positions
+    let separator = address.rfind('1').ok_or(AddressError::MissingSeparator)?;
+    if separator == 0 {
+        return Err(AddressError::MissingPrefix);
+    }
+    let (found_prefix, encoded) = (&address[..separator], &address[separator + 1..]);
+    let mut data = vec![];
+    for (position, c) in encoded.chars().enumerate() {
+        let value = CHARSET.iter().position(|known| *known as char == c).ok_or(AddressError::InvalidChar(separator + 1 + position, c))?;
+        data.push(value as u8);
+    }
+    if data.len() < CHECKSUM_LENGTH {
+        return Err(AddressError::BadLength);
+    }
+    let expanded = expand_prefix(found_prefix);
+    if !has_valid_checksum(&expanded, &data) {
+        return Err(AddressError::BadChecksum(locate_typo(&expanded, &mut data).map(|(position, c)| (separator + 1 + position, c))));
+    }
+    data.truncate(data.len() - CHECKSUM_LENGTH);
+    Ok((found_prefix.to_string(), data))
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    values.iter().fold(1, |checksum, value| {
+        let top = checksum >> 25;
+        let checksum = ((checksum & 0x1ffffff) << 5) ^ *value as u32;
+        GENERATOR.iter().enumerate().filter(|(i, _)| (top >> i) & 1 == 1).fold(checksum, |checksum, (_, generator)| checksum ^ generator)
+    })
+}
+
+fn expand_prefix(prefix: &str) -> Vec<u8> {
+    let bytes = prefix.as_bytes();
+    [bytes.iter().map(|byte| byte >> 5).collect::<Vec<u8>>(), vec![0], bytes.iter().map(|byte| byte & 31).collect()].concat()
+}
+
+fn has_valid_checksum(expanded_prefix: &[u8], data: &[u8]) -> bool {
+    polymod(&[expanded_prefix, data].concat()) == BECH32M_CONST
+}
+
+// the checksum catches any few typos, a single one can also be found by trying every character in every position
+fn locate_typo(expanded_prefix: &[u8], data: &mut [u8]) -> Option<(usize, char)> {
+    for position in 0..data.len() {
+        let original = data[position];
+        for value in (0..32).filter(|value| *value != original) {
+            data[position] = value;
+            if has_valid_checksum(expanded_prefix, data) {
+                return Some((position, CHARSET[value as usize] as char));
+            }
+        }
+        data[position] = original;
+    }
+    None
+}
+
+// regroups bits, from bytes to 5 bit values and back, padding is only allowed when asked for
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut accumulator: u32 = 0;
+    let mut bits = 0;
+    let mut converted = vec![];
+    let max = (1 << to) - 1;
+    for value in data {
+        accumulator = (accumulator << from) | *value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((accumulator >> bits) & max) as u8);
+        }
+    }
+    if pad && bits > 0 {
+        converted.push(((accumulator << (to - bits)) & max) as u8);
+    } else if !pad && (bits >= from || ((accumulator << (to - bits)) & max) != 0) {
+        return None;
+    }
+    Some(converted)
+}
+
+#[derive(Debug)]
+pub enum AddressError {
+    MixedCase,
+    MissingSeparator,
+    MissingPrefix,
+    // position in the address and the character found there
+    InvalidChar(usize, char),
+    BadLength,
+    // the position and likely intended character, when a single typo explains the mismatch
+    BadChecksum(Option<(usize, char)>),
+    WrongNetwork(String),
+    UnknownVersion(u8),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::MixedCase => write!(f, "address mixes upper and lower case"),
+            AddressError::MissingSeparator => write!(f, "address has no separator"),
+            AddressError::MissingPrefix => write!(f, "address has no network prefix"),
+            AddressError::InvalidChar(position, c) => write!(f, "invalid character '{}' at position {}", c, position),
+            AddressError::BadLength => write!(f, "address has the wrong length"),
+            AddressError::BadChecksum(Some((position, c))) => write!(f, "checksum mismatch, likely a typo at position {} (should be '{}')", position, c),
+            AddressError::BadChecksum(None) => write!(f, "checksum mismatch"),
+            AddressError::WrongNetwork(prefix) => write!(f, "address is for another network ({})", prefix),
+            AddressError::UnknownVersion(version) => write!(f, "unknown address version {}", version),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the bech32m test vectors of BIP-350
+    const VALID: [&str; 7] = [
+        "A1LQFN3A",
+        "a1lqfn3a",
+        "an83characterlonghumanreadablepartthatcontainsthetheexcludedcharactersbioandnumber11sg7hg6",
+        "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+        "11llllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllllludsr8",
+        "split1checkupstagehandshakeupstreamerranterredcaperredlc445v",
+        "?1v759aa",
+    ];
+
+    #[test]
+    fn accepts_the_valid_vectors() {
+        for address in VALID {
+            let (prefix, data) = decode_data(address).unwrap();
+            assert_eq!(encode_data(&prefix, data), address.to_ascii_lowercase());
+        }
+    }
+
+    #[test]
+    fn rejects_the_invalid_vectors() {
+        let invalid = [
+            ("\u{20}1xj0phk", AddressError::InvalidChar(0, ' ')),
+            ("\u{7f}1g6xzxy", AddressError::InvalidChar(0, '\u{7f}')),
+            ("\u{80}1vctc34", AddressError::InvalidChar(0, '\u{80}')),
+            ("an84characterslonghumanreadablepartthatcontainsthetheexcludedcharactersbioandnumber11d6pts4", AddressError::BadLength),
+            ("qyrz8wqd2c9m", AddressError::MissingSeparator),
+            ("1qyrz8wqd2c9m", AddressError::MissingPrefix),
+            ("y1b0jsk6g", AddressError::InvalidChar(2, 'b')),
+            ("lt1igcx5c0", AddressError::InvalidChar(3, 'i')),
+            ("in1muywd", AddressError::BadLength),
+            ("mm1crxm3i", AddressError::InvalidChar(8, 'i')),
+            ("au1s5cgom", AddressError::InvalidChar(7, 'o')),
+            ("16plkw9", AddressError::MissingPrefix),
+            ("1p2gdwpf", AddressError::MissingPrefix),
+        ];
+        for (address, error) in invalid {
+            assert_eq!(format!("{:?}", decode_data(address).unwrap_err()), format!("{:?}", error), "{}", address);
+        }
+        // the checksum was made with the upper case prefix, and a bech32 checksum isn't a bech32m one
+        for address in ["M1VUXWEZ", "a12uel5l"] {
+            assert!(matches!(decode_data(address), Err(AddressError::BadChecksum(_))), "{}", address);
+        }
+    }
+
+    #[test]
+    fn round_trips_a_key_in_either_case() {
+        let key: [u8;32] = rand::random();
+        let address = encode("tx", &key);
+        assert!(address.starts_with("tx1q"));
+        assert_eq!(decode("tx", &address).unwrap(), key);
+        assert_eq!(decode("tx", &address.to_ascii_uppercase()).unwrap(), key);
+    }
+
+    #[test]
+    fn tells_why_an_address_is_rejected() {
+        let key = [7; 32];
+        let address = encode("tx", &key);
+        let mixed = format!("TX{}", &address[2..]);
+        assert!(matches!(decode("tx", &mixed), Err(AddressError::MixedCase)));
+        assert!(matches!(decode("rtx", &address), Err(AddressError::WrongNetwork(prefix)) if prefix == "tx"));
+        let mut data = vec![1];
+        data.extend(convert_bits(&key, 8, 5, true).unwrap());
+        assert!(matches!(decode("tx", &encode_data("tx", data)), Err(AddressError::UnknownVersion(1))));
+        let mut data = vec![ADDRESS_VERSION];
+        data.extend(convert_bits(&key[..31], 8, 5, true).unwrap());
+        assert!(matches!(decode("tx", &encode_data("tx", data)), Err(AddressError::BadLength)));
+        // positions count characters, not bytes
+        assert!(matches!(decode("tx", &format!("t\u{e9}{}", &address[2..])), Err(AddressError::InvalidChar(1, '\u{e9}'))));
+    }
+
+    #[test]
+    fn points_at_a_mistyped_character() {
+        let address = encode("tx", &[7; 32]);
+        for position in [3, 20, address.len() - 1] {
+            let original = address.as_bytes()[position] as char;
+            let typo = if original == 'q' { 'p' } else { 'q' };
+            let mut mistyped: Vec<char> = address.chars().collect();
+            mistyped[position] = typo;
+            let mistyped: String = mistyped.into_iter().collect();
+            assert!(matches!(decode("tx", &mistyped), Err(AddressError::BadChecksum(Some(found))) if found == (position, original)));
+        }
+    }
+}
@@ -1,42 +1,220 @@
-use blake3;
-use num_format::{Locale, ToFormattedString};
+use std::fmt;
 
-use crate::transactions::Tx;
+use rayon::prelude::*;
 
-pub const MAX_BLOCK_SIZE: u32 = 100000;
-pub const BLOCK_REWARD: u32 = 5000000;
+use crate::chain_params::ChainParams;
+use crate::transactions::Tx;
 
+#[derive(Clone)]
 pub struct Block {
     pub index: u32,
     pub hash: [u8;32],
     pub previous_hash: [u8;32],
+    pub merkle_root: [u8;32],
     pub time: u64,
     pub target: u64,
     pub nonce: u64,
     pub transactions: Vec<Tx>,
 }
 
+// header is everything in a block except the transactions, which are committed to by the merkle root
+#[derive(Clone, Copy)]
+pub struct BlockHeader {
+    pub index: u32,
+    pub hash: [u8;32],
+    pub previous_hash: [u8;32],
+    pub merkle_root: [u8;32],
+    pub time: u64,
+    pub target: u64,
+    pub nonce: u64,
+}
+
 impl Block {
-    pub fn print(&self) {
-        println!("\n-------------------------------------------------------------------------------");
-        print!("Block: {} ",self.index);
-        self.hash.iter().for_each(|hex|print!("{:02x}",hex));
-        println!("\nHeader Data: ");
-        print!("\nPrevious block: ");
-        self.previous_hash.iter().for_each(|hex|print!("{:02x}",hex));
-        println!("\nUnix Timestamp: {}",self.time);
-        println!("Target {:016x}", self.target);
-        println!("Nonce: {:016x}", self.nonce);
-        println!("\nTransactions: ");
-        self.transactions.iter().for_each(|transaction| transaction.print());
-        println!("\n\nTotal block size: {} Bytes",self.get_size().to_formatted_string(&Locale::en));
-        println!("\nEnd Block: {}",self.index);
-        println!("-------------------------------------------------------------------------------");
+    pub fn from_header(header: BlockHeader, transactions: Vec<Tx>) -> Block {
+        Block {
+            index: header.index,
+            hash: header.hash,
+            previous_hash: header.previous_hash,
+            merkle_root: header.merkle_root,
+            time: header.time,
+            target: header.target,
+            nonce: header.nonce,
+            transactions,
+        }
     }
 
+    // blocks kept as headers only have no txs, genesis is the only block that has none of its own
+    pub fn has_body(&self) -> bool { self.index == 0 || !self.transactions.is_empty() }
+
     pub fn get_size(&self) -> u32{
-        const HEADER_BYTES: u32 = 92;
+        const HEADER_BYTES: u32 = 124;
         let tx_bytes: u32 = self.transactions.iter().map(|tx|tx.get_size()).sum();
         HEADER_BYTES + tx_bytes
     }
-}
\ No newline at end of file
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            hash: self.hash,
+            previous_hash: self.previous_hash,
+            merkle_root: self.merkle_root,
+            time: self.time,
+            target: self.target,
+            nonce: self.nonce,
+        }
+    }
+
+    pub fn calc_merkle_root(transactions: &[Tx]) -> [u8;32] {
+        let mut level: Vec<[u8;32]> = transactions.iter().map(|tx|tx.txid).collect();
+        if level.is_empty() {
+            return [0; 32];
+        }
+        // hashes pairs of nodes until only the root is left, an odd node out is paired with itself
+        while level.len() > 1 {
+            level = level.chunks(2).map(|pair| {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(&pair[0]);
+                hasher.update(pair.get(1).unwrap_or(&pair[0]));
+                *hasher.finalize().as_bytes()
+            }).collect();
+        }
+        level[0]
+    }
+
+    // checks the body of the block against its own header, header validation is done separately
+    pub fn validate_body(&self, params: &ChainParams) -> Result<(), BlockError> {
+        if self.get_size() > params.max_block_size {
+            return Err(BlockError::TooLarge);
+        }
+        if self.transactions.is_empty() || !self.transactions[0].is_coinbase() {
+            return Err(BlockError::MissingCoinbase);
+        }
+        if self.transactions[0].inputs[0].coinbase_height() != Some(self.index) {
+            return Err(BlockError::BadCoinbaseHeight);
+        }
+        if Block::calc_merkle_root(&self.transactions) != self.merkle_root {
+            return Err(BlockError::BadMerkleRoot);
+        }
+        // every tx checks on its own, so they're spread over threads
+        let well_formed = self.transactions.par_iter().enumerate()
+            .all(|(index, tx)| tx.is_coinbase() == (index == 0) && tx.check_structure());
+        if !well_formed {
+            return Err(BlockError::InvalidTransaction);
+        }
+        Ok(())
+    }
+}
+
+impl BlockHeader {
+    pub fn calc_hash(&self) -> [u8;32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.index.to_be_bytes());
+        hasher.update(&self.previous_hash);
+        hasher.update(&self.merkle_root);
+        hasher.update(&self.time.to_be_bytes());
+        hasher.update(&self.target.to_be_bytes());
+        hasher.update(&self.nonce.to_be_bytes());
+        *hasher.finalize().as_bytes()
+    }
+
+    pub fn meets_target(&self) -> bool { hash_to_u64(self.hash) <= self.target }
+
+    // checks that the header links onto the previous header and carries valid proof of work
+    pub fn validate(&self, previous: &BlockHeader, params: &ChainParams) -> Result<(), BlockError> {
+        if self.previous_hash != previous.hash {
+            return Err(BlockError::BadPreviousHash);
+        }
+        if self.index != previous.index + 1 {
+            return Err(BlockError::BadIndex);
+        }
+        // difficulty is fixed, so every block must use the target of the network
+        if self.target != params.target {
+            return Err(BlockError::BadTarget);
+        }
+        if self.calc_hash() != self.hash {
+            return Err(BlockError::BadHash);
+        }
+        if !self.meets_target() {
+            return Err(BlockError::InsufficientWork);
+        }
+        Ok(())
+    }
+
+    // the time must move past the median of recent blocks and can't run too far ahead of our clock
+    pub fn validate_time(&self, median_time_past: u64, now: u64, params: &ChainParams) -> Result<(), BlockError> {
+        if self.time <= median_time_past {
+            return Err(BlockError::TimeTooOld);
+        }
+        if self.time > now + params.max_future_drift {
+            return Err(BlockError::TimeTooNew);
+        }
+        Ok(())
+    }
+}
+
+pub fn hash_to_u64(hash: [u8; 32]) -> u64 {
+    // takes the 8 most significant bytes of the hash
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+#[derive(Debug)]
+pub enum BlockError {
+    BadPreviousHash,
+    BadIndex,
+    BadTarget,
+    BadHash,
+    InsufficientWork,
+    BadMerkleRoot,
+    MissingCoinbase,
+    BadCoinbaseHeight,
+    BadCoinbaseAmount,
+    ImmatureCoinbaseSpend,
+    TimeTooOld,
+    TimeTooNew,
+    TimeLocked,
+    // the index of the tx in the block and of its input that failed
+    InvalidScript(usize, usize),
+    // an input spends an output that doesn't exist or is already spent, by tx and input index like InvalidScript
+    MissingInput(usize, usize),
+    InvalidTransaction,
+    TooLarge,
+    // the fork is below the blocks a pruned chain kept the bodies of
+    ReorgTooDeep,
+    // the chain was loaded from a snapshot the history before it doesn't lead to
+    InvalidSnapshot,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::InvalidScript(tx, input) => write!(f, "input {} of tx {} fails its script", input, tx),
+            BlockError::MissingInput(tx, input) => write!(f, "input {} of tx {} spends a missing output", input, tx),
+            error => write!(f, "{:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::fixtures::{miner, params};
+
+    #[test]
+    fn rejects_a_coinbase_committing_to_another_height() {
+        let mut chain = Blockchain::new(params());
+        let miner = miner([1; 32]);
+        let mut block = miner.build_block(1, chain.get_current_hash(), vec![], 0, &chain);
+        // a well formed block whose coinbase claims the next height
+        block.transactions[0] = miner.generate_coinbase(2, 0, chain.params.subsidy(1), 0);
+        block.merkle_root = Block::calc_merkle_root(&block.transactions);
+        block.hash = block.header().calc_hash();
+        assert!(matches!(block.validate_body(&chain.params), Err(BlockError::BadCoinbaseHeight)));
+        block.transactions[0].inputs[0].witness = vec![vec![]];
+        assert!(matches!(block.validate_body(&chain.params), Err(BlockError::BadCoinbaseHeight)));
+        block.transactions[0] = miner.generate_coinbase(1, 0, chain.params.subsidy(1), 0);
+        block.merkle_root = Block::calc_merkle_root(&block.transactions);
+        block.hash = block.header().calc_hash();
+        chain.connect_block(block).unwrap();
+    }
+}
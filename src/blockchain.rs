@@ -1,12 +1,85 @@
-use crate::block::Block;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use rayon::prelude::*;
+
+use crate::block::{Block, BlockError, BlockHeader};
+use crate::chain_params::{ChainParams, MIN_PRUNE_DEPTH};
+use crate::clock::{Clock, SystemClock};
+use crate::commitment::UtxoCommitment;
+use crate::index::ChainIndex;
+use crate::input::{SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY, SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG};
+use crate::output::Output;
+use crate::script;
+use crate::sig_cache::SignatureCache;
+use crate::snapshot::{Coin, SnapshotBase, SnapshotError, UtxoSnapshot};
+use crate::transactions::Tx;
+
+// number of blocks the median time past is taken over
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// a signature check the scripts assumed to pass, verified later in a batch
+type DeferredSignature = (VerifyingKey, Signature, [u8;32]);
+// signatures verified together, one batch per rayon task
+pub const SIGNATURE_BATCH_SIZE: usize = 64;
 
 pub struct Blockchain {
-    pub chain: Vec<Block>
+    pub chain: Vec<Block>,
+    pub params: ChainParams,
+    pub clock: Arc<dyn Clock>,
+    // shared with whoever checks txs against this chain, the mempool fills it and block validation reads it
+    pub signature_cache: Arc<SignatureCache>,
+    pub index: ChainIndex,
+    // set when the chain was loaded from a snapshot instead of built from genesis
+    pub snapshot: Option<SnapshotBase>,
+    // blocks up to this height only have their headers, because the chain was loaded from a snapshot or pruned
+    pub pruned_height: u32,
+    // the utxo set as of the tip, what blocks are validated against
+    pub coins: HashMap<([u8;32], u32), Coin>,
+    // the coins spent by each block, by height, so a block can be disconnected without looking up what it spent
+    pub undo: Vec<Vec<Coin>>,
+    // commits to the utxo set as of the tip
+    pub utxo_commitment: UtxoCommitment,
 }
 
 impl Blockchain {
-    pub fn print(&self) {
-        self.chain.iter().for_each(|block| block.print());
+    pub fn new(params: ChainParams) -> Blockchain {
+        Blockchain::with_clock(params, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(params: ChainParams, clock: Arc<dyn Clock>) -> Blockchain {
+        let genesis = params.genesis();
+        let mut index = ChainIndex::new(params.tx_index, params.address_index);
+        index.connect(&genesis, &[]);
+        let signature_cache = Arc::new(SignatureCache::new(params.signature_cache_size));
+        Blockchain { chain: vec![genesis], params, clock, signature_cache, index, snapshot: None, pruned_height: 0, coins: HashMap::new(), undo: vec![vec![]], utxo_commitment: UtxoCommitment::new() }
+    }
+
+    // starts the chain at the height of the snapshot, the blocks before it are kept as headers only
+    // the headers are checked like any others, the coins only have to match the snapshot hash the params trust
+    // until the history is replayed
+    pub fn from_snapshot(params: ChainParams, snapshot: &UtxoSnapshot) -> Result<Blockchain, SnapshotError> {
+        if params.snapshot_hash != Some(snapshot.hash()) {
+            return Err(SnapshotError::Untrusted);
+        }
+        let mut chain = Blockchain::new(params);
+        if snapshot.headers[0].hash != chain.get_current_hash() {
+            return Err(SnapshotError::WrongNetwork);
+        }
+        for header in &snapshot.headers[1..] {
+            header.validate(&chain.get_tip_header(), &chain.params).map_err(SnapshotError::InvalidHeader)?;
+            header.validate_time(chain.median_time_past(), chain.clock.now(), &chain.params).map_err(SnapshotError::InvalidHeader)?;
+            chain.add_block(Block::from_header(*header, vec![]));
+        }
+        chain.coins = snapshot.coins.iter().map(|coin| ((coin.txid, coin.vout), coin.clone())).collect();
+        chain.pruned_height = snapshot.height();
+        // the coins of a valid chain never add up past its supply, so the commitment can't overflow once it's built on
+        chain.utxo_commitment = UtxoCommitment::from_coins(&snapshot.coins)
+            .filter(|commitment| commitment.amount <= chain.params.total_supply_at(snapshot.height()))
+            .ok_or(SnapshotError::TooManyCoins)?;
+        chain.snapshot = Some(SnapshotBase { height: snapshot.height(), hash: snapshot.hash(), validated: None });
+        Ok(chain)
     }
 
     pub fn get_height(&self) -> u32{
@@ -15,13 +88,745 @@ impl Blockchain {
 
     pub fn get_current_hash(&self) -> [u8;32] { self.chain.last().unwrap().hash }
 
+    pub fn get_tip_header(&self) -> BlockHeader { self.chain.last().unwrap().header() }
+
     pub fn add_block(&mut self, candidate_block: Block) {
+            let spent = self.spend_coins(&candidate_block);
+            self.index.connect(&candidate_block, &spent);
+            self.undo.push(spent);
             self.chain.push(candidate_block);
+            if self.params.prunes() {
+                self.prune();
+            }
+    }
+
+    // drops the bodies and undo data of blocks past the prune depth, or the oldest ones while they take more than the prune size
+    // their outputs that are still unspent stay in coins, returns the number of blocks pruned
+    pub fn prune(&mut self) -> u32 {
+        let tip = self.get_height();
+        let mut prune_to = self.pruned_height;
+        if self.params.prune_depth != 0 {
+            prune_to = prune_to.max(tip.saturating_sub(self.params.prune_depth.max(MIN_PRUNE_DEPTH)));
+        }
+        if self.params.prune_size != 0 {
+            let mut kept: u64 = self.chain[prune_to as usize + 1..].iter().map(|block| block.get_size() as u64).sum();
+            while kept > self.params.prune_size && prune_to + MIN_PRUNE_DEPTH < tip {
+                prune_to += 1;
+                kept -= self.chain[prune_to as usize].get_size() as u64;
+            }
+        }
+        if prune_to <= self.pruned_height {
+            return 0;
+        }
+        for height in self.pruned_height + 1..=prune_to {
+            self.chain[height as usize].transactions = vec![];
+            self.undo[height as usize] = vec![];
+        }
+        let pruned = prune_to - self.pruned_height;
+        self.pruned_height = prune_to;
+        pruned
+    }
+
+    // takes the tip off the chain, the genesis block and blocks without their bodies stay
+    // so a pruned chain can't reorg deeper than the blocks it kept
+    // utxo sets built from the chain don't follow, they have to be rebuilt
+    pub fn disconnect_tip(&mut self) -> Option<Block> {
+        if self.chain.len() < 2 || self.get_height() <= self.pruned_height {
+            return None;
+        }
+        let block = self.chain.pop()?;
+        let mut spent = self.undo.pop().unwrap();
+        self.index.disconnect(&block, &spent);
+        // the outputs of each tx leave the set and the coins it spent come back, last tx first
+        // so an output spent further down the same block is back before the tx that created it is undone
+        for tx in block.transactions.iter().rev() {
+            for coin in Coin::created_by(tx, block.index) {
+                self.coins.remove(&(coin.txid, coin.vout));
+                self.utxo_commitment.remove(&coin);
+            }
+            if !tx.is_coinbase() {
+                for coin in spent.split_off(spent.len().saturating_sub(tx.inputs.len())) {
+                    self.utxo_commitment.add(&coin).expect("the coins of a valid chain add up to less than its supply");
+                    self.coins.insert((coin.txid, coin.vout), coin);
+                }
+            }
+        }
+        Some(block)
+    }
+
+    // switches to a longer branch forking off below the tip, branch starts with the block after the fork point
+    // returns the blocks that were disconnected, tip first, if any block of the branch is invalid the old chain is put back
+    // a pruned chain refuses forks below the bodies it kept, which are at least MIN_PRUNE_DEPTH blocks
+    pub fn reorganize(&mut self, branch: Vec<Block>) -> Result<Vec<Block>, BlockError> {
+        let first = branch.first().ok_or(BlockError::InsufficientWork)?;
+        let fork_height = first.index.checked_sub(1).ok_or(BlockError::BadIndex)?;
+        if self.chain.get(fork_height as usize).is_none_or(|block| block.hash != first.previous_hash) {
+            return Err(BlockError::BadPreviousHash);
+        }
+        // every block takes the same work, so the longer chain is the one with more
+        if fork_height + branch.len() as u32 <= self.get_height() {
+            return Err(BlockError::InsufficientWork);
+        }
+        if fork_height < self.pruned_height {
+            return Err(BlockError::ReorgTooDeep);
+        }
+        let mut disconnected = vec![];
+        while self.get_height() > fork_height {
+            disconnected.push(self.disconnect_tip().unwrap());
+        }
+        for block in branch {
+            if let Err(error) = self.connect_block(block) {
+                while self.get_height() > fork_height {
+                    self.disconnect_tip();
+                }
+                // the old blocks were valid on top of the fork point before, so they are again
+                disconnected.into_iter().rev().for_each(|block| self.add_block(block));
+                return Err(error);
+            }
+        }
+        Ok(disconnected)
+    }
+
+    // switches to the index kept at path, indexing whatever blocks of the chain it doesn't know yet
+    pub fn open_index(&mut self, path: &str) -> io::Result<()> {
+        let chain = &self.chain;
+        let index = ChainIndex::open(path, self.params.tx_index, self.params.address_index, |hash, height| chain.get(height as usize).is_some_and(|block| block.hash == *hash))?;
+        let known: Vec<bool> = self.chain.iter().map(|block| index.get_height(&block.hash) == Some(block.index)).collect();
+        self.index = index;
+        for (height, known) in known.into_iter().enumerate() {
+            if known {
+                self.index.restore(&self.chain[height], &self.undo[height]);
+            } else {
+                self.index.connect(&self.chain[height], &self.undo[height]);
+            }
+        }
+        Ok(())
+    }
+
+    // validates the block against the current tip before adding it to the chain
+    // the checks of single txs run in parallel, the ones that depend on what came before follow in block order
+    pub fn connect_block(&mut self, block: Block) -> Result<(), BlockError> {
+        // the coins a bad snapshot started from are wrong, so nothing built on them can be trusted
+        if self.snapshot.as_ref().is_some_and(|base| base.validated == Some(false)) {
+            return Err(BlockError::InvalidSnapshot);
+        }
+        block.header().validate(&self.get_tip_header(), &self.params)?;
+        block.header().validate_time(self.median_time_past(), self.clock.now(), &self.params)?;
+        block.validate_body(&self.params)?;
+        let spent = self.find_spent_coins(&block)?;
+        self.validate_scripts(&block, &spent)?;
+        self.validate_coinbase(&block, &spent)?;
+        self.validate_maturity(&block, &spent)?;
+        self.validate_locks(&block, &spent)?;
+        self.add_block(block);
+        Ok(())
+    }
+
+    // the coins every tx of the block spends, by tx and then input, the coinbase spends none
+    // a tx can spend outputs of the txs before it in the block, but no coin can be spent twice
+    fn find_spent_coins(&self, block: &Block) -> Result<Vec<Vec<Coin>>, BlockError> {
+        let mut created: HashMap<([u8;32], u32), Coin> = HashMap::new();
+        let mut spent: HashSet<([u8;32], u32)> = HashSet::new();
+        let mut spent_coins = vec![];
+        for (tx_index, tx) in block.transactions.iter().enumerate() {
+            let mut coins = vec![];
+            for (input_index, input) in tx.inputs.iter().enumerate().filter(|_| !tx.is_coinbase()) {
+                let outpoint = (input.txid, input.vout);
+                let coin = spent.insert(outpoint).then(|| created.remove(&outpoint).or_else(|| self.coins.get(&outpoint).cloned())).flatten();
+                coins.push(coin.ok_or(BlockError::MissingInput(tx_index, input_index))?);
+            }
+            created.extend(Coin::created_by(tx, block.index).into_iter().map(|coin| ((coin.txid, coin.vout), coin)));
+            spent_coins.push(coins);
+        }
+        Ok(spent_coins)
+    }
+
+    // the coinbase may claim the subsidy for its height plus the fees of every other transaction, and nothing more
+    fn validate_coinbase(&self, block: &Block, spent: &[Vec<Coin>]) -> Result<(), BlockError> {
+        let mut fees: u64 = 0;
+        for (tx, coins) in block.transactions.iter().zip(spent).skip(1) {
+            let fee = tx.fee_from(coins.iter().map(|coin| coin.output.amount)).ok_or(BlockError::InvalidTransaction)?;
+            fees = fees.checked_add(fee).ok_or(BlockError::InvalidTransaction)?;
+        }
+        let claimed: u64 = block.transactions[0].outputs.iter().map(|output| output.amount).sum();
+        if claimed > self.params.subsidy(block.index) + fees {
+            return Err(BlockError::BadCoinbaseAmount);
+        }
+        Ok(())
+    }
+
+    fn validate_maturity(&self, block: &Block, spent: &[Vec<Coin>]) -> Result<(), BlockError> {
+        let spends_immature = spent.iter().flatten().any(|coin| coin.coinbase && block.index - coin.height < self.params.coinbase_maturity);
+        if spends_immature {
+            return Err(BlockError::ImmatureCoinbaseSpend);
+        }
+        Ok(())
+    }
+
+    fn validate_locks(&self, block: &Block, spent: &[Vec<Coin>]) -> Result<(), BlockError> {
+        for (tx, coins) in block.transactions.iter().zip(spent).skip(1) {
+            let source_heights: Vec<u32> = coins.iter().map(|coin| coin.height).collect();
+            if !self.check_locks(tx, block.index, &source_heights) {
+                return Err(BlockError::TimeLocked);
+            }
+        }
+        Ok(())
+    }
+
+    // runs the scripts with every signature assumed valid, then checks the signatures in parallel batches
+    // if they all hold the assumption changed nothing, otherwise the inputs are checked one by one to find the bad one
+    // spent holds the coins every tx of the block spends, as found by find_spent_coins or kept as undo data
+    pub fn validate_scripts(&self, block: &Block, spent: &[Vec<Coin>]) -> Result<(), BlockError> {
+        let deferred: Option<Vec<Vec<DeferredSignature>>> = block.transactions.par_iter().zip(spent).skip(1)
+            .map(|(tx, coins)| self.defer_signatures(tx, coins)).collect();
+        let signatures_pass = |deferred: Vec<DeferredSignature>| deferred.par_chunks(SIGNATURE_BATCH_SIZE).all(|batch| {
+            let messages: Vec<&[u8]> = batch.iter().map(|(_, _, sighash)| sighash.as_slice()).collect();
+            let signatures: Vec<Signature> = batch.iter().map(|(_, signature, _)| *signature).collect();
+            let keys: Vec<VerifyingKey> = batch.iter().map(|(key, _, _)| *key).collect();
+            ed25519_dalek::verify_batch(&messages, &signatures, &keys).is_ok()
+        });
+        if deferred.is_some_and(|deferred| signatures_pass(deferred.concat())) {
+            return Ok(());
+        }
+        self.validate_scripts_individually(block, spent)
+    }
+
+    // runs the scripts of the tx, returning the signatures they assumed valid that aren't cached, None if a script fails anyway
+    fn defer_signatures(&self, tx: &Tx, coins: &[Coin]) -> Option<Vec<DeferredSignature>> {
+        let mut deferred = vec![];
+        let scripts_pass = tx.inputs.len() == coins.len() && tx.inputs.iter().zip(coins).enumerate().all(|(index, (input, coin))| {
+            script::verify_with(&input.witness, &coin.output.script_pubkey, tx, index, &mut |key, signature, sighash, deferrable| {
+                if !deferrable {
+                    return self.signature_cache.verify(key, signature, sighash);
+                }
+                if self.signature_cache.contains(key, signature, sighash) {
+                    return true;
+                }
+                deferred.push((*key, *signature, *sighash));
+                // only the equation can be left to the batch
+                script::has_prime_order_points(key, signature)
+            }).is_ok()
+        });
+        scripts_pass.then_some(deferred)
+    }
+
+    // the plain path, one signature at a time, reporting the first input that fails
+    pub fn validate_scripts_individually(&self, block: &Block, spent: &[Vec<Coin>]) -> Result<(), BlockError> {
+        for (tx_index, (tx, coins)) in block.transactions.iter().zip(spent).enumerate().skip(1) {
+            if let Some(input_index) = (0..tx.inputs.len()).find(|index| !coins.get(*index).is_some_and(|coin| self.check_script(tx, *index, &coin.output))) {
+                return Err(BlockError::InvalidScript(tx_index, input_index));
+            }
+        }
+        Ok(())
+    }
+
+    // every input has to satisfy the script of the unspent output it spends
+    pub fn check_scripts(&self, tx: &Tx) -> bool {
+        tx.inputs.iter().enumerate().all(|(index, input)| {
+            self.coins.get(&(input.txid, input.vout)).is_some_and(|coin| self.check_script(tx, index, &coin.output))
+        })
+    }
+
+    fn check_script(&self, tx: &Tx, index: usize, output: &Output) -> bool {
+        script::verify_with(&tx.inputs[index].witness, &output.script_pubkey, tx, index, &mut |key, signature, sighash, _| self.signature_cache.verify(key, signature, sighash)).is_ok()
+    }
+
+    // checks the absolute and relative locks of a tx mined at spend_height, given the heights its inputs were mined at
+    pub fn check_locks(&self, tx: &Tx, spend_height: u32, source_heights: &[u32]) -> bool {
+        // time locks compare against the block before, so a miner can't unlock them with its own timestamp
+        let median_time_past = self.median_time_past_at(spend_height - 1);
+        if !tx.is_final(spend_height, median_time_past) {
+            return false;
+        }
+        tx.inputs.iter().zip(source_heights).all(|(input, source_height)| {
+            if input.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                return true;
+            }
+            let lock = input.sequence & SEQUENCE_LOCKTIME_MASK;
+            if input.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let source_time = self.median_time_past_at(source_height.saturating_sub(1));
+                median_time_past >= source_time + ((lock as u64) << SEQUENCE_LOCKTIME_GRANULARITY)
+            } else {
+                spend_height - source_height >= lock
+            }
+        })
+    }
+
+    // median timestamp of the block at this height and the ones before it
+    pub fn median_time_past_at(&self, height: u32) -> u64 {
+        let end = (height as usize + 1).min(self.chain.len());
+        let times: Vec<u64> = self.chain[end.saturating_sub(MEDIAN_TIME_SPAN)..end].iter().map(|block| block.time).collect();
+        median_time(&times)
+    }
+
+    pub fn median_time_past(&self) -> u64 { self.median_time_past_at(self.get_height()) }
+
+    pub fn get_block_by_hash(&self, hash: &[u8;32]) -> Option<&Block> {
+        let block = self.chain.get(self.index.get_height(hash)? as usize)?;
+        (block.hash == *hash).then_some(block)
+    }
+
+    // the tx and the block it was mined in, scanning the whole chain when the tx index is disabled
+    pub fn get_tx(&self, txid: &[u8;32]) -> Option<(&Tx, &Block)> {
+        if !self.index.has_tx_index() {
+            return self.chain.iter().find_map(|block| block.transactions.iter().find(|tx| tx.txid == *txid).map(|tx| (tx, block)));
+        }
+        let (hash, position) = self.index.get_tx(txid)?;
+        let block = self.get_block_by_hash(&hash)?;
+        Some((block.transactions.get(position as usize)?, block))
+    }
+
+    // takes the coins the block spends out of the set and adds the ones it creates, a tx at a time
+    // so an output spent later in the same block comes and goes again, returns the spent coins in input order
+    fn spend_coins(&mut self, block: &Block) -> Vec<Coin> {
+        let mut spent = vec![];
+        for tx in &block.transactions {
+            for input in tx.inputs.iter().filter(|_| !tx.is_coinbase()) {
+                if let Some(coin) = self.coins.remove(&(input.txid, input.vout)) {
+                    self.utxo_commitment.remove(&coin);
+                    spent.push(coin);
+                }
+            }
+            for coin in Coin::created_by(tx, block.index) {
+                self.utxo_commitment.add(&coin).expect("the coins of a valid chain add up to less than its supply");
+                self.coins.insert((coin.txid, coin.vout), coin);
+            }
+        }
+        spent
+    }
+
+    // the undo data of a connected block split up by tx, the way validate_scripts takes it
+    pub fn spent_by_tx(&self, block: &Block) -> Vec<Vec<Coin>> {
+        let mut undo = self.undo.get(block.index as usize).map(|coins| coins.as_slice()).unwrap_or(&[]);
+        block.transactions.iter().map(|tx| {
+            let count = if tx.is_coinbase() { 0 } else { tx.inputs.len().min(undo.len()) };
+            let (coins, rest) = undo.split_at(count);
+            undo = rest;
+            coins.to_vec()
+        }).collect()
+    }
+
+    // returns the transaction and the height of the block that contains it
+    pub fn find_tx(&self, txid: &[u8;32]) -> Option<(&Tx, u32)> {
+        self.get_tx(txid).map(|(tx, block)| (tx, block.index))
+    }
+
+    // any output the chain has, spent or not, for showing txs
+    // outputs of blocks that only have their headers come from coins, so only the unspent ones are found
+    pub fn find_output(&self, txid: &[u8;32], vout: u32) -> Option<&Output> {
+        match self.find_tx(txid) {
+            Some((tx, _)) => tx.outputs.get(vout as usize),
+            None => self.coins.get(&(*txid, vout)).map(|coin| &coin.output),
+        }
+    }
+
+    // block locator lists recent hashes densely, then exponentially further back, always ending at genesis
+    pub fn get_locator(&self) -> Vec<[u8;32]> {
+        let mut locator = vec![];
+        let mut height = self.chain.len() as i64 - 1;
+        let mut step = 1;
+        while height > 0 {
+            locator.push(self.chain[height as usize].hash);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+        locator.push(self.chain[0].hash);
+        locator
+    }
+
+    // returns up to max headers following the first locator hash found in our chain
+    pub fn get_headers(&self, locator: &[[u8;32]], max: usize) -> Vec<BlockHeader> {
+        let start = locator.iter()
+            .find_map(|hash| self.get_block_by_hash(hash).map(|block| block.index as usize))
+            .unwrap_or(0);
+        self.chain.iter().skip(start + 1).take(max).map(|block| block.header()).collect()
+    }
+}
+
+// median of the last MEDIAN_TIME_SPAN timestamps, oldest first
+pub fn median_time(times: &[u64]) -> u64 {
+    let mut recent = times[times.len().saturating_sub(MEDIAN_TIME_SPAN)..].to_vec();
+    recent.sort();
+    recent[recent.len() / 2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curve25519_dalek::constants::{ED25519_BASEPOINT_POINT, EIGHT_TORSION};
+    use curve25519_dalek::Scalar;
+    use ed25519_dalek::{Digest, Sha512};
+    use rand::random;
+    use crate::clock::MockClock;
+    use crate::fixtures::{funded_chain, miner, mine, params, utxos_of};
+    use crate::global_utxos::{GlobalUtxos, Utxo};
+    use crate::input::{Input, SEQUENCE_FINAL};
+    use crate::mempool::{Mempool, MempoolError};
+    use crate::miner::Miner;
+    use crate::wallet::Wallet;
+
+
+    fn pay(chain: &Blockchain, wallet: &mut Wallet, utxos: &[Utxo], amount: u64) -> Tx {
+        let spend_height = chain.get_height() + 1;
+        wallet.send_to_script(amount, 0, Wallet::new().script_pubkey(), utxos, spend_height, chain.params.coinbase_maturity).unwrap()
+    }
+
+    #[test]
+    fn rejects_spending_an_output_spent_in_an_earlier_block() {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        let utxos = utxos_of(&chain, &wallet);
+        let first = pay(&chain, &mut wallet, &utxos, 1000);
+        let second = pay(&chain, &mut wallet, &utxos, 2000);
+        mine(&mut chain, &miner, vec![first]).unwrap();
+        let height = chain.get_height();
+        assert!(matches!(mine(&mut chain, &miner, vec![second]), Err(BlockError::MissingInput(1, 0))));
+        assert_eq!(chain.get_height(), height);
+    }
+
+    #[test]
+    fn rejects_a_coinbase_spend_one_block_before_maturity() {
+        let mut wallet = Wallet::new();
+        let mut chain = Blockchain::new(ChainParams { coinbase_maturity: 3, ..params() });
+        mine(&mut chain, &miner(wallet.address()), vec![]).unwrap();
+        let other = miner(Wallet::new().address());
+        mine(&mut chain, &other, vec![]).unwrap();
+        // the wallet holds back immature coins, so it's told there's no maturity to get the spend built
+        let utxos = utxos_of(&chain, &wallet);
+        let tx = wallet.send_to_script(1000, 0, Wallet::new().script_pubkey(), &utxos, chain.get_height() + 1, 0).unwrap();
+        assert!(matches!(mine(&mut chain, &other, vec![tx.clone()]), Err(BlockError::ImmatureCoinbaseSpend)));
+        assert_eq!(chain.get_height(), 2);
+        // exactly maturity blocks after the coinbase it can be spent
+        mine(&mut chain, &other, vec![]).unwrap();
+        mine(&mut chain, &other, vec![tx]).unwrap();
+        assert_eq!(chain.get_height(), 4);
+    }
+
+    #[test]
+    fn rejects_a_block_spending_an_output_twice() {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        let utxos = utxos_of(&chain, &wallet);
+        let first = pay(&chain, &mut wallet, &utxos, 1000);
+        let second = pay(&chain, &mut wallet, &utxos, 2000);
+        let commitment = chain.utxo_commitment.hash();
+        assert!(matches!(mine(&mut chain, &miner, vec![first, second]), Err(BlockError::MissingInput(2, 0))));
+        assert_eq!(chain.utxo_commitment.hash(), commitment);
+    }
+
+    #[test]
+    fn rejects_an_input_that_never_existed() {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        let mut utxos = utxos_of(&chain, &wallet);
+        utxos[0].vout = 7;
+        let tx = pay(&chain, &mut wallet, &utxos, 1000);
+        assert!(matches!(mine(&mut chain, &miner, vec![tx]), Err(BlockError::MissingInput(1, 0))));
+    }
+
+    #[test]
+    fn spends_an_output_created_earlier_in_the_block() {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        let utxos = utxos_of(&chain, &wallet);
+        let first = pay(&chain, &mut wallet, &utxos, 1000);
+        // the change of the first tx goes back to the wallet and is spent right away
+        let change = first.outputs.iter().position(|output| output.script_pubkey == wallet.script_pubkey()).unwrap();
+        let change = Utxo { amount: first.outputs[change].amount, txid: first.txid, vout: change as u32, height: chain.get_height() + 1, coinbase: false };
+        let second = pay(&chain, &mut wallet, &[change], 2000);
+        let commitment = chain.utxo_commitment.hash();
+        mine(&mut chain, &miner, vec![first.clone(), second.clone()]).unwrap();
+        assert!(!chain.coins.contains_key(&(first.txid, change.vout)));
+        assert!(chain.coins.contains_key(&(second.txid, 0)));
+        // disconnecting brings back exactly the coin the block spent from before it
+        chain.disconnect_tip().unwrap();
+        assert_eq!(chain.utxo_commitment.hash(), commitment);
+        assert!(chain.coins.contains_key(&(utxos[0].txid, utxos[0].vout)));
+    }
+
+    // a key with a small order component, and a signature a batch takes while a single check doesn't
+    fn torsioned_signer() -> (Scalar, [u8;32]) {
+        let secret = Scalar::from_bytes_mod_order(random::<[u8;32]>());
+        let key = ED25519_BASEPOINT_POINT * secret + EIGHT_TORSION[1];
+        (secret, key.compress().to_bytes())
+    }
+
+    fn torsioned_signature(secret: &Scalar, key: &[u8;32], message: &[u8;32]) -> Signature {
+        loop {
+            let nonce = Scalar::from_bytes_mod_order(random::<[u8;32]>());
+            let r = (ED25519_BASEPOINT_POINT * nonce).compress().to_bytes();
+            let challenge = Scalar::from_hash(Sha512::new().chain_update(r).chain_update(key).chain_update(message));
+            let mut bytes = r.to_vec();
+            bytes.extend_from_slice((nonce + challenge * secret).as_bytes());
+            let signature = Signature::from_slice(&bytes).unwrap();
+            // the torsion drops out of a batch when its random coefficient times the challenge is a multiple of 8
+            // and out of a single check only when the challenge is
+            let verifying_key = VerifyingKey::from_bytes(key).unwrap();
+            let batch_passes = ed25519_dalek::verify_batch(&[message.as_slice()], &[signature], &[verifying_key]).is_ok();
+            if batch_passes && verifying_key.verify_strict(message, &signature).is_err() {
+                return signature;
+            }
+        }
+    }
+
+    #[test]
+    fn batch_and_single_checks_reject_a_torsioned_key() {
+        let (secret, key) = torsioned_signer();
+        let mut chain = Blockchain::new(params());
+        let miner = miner(key);
+        mine(&mut chain, &miner, vec![]).unwrap();
+        let coinbase = &chain.chain[1].transactions[0];
+        let input = Input { txid: coinbase.txid, vout: 0, witness: vec![], sequence: SEQUENCE_FINAL };
+        let mut tx = Tx { txid: [0; 32], inputs: vec![input], outputs: vec![Output::to_address(coinbase.outputs[0].amount, key)], lock_time: 0 };
+        let signature = torsioned_signature(&secret, &key, &tx.sighash());
+        tx.inputs[0].witness = vec![signature.to_bytes().to_vec()];
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        assert!(!script::check_signature(&signature.to_bytes(), &key, &tx.sighash()));
+
+        let block = miner.build_block(2, chain.get_current_hash(), vec![tx.clone()], 0, &chain);
+        let spent = chain.find_spent_coins(&block).unwrap();
+        assert!(matches!(chain.validate_scripts(&block, &spent), Err(BlockError::InvalidScript(1, 0))));
+        assert!(matches!(chain.validate_scripts_individually(&block, &spent), Err(BlockError::InvalidScript(1, 0))));
+        assert!(matches!(chain.connect_block(block), Err(BlockError::InvalidScript(1, 0))));
+        let mut utxos = GlobalUtxos::new();
+        utxos.find_utxos(&chain);
+        assert!(matches!(Mempool::new().add_tx(tx.clone(), &chain, &utxos.find_spent_utxos(&tx)), Err(MempoolError::InvalidScript)));
+    }
+
+    // two chains sharing the first fork_height blocks, then mined on by different miners to the given heights
+    // only the first one prunes if the params say so, the other keeps its blocks to serve the branch
+    fn forked_chains(params: ChainParams, fork_height: u32, ours: u32, theirs: u32) -> (Blockchain, Blockchain) {
+        let mut chain = Blockchain::new(params.clone());
+        let mut other = Blockchain::new(ChainParams { prune_depth: 0, prune_size: 0, ..params });
+        let (miner, other_miner) = (miner([1; 32]), miner([2; 32]));
+        for _ in 0..fork_height {
+            mine(&mut chain, &miner, vec![]).unwrap();
+            other.connect_block(chain.chain.last().unwrap().clone()).unwrap();
+        }
+        (fork_height..ours).for_each(|_| mine(&mut chain, &miner, vec![]).unwrap());
+        (fork_height..theirs).for_each(|_| mine(&mut other, &other_miner, vec![]).unwrap());
+        (chain, other)
+    }
+
+    #[test]
+    fn reorganizes_onto_a_longer_branch() {
+        let (mut chain, other) = forked_chains(ChainParams::regtest(), 1, 3, 4);
+        let disconnected = chain.reorganize(other.chain[2..].to_vec()).unwrap();
+        assert_eq!(disconnected.len(), 2);
+        assert_eq!(chain.get_current_hash(), other.get_current_hash());
+        assert_eq!(chain.utxo_commitment.hash(), other.utxo_commitment.hash());
+    }
+
+    #[test]
+    fn keeps_the_chain_when_the_branch_is_shorter_or_invalid() {
+        let (mut chain, mut other) = forked_chains(ChainParams::regtest(), 1, 3, 4);
+        let (tip, commitment) = (chain.get_current_hash(), chain.utxo_commitment.hash());
+        assert!(matches!(chain.reorganize(other.chain[2..4].to_vec()), Err(BlockError::InsufficientWork)));
+        // the last block of the branch fails only after the ones before it were connected
+        other.chain[4].transactions[0].outputs[0].amount += 1;
+        assert!(matches!(chain.reorganize(other.chain[2..].to_vec()), Err(BlockError::InvalidTransaction)));
+        assert_eq!((chain.get_current_hash(), chain.utxo_commitment.hash()), (tip, commitment));
+        assert_eq!(chain.get_height(), 3);
     }
 
-    pub fn create_from_genesis(genesis: Block) -> Blockchain {
-        let mut chain = vec![];
-        chain.push(genesis);
-        Blockchain { chain }
+    #[test]
+    fn refuses_a_reorg_deeper_than_a_pruned_chain_kept() {
+        let params = ChainParams { prune_depth: MIN_PRUNE_DEPTH, ..ChainParams::regtest() };
+        let (mut chain, other) = forked_chains(params.clone(), 1, MIN_PRUNE_DEPTH + 2, MIN_PRUNE_DEPTH + 3);
+        assert_eq!(chain.pruned_height, 2);
+        let tip = chain.get_current_hash();
+        assert!(matches!(chain.reorganize(other.chain[2..].to_vec()), Err(BlockError::ReorgTooDeep)));
+        assert_eq!(chain.get_current_hash(), tip);
+        // a fork MIN_PRUNE_DEPTH blocks down is still followed
+        let (mut chain, other) = forked_chains(params, 2, MIN_PRUNE_DEPTH + 2, MIN_PRUNE_DEPTH + 3);
+        assert_eq!(chain.reorganize(other.chain[3..].to_vec()).unwrap().len() as u32, MIN_PRUNE_DEPTH);
+        assert_eq!(chain.get_current_hash(), other.get_current_hash());
     }
-}
\ No newline at end of file
+
+    // a chain keeping both indexes, logged to a fresh file named after the test
+    fn indexed_chain(name: &str) -> (Blockchain, Wallet, Miner, String) {
+        let path = std::env::temp_dir().join(format!("index-{}-{}.log", name, std::process::id())).to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&path);
+        let mut chain = Blockchain::new(ChainParams { tx_index: true, address_index: true, ..params() });
+        chain.open_index(&path).unwrap();
+        let wallet = Wallet::new();
+        let miner = miner(wallet.address());
+        mine(&mut chain, &miner, vec![]).unwrap();
+        (chain, wallet, miner, path)
+    }
+
+    #[test]
+    fn replays_connects_and_disconnects_from_the_index_log() {
+        let (mut chain, mut wallet, miner, path) = indexed_chain("replay");
+        let utxos = utxos_of(&chain, &wallet);
+        let dropped = pay(&chain, &mut wallet, &utxos, 1000);
+        mine(&mut chain, &miner, vec![dropped.clone()]).unwrap();
+        chain.disconnect_tip().unwrap();
+        let kept = pay(&chain, &mut wallet, &utxos, 2000);
+        mine(&mut chain, &miner, vec![kept.clone()]).unwrap();
+        let history = chain.index.address_history(&wallet.script_pubkey(), 0, 100).unwrap();
+        chain.open_index(&path).unwrap();
+        assert_eq!(chain.index.address_history(&wallet.script_pubkey(), 0, 100).unwrap(), history);
+        assert_eq!(chain.index.get_tx(&kept.txid), Some((chain.get_current_hash(), 1)));
+        assert_eq!(chain.index.get_tx(&dropped.txid), None);
+        assert_eq!(chain.index.blocks.len(), chain.chain.len());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_blocks_of_the_index_log_the_chain_doesnt_have() {
+        let (mut chain, mut wallet, miner, path) = indexed_chain("stale");
+        let utxos = utxos_of(&chain, &wallet);
+        let tx = pay(&chain, &mut wallet, &utxos, 1000);
+        mine(&mut chain, &miner, vec![tx.clone()]).unwrap();
+        let stale = chain.get_current_hash();
+        // the tip goes away without the log hearing of it, like a crash before it was written
+        chain.index = ChainIndex::new(true, true);
+        chain.disconnect_tip().unwrap();
+        chain.open_index(&path).unwrap();
+        let reindexed = format!("{}.fresh", path);
+        let mut fresh = Blockchain::new(chain.params.clone());
+        chain.chain[1..].iter().for_each(|block| fresh.connect_block(block.clone()).unwrap());
+        fresh.open_index(&reindexed).unwrap();
+        // the block is dropped on open, and the disconnect it appended keeps it dropped on the next one
+        for _ in 0..2 {
+            assert_eq!(chain.index.get_height(&stale), None);
+            assert_eq!(chain.index.get_tx(&tx.txid), None);
+            assert_eq!(chain.index.address_history(&wallet.script_pubkey(), 0, 100), fresh.index.address_history(&wallet.script_pubkey(), 0, 100));
+            chain.open_index(&path).unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&reindexed).unwrap();
+    }
+
+    // a block spending a 2 of 3 multisig output with the signatures of the keys at the given positions, in that order
+    fn multisig_spend(signers: [usize; 2]) -> (Blockchain, Block) {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        let keys = [Wallet::new(), Wallet::new(), Wallet::new()];
+        let script_pubkey = script::multisig(2, &keys.iter().map(Wallet::address).collect::<Vec<[u8;32]>>());
+        let utxos = utxos_of(&chain, &wallet);
+        let lock = wallet.send_to_script(5000, 0, script_pubkey.clone(), &utxos, chain.get_height() + 1, chain.params.coinbase_maturity).unwrap();
+        let locked = Utxo { txid: lock.txid, vout: 0, amount: 5000, height: chain.get_height() + 1, coinbase: false };
+        mine(&mut chain, &miner, vec![lock]).unwrap();
+        let mut spend = Wallet::create_multisig_tx(&[locked], vec![Output::to_address(5000, [9; 32])]);
+        spend.inputs[0].witness = signers.iter().map(|signer| keys[*signer].sign(&spend)).collect();
+        spend.txid = Tx::generate_txid(&spend.inputs, &spend.outputs, spend.lock_time);
+        let block = miner.build_block(chain.get_height() + 1, chain.get_current_hash(), vec![spend], 0, &chain);
+        (chain, block)
+    }
+
+    #[test]
+    fn pairs_multisig_signatures_that_skip_a_key_in_batch_and_single_checks() {
+        let (chain, block) = multisig_spend([0, 2]);
+        let spent = chain.find_spent_coins(&block).unwrap();
+        // the signature of the last key is only deferred against that key, so the batch holds without falling back
+        let deferred = chain.defer_signatures(&block.transactions[1], &spent[1]).unwrap();
+        let keys: Vec<VerifyingKey> = deferred.iter().map(|(key, _, _)| *key).collect();
+        let signatures: Vec<Signature> = deferred.iter().map(|(_, signature, _)| *signature).collect();
+        let messages: Vec<&[u8]> = deferred.iter().map(|(_, _, sighash)| sighash.as_slice()).collect();
+        assert!(ed25519_dalek::verify_batch(&messages, &signatures, &keys).is_ok());
+        assert!(chain.validate_scripts_individually(&block, &spent).is_ok());
+        assert!(chain.validate_scripts(&block, &spent).is_ok());
+    }
+
+    #[test]
+    fn rejects_multisig_signatures_out_of_key_order() {
+        let (mut chain, block) = multisig_spend([2, 0]);
+        let spent = chain.find_spent_coins(&block).unwrap();
+        assert!(matches!(chain.validate_scripts_individually(&block, &spent), Err(BlockError::InvalidScript(1, 0))));
+        assert!(matches!(chain.connect_block(block), Err(BlockError::InvalidScript(1, 0))));
+    }
+
+    // a chain whose clock moves ten minutes before every block, with four coinbases paid to the wallet
+    fn clocked_chain() -> (Blockchain, Arc<MockClock>, Wallet, Miner) {
+        let params = params();
+        let clock = Arc::new(MockClock::new(params.genesis_time));
+        let mut chain = Blockchain::with_clock(params, clock.clone());
+        let wallet = Wallet::new();
+        let miner = miner(wallet.address());
+        (0..4).for_each(|_| mine_later(&mut chain, &clock, &miner, vec![]).unwrap());
+        (chain, clock, wallet, miner)
+    }
+
+    fn mine_later(chain: &mut Blockchain, clock: &MockClock, miner: &Miner, transactions: Vec<Tx>) -> Result<(), BlockError> {
+        clock.advance(600);
+        mine(chain, miner, transactions)
+    }
+
+    // spends the coin to a new key, the locks change the sighash so they're set before signing
+    fn locked_spend(wallet: &Wallet, utxo: &Utxo, lock_time: u32, sequence: u32) -> Tx {
+        let input = Input { txid: utxo.txid, vout: utxo.vout, witness: vec![], sequence: SEQUENCE_FINAL };
+        let mut tx = Tx { txid: [0; 32], inputs: vec![input], outputs: vec![Output::to_address(utxo.amount, Wallet::new().address())], lock_time: 0 };
+        tx.set_sequence(0, sequence);
+        if lock_time != 0 {
+            tx.set_lock_time(lock_time);
+        }
+        tx.inputs[0].witness = vec![wallet.sign(&tx)];
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        tx
+    }
+
+    #[test]
+    fn rejects_txs_mined_before_their_locks() {
+        let (mut chain, clock, wallet, miner) = clocked_chain();
+        let mut coins = utxos_of(&chain, &wallet);
+        coins.sort_by_key(|coin| coin.height);
+        let next = chain.get_height() + 1;
+        // a height and a time lock, then relative ones of three blocks and 1024 seconds on the two newest coins
+        let txs = vec![
+            locked_spend(&wallet, &coins[0], next, SEQUENCE_FINAL),
+            locked_spend(&wallet, &coins[1], chain.median_time_past() as u32 + 1200, SEQUENCE_FINAL),
+            locked_spend(&wallet, &coins[3], 0, Input::relative_height_lock(3)),
+            locked_spend(&wallet, &coins[2], 0, Input::relative_time_lock(1024)),
+        ];
+        for tx in &txs {
+            assert!(matches!(mine_later(&mut chain, &clock, &miner, vec![tx.clone()]), Err(BlockError::TimeLocked)));
+        }
+        assert_eq!(chain.get_height(), next - 1);
+        (0..8).for_each(|_| mine_later(&mut chain, &clock, &miner, vec![]).unwrap());
+        mine_later(&mut chain, &clock, &miner, txs).unwrap();
+    }
+
+    // the block the miner would build next, with its timestamp replaced
+    fn block_at(chain: &Blockchain, miner: &Miner, time: u64) -> Block {
+        let mut block = miner.build_block(chain.get_height() + 1, chain.get_current_hash(), vec![], 0, chain);
+        block.time = time;
+        block.hash = block.header().calc_hash();
+        block
+    }
+
+    #[test]
+    fn rejects_a_block_timed_at_or_before_the_median_time_past() {
+        let (mut chain, clock, _, miner) = clocked_chain();
+        let median = chain.median_time_past();
+        assert!(matches!(chain.connect_block(block_at(&chain, &miner, median)), Err(BlockError::TimeTooOld)));
+        assert!(matches!(chain.connect_block(block_at(&chain, &miner, median - 1)), Err(BlockError::TimeTooOld)));
+        // a clock that fell behind the chain doesn't keep a block just past the median out
+        clock.set(median - 3600);
+        chain.connect_block(block_at(&chain, &miner, median + 1)).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_block_timed_too_far_ahead_of_the_clock() {
+        let (mut chain, clock, _, miner) = clocked_chain();
+        let limit = clock.now() + chain.params.max_future_drift;
+        assert!(matches!(chain.connect_block(block_at(&chain, &miner, limit + 1)), Err(BlockError::TimeTooNew)));
+        chain.connect_block(block_at(&chain, &miner, limit)).unwrap();
+        // the same block is fine once the clock has caught up with it
+        let early = block_at(&chain, &miner, limit + 600);
+        assert!(matches!(chain.connect_block(early.clone()), Err(BlockError::TimeTooNew)));
+        clock.advance(600);
+        chain.connect_block(early).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_coinbase_paying_more_than_the_subsidy_and_fees() {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        let utxos = utxos_of(&chain, &wallet);
+        let tx = wallet.send_to_script(1000, 100, Wallet::new().script_pubkey(), &utxos, chain.get_height() + 1, chain.params.coinbase_maturity).unwrap();
+        let height = chain.get_height() + 1;
+        let greedy = miner.build_block(height, chain.get_current_hash(), vec![tx.clone()], 101, &chain);
+        assert!(matches!(chain.connect_block(greedy), Err(BlockError::BadCoinbaseAmount)));
+        let honest = miner.build_block(height, chain.get_current_hash(), vec![tx], 100, &chain);
+        assert_eq!(honest.transactions[0].outputs.iter().map(|output| output.amount).sum::<u64>(), chain.params.subsidy(height) + 100);
+        chain.connect_block(honest).unwrap();
+    }
+}
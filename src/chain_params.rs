@@ -0,0 +1,308 @@
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+
+use crate::block::{Block, BlockHeader};
+use crate::util::from_hex;
+
+// a pruned node always keeps the bodies of this many blocks below the tip, the deepest reorg it can follow
+pub const MIN_PRUNE_DEPTH: u32 = 10;
+
+// consensus and policy values that differ between networks
+#[derive(Clone)]
+pub struct ChainParams {
+    pub name: String,
+    // subsidy of the first era, halved every halving_interval blocks
+    pub initial_reward: u64,
+    pub halving_interval: u32,
+    // total subsidy ever paid out never exceeds this, the last paying block is cut short if needed
+    pub max_supply: u64,
+    // how many blocks must be built on top of a coinbase before its outputs can be spent
+    pub coinbase_maturity: u32,
+    pub max_block_size: u32,
+    pub max_mempool_size: u32,
+    // how many verified signatures are remembered between mempool admission and block validation
+    pub signature_cache_size: usize,
+    // keeps a txid index, so txs are found without scanning the chain
+    pub tx_index: bool,
+    // keeps the history of every address, for explorers and wallet history
+    pub address_index: bool,
+    // drops the bodies and undo data of blocks this deep below the tip, 0 keeps them all
+    pub prune_depth: u32,
+    // drops the oldest bodies once the ones kept take more bytes than this, 0 for no limit
+    pub prune_size: u64,
+    pub target: u64,
+    pub genesis_time: u64,
+    // how many seconds a block time may be ahead of our clock
+    pub max_future_drift: u64,
+    pub rpc_port: u16,
+    // human readable part of addresses, so coins can't be sent to an address of another network by mistake
+    pub address_prefix: String,
+    // the commitment of the one utxo snapshot a node will start from, none by default, so snapshots must be trusted explicitly
+    pub snapshot_hash: Option<[u8;32]>,
+}
+
+// a params file names the profile it starts from and overrides any of its values
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ParamsFile {
+    base: Option<String>,
+    name: Option<String>,
+    initial_reward: Option<u64>,
+    halving_interval: Option<u32>,
+    max_supply: Option<u64>,
+    coinbase_maturity: Option<u32>,
+    max_block_size: Option<u32>,
+    max_mempool_size: Option<u32>,
+    signature_cache_size: Option<usize>,
+    tx_index: Option<bool>,
+    address_index: Option<bool>,
+    prune_depth: Option<u32>,
+    prune_size: Option<u64>,
+    target: Option<u64>,
+    genesis_time: Option<u64>,
+    max_future_drift: Option<u64>,
+    rpc_port: Option<u16>,
+    address_prefix: Option<String>,
+    snapshot_hash: Option<String>,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> ChainParams {
+        ChainParams {
+            name: "main".to_string(),
+            initial_reward: 5000000,
+            halving_interval: 210000,
+            max_supply: 2100000000000,
+            coinbase_maturity: 100,
+            max_block_size: 100000,
+            max_mempool_size: 150000,
+            signature_cache_size: 50000,
+            tx_index: false,
+            address_index: false,
+            prune_depth: 0,
+            prune_size: 0,
+            target: 2u64.pow(64-5),
+            genesis_time: 1700000000,
+            max_future_drift: 7200,
+            rpc_port: 7332,
+            address_prefix: "tx".to_string(),
+            snapshot_hash: None,
+        }
+    }
+
+    pub fn testnet() -> ChainParams {
+        ChainParams {
+            name: "test".to_string(),
+            target: 2u64.pow(64-3),
+            genesis_time: 1700000001,
+            rpc_port: 17332,
+            address_prefix: "ttx".to_string(),
+            ..ChainParams::mainnet()
+        }
+    }
+
+    // every hash meets the regtest target, so blocks are mined instantly
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            name: "regtest".to_string(),
+            halving_interval: 150,
+            target: u64::MAX,
+            genesis_time: 1700000002,
+            rpc_port: 27332,
+            address_prefix: "rtx".to_string(),
+            ..ChainParams::mainnet()
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<ChainParams, ParamsError> {
+        match name {
+            "main" | "mainnet" => Ok(ChainParams::mainnet()),
+            "test" | "testnet" => Ok(ChainParams::testnet()),
+            "regtest" => Ok(ChainParams::regtest()),
+            _ => Err(ParamsError::UnknownNetwork(name.to_string())),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<ChainParams, ParamsError> {
+        let file: ParamsFile = toml::from_str(&fs::read_to_string(path)?).map_err(ParamsError::Parse)?;
+        let base = ChainParams::from_name(file.base.as_deref().unwrap_or("main"))?;
+        if file.halving_interval == Some(0) {
+            return Err(ParamsError::Invalid("halving_interval must be at least 1".to_string()));
+        }
+        if file.prune_depth.is_some_and(|depth| depth != 0 && depth < MIN_PRUNE_DEPTH) {
+            return Err(ParamsError::Invalid(format!("prune_depth must be 0 or at least {}", MIN_PRUNE_DEPTH)));
+        }
+        // addresses are all one case, so the prefix can't have upper case letters
+        if file.address_prefix.as_ref().is_some_and(|prefix| prefix.is_empty() || !prefix.bytes().all(|byte| (33..=126).contains(&byte) && !byte.is_ascii_uppercase())) {
+            return Err(ParamsError::Invalid("address_prefix must be printable ascii without upper case letters".to_string()));
+        }
+        let snapshot_hash = match file.snapshot_hash {
+            Some(hex) => Some(from_hex(&hex).and_then(|bytes| bytes.try_into().ok()).ok_or(ParamsError::Invalid("snapshot_hash must be 32 bytes of hex".to_string()))?),
+            None => base.snapshot_hash,
+        };
+        Ok(ChainParams {
+            name: file.name.unwrap_or(base.name),
+            initial_reward: file.initial_reward.unwrap_or(base.initial_reward),
+            halving_interval: file.halving_interval.unwrap_or(base.halving_interval),
+            max_supply: file.max_supply.unwrap_or(base.max_supply),
+            coinbase_maturity: file.coinbase_maturity.unwrap_or(base.coinbase_maturity),
+            max_block_size: file.max_block_size.unwrap_or(base.max_block_size),
+            max_mempool_size: file.max_mempool_size.unwrap_or(base.max_mempool_size),
+            signature_cache_size: file.signature_cache_size.unwrap_or(base.signature_cache_size),
+            tx_index: file.tx_index.unwrap_or(base.tx_index),
+            address_index: file.address_index.unwrap_or(base.address_index),
+            prune_depth: file.prune_depth.unwrap_or(base.prune_depth),
+            prune_size: file.prune_size.unwrap_or(base.prune_size),
+            target: file.target.unwrap_or(base.target),
+            genesis_time: file.genesis_time.unwrap_or(base.genesis_time),
+            max_future_drift: file.max_future_drift.unwrap_or(base.max_future_drift),
+            rpc_port: file.rpc_port.unwrap_or(base.rpc_port),
+            address_prefix: file.address_prefix.unwrap_or(base.address_prefix),
+            snapshot_hash,
+        })
+    }
+
+    pub fn prunes(&self) -> bool { self.prune_depth != 0 || self.prune_size != 0 }
+
+    // the block subsidy is whatever the supply grows by at this height, so the cap is never exceeded
+    pub fn subsidy(&self, height: u32) -> u64 {
+        if height == 0 {
+            return 0;
+        }
+        self.total_supply_at(height) - self.total_supply_at(height - 1)
+    }
+
+    // total subsidy paid out by all blocks up to and including this height, genesis pays nothing
+    pub fn total_supply_at(&self, height: u32) -> u64 {
+        let interval = self.halving_interval as u64;
+        let height = height as u64;
+        let mut total: u64 = 0;
+        let mut era_start = 1;
+        // adds up whole eras at a time, each era paying half of the one before
+        while era_start <= height {
+            let era = era_start / interval;
+            let reward = if era >= 64 { 0 } else { self.initial_reward >> era };
+            if reward == 0 {
+                break;
+            }
+            let era_end = ((era + 1) * interval - 1).min(height);
+            total = total.saturating_add(reward.saturating_mul(era_end - era_start + 1));
+            era_start = era_end + 1;
+        }
+        total.min(self.max_supply)
+    }
+
+    // the genesis block is fully determined by the params, searching nonces upwards from zero
+    pub fn genesis(&self) -> Block {
+        let mut header = BlockHeader { index: 0, hash: [0; 32], previous_hash: [0; 32], merkle_root: Block::calc_merkle_root(&[]), time: self.genesis_time, target: self.target, nonce: 0 };
+        header.hash = header.calc_hash();
+        while !header.meets_target() {
+            header.nonce += 1;
+            header.hash = header.calc_hash();
+        }
+        Block::from_header(header, Vec::new())
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamsError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    UnknownNetwork(String),
+    Invalid(String),
+}
+
+impl From<io::Error> for ParamsError {
+    fn from(error: io::Error) -> ParamsError { ParamsError::Io(error) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 100 a block, halving every 10 blocks, capped at max_supply
+    fn small(max_supply: u64) -> ChainParams {
+        ChainParams { initial_reward: 100, halving_interval: 10, max_supply, ..ChainParams::regtest() }
+    }
+
+    #[test]
+    fn halves_the_subsidy_at_each_interval() {
+        let params = ChainParams::regtest();
+        assert_eq!(params.subsidy(0), 0);
+        assert_eq!(params.subsidy(1), params.initial_reward);
+        assert_eq!(params.subsidy(params.halving_interval - 1), params.initial_reward);
+        assert_eq!(params.subsidy(params.halving_interval), params.initial_reward / 2);
+        assert_eq!(params.subsidy(2 * params.halving_interval - 1), params.initial_reward / 2);
+        assert_eq!(params.subsidy(2 * params.halving_interval), params.initial_reward / 4);
+        // the reward runs out once it has been halved down to nothing
+        let params = small(u64::MAX);
+        assert_eq!(params.subsidy(69), 1);
+        assert_eq!(params.subsidy(70), 0);
+        assert_eq!(params.total_supply_at(u32::MAX), params.total_supply_at(69));
+    }
+
+    #[test]
+    fn never_pays_out_more_than_the_supply_cap() {
+        // 900 in the first era and 500 in the second, then 25 a block up to the cap
+        let params = small(1500);
+        assert_eq!(params.total_supply_at(19), 1400);
+        assert_eq!(params.subsidy(23), 25);
+        assert_eq!(params.total_supply_at(23), 1500);
+        assert_eq!(params.subsidy(24), 0);
+        // the last paying block is cut short
+        let params = small(1490);
+        assert_eq!(params.subsidy(23), 15);
+        assert_eq!(params.subsidy(24), 0);
+        assert_eq!(params.total_supply_at(u32::MAX), 1490);
+        let params = ChainParams::mainnet();
+        assert!(params.total_supply_at(u32::MAX) <= params.max_supply);
+        assert_eq!(params.subsidy(u32::MAX), 0);
+    }
+
+    // loads the toml from a file in the temp dir named after the test
+    fn load(name: &str, toml: &str) -> Result<ChainParams, ParamsError> {
+        let path = std::env::temp_dir().join(format!("params-{}-{}.toml", name, std::process::id())).to_string_lossy().into_owned();
+        fs::write(&path, toml).unwrap();
+        ChainParams::load(&path)
+    }
+
+    #[test]
+    fn loads_a_profile_over_its_base() {
+        let profile = format!("base = \"regtest\"
+name = \"dev\"
+coinbase_maturity = 5
+prune_depth = 10
+address_prefix = \"dev\"
+snapshot_hash = \"{}\"
+", "ab".repeat(32));
+        let params = load("valid", &profile).unwrap();
+        assert_eq!((params.name.as_str(), params.coinbase_maturity, params.prune_depth, params.address_prefix.as_str()), ("dev", 5, 10, "dev"));
+        assert_eq!(params.snapshot_hash, Some([0xab; 32]));
+        // the rest comes from the base, and a file without one starts from mainnet
+        assert_eq!(params.target, ChainParams::regtest().target);
+        assert_eq!(load("empty", "").unwrap().rpc_port, ChainParams::mainnet().rpc_port);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_invalid_values() {
+        assert!(matches!(load("unknown", "coinbase_maturty = 5"), Err(ParamsError::Parse(_))));
+        assert!(matches!(load("type", "coinbase_maturity = -1"), Err(ParamsError::Parse(_))));
+        assert!(matches!(load("base", "base = \"nowhere\""), Err(ParamsError::UnknownNetwork(name)) if name == "nowhere"));
+        let invalid = [
+            "halving_interval = 0",
+            "prune_depth = 9",
+            "address_prefix = \"\"",
+            "address_prefix = \"TX\"",
+            "address_prefix = \"t x\"",
+            "address_prefix = \"té\"",
+            "snapshot_hash = \"abcd\"",
+            "snapshot_hash = \"zz\"",
+        ];
+        for toml in invalid {
+            assert!(matches!(load("invalid", toml), Err(ParamsError::Invalid(_))), "{}", toml);
+        }
+        assert!(matches!(ChainParams::load("/nonexistent/params.toml"), Err(ParamsError::Io(_))));
+    }
+}
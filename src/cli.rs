@@ -0,0 +1,713 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use num_format::{Locale, ToFormattedString};
+use serde_json::{json, Value};
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::chain_params::{ChainParams, ParamsError};
+use crate::explorer;
+use crate::json::BlockJson;
+use crate::node::Node;
+use crate::rpc;
+use crate::snapshot::{self, UtxoSnapshot};
+use crate::sync::Peer;
+use crate::util::from_hex;
+
+const USAGE: &str = "usage: Transactions [--json] [--network main|test|regtest] [--config FILE] [--port PORT] [--cookie FILE] <command>
+
+the node writes the cookie every other command authenticates with to FILE, .<network>.cookie by default
+
+commands:
+    node run [--index FILE] [--explorer PORT] [--snapshot FILE [--snapshot-hash HASH] [--history DUMP]]
+                                              start a node serving json-rpc on localhost, keeping its index in FILE
+                                              and serving a block explorer on PORT, optionally starting from a utxo
+                                              snapshot with the hash the params or HASH trust and checking it against
+                                              a chain dump in the background
+    wallet create                             generate a new address
+    wallet balance                            show the balance of every address in the node wallet
+    wallet address                            list the node wallet addresses
+    wallet send <address> <amount> [--fee F]  pay an address from the node wallet
+    wallet history [--skip N] [--count N]     list the transactions that changed the node wallet, newest first
+    chain info                                show the current chain tip
+    chain block <hash|height>                 show a block and its transactions
+    chain tx <txid>                           show a transaction
+    chain address <address> [--skip N] [--count N]
+                                              show the balance and history of an address
+    chain utxoset                             show the size of the utxo set and its commitment
+    chain snapshot <file> [--height N]        write the utxo set at a height, the tip by default, to a file on the node
+    chain dump <file>                         write every block to file as json, one per line
+    chain import <file>                       submit the blocks of a dump the node doesn't have yet
+    mine --blocks N [--threads T] [--address A]
+                                              mine blocks, paying the reward to the wallet or address
+    mempool show                              list transactions waiting to be mined
+    multisig create <required> <address>...   build a script locking coins to required of the addresses
+    multisig send <script> <amount> [--fee F] lock coins of the node wallet to a multisig script
+    multisig spend <script> <address> <amount> [--fee F]
+                                              spend coins locked to the script as a pst for the co-signers to sign
+    htlc create <address> <hash> <timeout> <amount> [--fee F]
+                                              lock coins the address can claim with the preimage of the hash, the
+                                              node wallet can take them back from the timeout height or unix time
+    htlc claim <txid> <vout> <preimage> [--fee F]
+                                              claim htlc coins locked to the node wallet
+    htlc refund <txid> <vout> [--fee F]       take back htlc coins once the timeout has passed
+    pst create <address> <amount> [--fee F] [--locktime N] [--after-blocks N | --after-seconds N]
+                                              fund a payment from the node wallet without signing it, optionally
+                                              not minable before a height or unix time, or before the coins it
+                                              spends are N blocks or seconds old
+    pst sign <pst>                            add signatures from the node wallet
+    pst combine <pst> <pst>...                merge signatures collected separately
+    pst finalize <pst>                        build the signed transaction and broadcast it
+    pst decode <pst>                          show a partially signed transaction
+    bench                                     run the original benchmark";
+
+// options that take a value, anything else starting with -- is a flag
+const VALUE_OPTIONS: [&str; 19] = ["network", "config", "port", "cookie", "fee", "blocks", "threads", "address", "index", "skip", "count", "explorer", "snapshot", "snapshot-hash", "history", "height", "locktime", "after-blocks", "after-seconds"];
+
+// where the node serves json-rpc and the cookie file it wrote
+struct Connection {
+    port: u16,
+    cookie: String,
+}
+
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    json: bool,
+}
+
+pub fn run(args: &[String]) -> Result<(), CliError> {
+    let args = parse(args)?;
+    let params = chain_params(&args)?;
+    let port = match args.options.get("port") {
+        Some(port) => port.parse().map_err(|_| CliError::Usage(format!("invalid port {}", port)))?,
+        None => params.rpc_port,
+    };
+    let connection = Connection { port, cookie: args.options.get("cookie").cloned().unwrap_or(format!(".{}.cookie", params.name)) };
+    let command: Vec<&str> = args.positional.iter().map(String::as_str).collect();
+    match command.as_slice() {
+        ["node", "run"] => {
+            println!("Starting {} node", params.name);
+            // a chain dump to replay the history before the snapshot from, it's only read once the node is running
+            let history = match (args.options.get("snapshot"), args.options.get("history")) {
+                (None, Some(_)) => return Err(CliError::Usage("--history needs --snapshot FILE".to_string())),
+                (_, history) => history.cloned(),
+            };
+            if args.options.contains_key("snapshot-hash") && !args.options.contains_key("snapshot") {
+                return Err(CliError::Usage("--snapshot-hash needs --snapshot FILE".to_string()));
+            }
+            let mut node = match args.options.get("snapshot") {
+                Some(path) => {
+                    let snapshot = UtxoSnapshot::load(path).map_err(|error| CliError::Usage(format!("{}: {}", path, error)))?;
+                    println!("Loaded snapshot at height {} with {} coins", snapshot.height(), snapshot.coins.len());
+                    Node::from_snapshot(params.clone(), &snapshot).map_err(|error| CliError::Usage(format!("{}: {}", path, error)))?
+                }
+                None => Node::new(params.clone()),
+            };
+            if let Some(path) = args.options.get("index") {
+                node.chain.open_index(path)?;
+            }
+            let node = Arc::new(Mutex::new(node));
+            if let Some(path) = history {
+                let node = Arc::clone(&node);
+                thread::spawn(move || {
+                    let result = load_dump(&path, &params).and_then(|store| {
+                        let peers: Vec<Arc<dyn Peer>> = vec![Arc::new(store)];
+                        snapshot::validate_history(&node, &peers).map_err(|error| CliError::Usage(error.to_string()))
+                    });
+                    match result {
+                        Ok(true) => println!("Snapshot validated against the history in {}", path),
+                        // the chain stopped growing, the node shouldn't keep serving what it built on the snapshot either
+                        Ok(false) => {
+                            eprintln!("Snapshot does not match the history in {}, stopping", path);
+                            process::exit(1);
+                        }
+                        Err(error) => eprintln!("Snapshot validation failed: {}", error),
+                    }
+                });
+            }
+            if let Some(explorer_port) = args.options.get("explorer") {
+                let explorer_port: u16 = explorer_port.parse().map_err(|_| CliError::Usage(format!("invalid port {}", explorer_port)))?;
+                let listener = TcpListener::bind(("127.0.0.1", explorer_port))?;
+                let node = Arc::clone(&node);
+                thread::spawn(move || explorer::serve(node, listener));
+                println!("Block explorer on http://127.0.0.1:{}", explorer_port);
+            }
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            let cookie = rpc::write_cookie(&connection.cookie)?;
+            println!("JSON-RPC server listening on 127.0.0.1:{}, cookie in {}", port, connection.cookie);
+            rpc::serve(node, listener, &cookie);
+            Ok(())
+        }
+        ["wallet", "create"] => {
+            let address = call(&connection, "getnewaddress", json!([]))?;
+            output(args.json, &address, |address| println!("New address: {}", address.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["wallet", "balance"] => {
+            let balances = call(&connection, "getbalances", json!([]))?;
+            output(args.json, &balances, |balances| {
+                println!("Balance:  {}", balances["trusted"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+                println!("Immature: {}", balances["immature"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+            });
+            Ok(())
+        }
+        ["wallet", "address"] => {
+            let addresses = call(&connection, "listaddresses", json!([]))?;
+            output(args.json, &addresses, |addresses| {
+                addresses.as_array().into_iter().flatten().for_each(|address| println!("{}", address.as_str().unwrap_or_default()));
+            });
+            Ok(())
+        }
+        ["wallet", "send", address, amount] => {
+            let amount: u64 = amount.parse().map_err(|_| CliError::Usage(format!("invalid amount {}", amount)))?;
+            let fee: u64 = option_u64(&args, "fee")?.unwrap_or(rpc::DEFAULT_MINING_FEE);
+            let txid = call(&connection, "sendtoaddress", json!([address, amount, fee]))?;
+            output(args.json, &txid, |txid| println!("Sent {} to {}\nTxid: {}", amount.to_formatted_string(&Locale::en), address, txid.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["wallet", "history"] => {
+            let history = call(&connection, "listtransactions", json!(page_options(&args)?))?;
+            output(args.json, &history, print_history);
+            Ok(())
+        }
+        ["chain", "info"] => {
+            let info = call(&connection, "getblockchaininfo", json!([]))?;
+            output(args.json, &info, |info| {
+                if let Some(snapshot) = info["snapshot"].as_object() {
+                    let status = match snapshot["validated"].as_bool() {
+                        Some(true) => "validated",
+                        Some(false) => "does not match the chain",
+                        None => "not validated yet",
+                    };
+                    println!("Snapshot:    height {}, {}", snapshot["height"], status);
+                }
+                println!("Network:     {}", info["chain"].as_str().unwrap_or_default());
+                println!("Height:      {}", info["blocks"]);
+                println!("Best block:  {}", info["bestblockhash"].as_str().unwrap_or_default());
+                println!("Median time: {}", info["mediantime"]);
+                println!("Target:      {}", info["target"].as_str().unwrap_or_default());
+                println!("Chain size:  {} Bytes", info["size"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+                println!("Supply:      {}", info["supply"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+                if info["pruned"].as_bool().unwrap_or_default() {
+                    println!("Pruned:      bodies up to height {} dropped", info["pruneheight"]);
+                }
+            });
+            Ok(())
+        }
+        ["chain", "block", block] => {
+            // anything that parses as a number is a height, otherwise it's a block hash
+            let block = match block.parse::<u64>() {
+                Ok(height) => json!(height),
+                Err(_) => json!(block),
+            };
+            let block = call(&connection, "getblock", json!([block, true]))?;
+            output(args.json, &block, print_block);
+            Ok(())
+        }
+        ["chain", "tx", txid] => {
+            let tx = call(&connection, "getrawtransaction", json!([txid, true]))?;
+            output(args.json, &tx, print_tx);
+            Ok(())
+        }
+        ["chain", "address", address] => {
+            let mut params = vec![json!(address)];
+            params.extend(page_options(&args)?);
+            let history = call(&connection, "getaddresshistory", json!(params))?;
+            output(args.json, &history, |history| {
+                println!("Address: {}", history["address"].as_str().unwrap_or_default());
+                println!("Balance: {}", history["balance"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+                print_history(history);
+            });
+            Ok(())
+        }
+        ["chain", "utxoset"] => {
+            let info = call(&connection, "gettxoutsetinfo", json!([]))?;
+            output(args.json, &info, |info| {
+                println!("Height:     {}", info["height"]);
+                println!("Outputs:    {}", info["txouts"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+                println!("Amount:     {}", info["total_amount"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+                println!("Commitment: {}", info["commitment"].as_str().unwrap_or_default());
+            });
+            Ok(())
+        }
+        ["chain", "snapshot", path] => {
+            let mut params = vec![json!(path)];
+            params.extend(option_u64(&args, "height")?.map(|height| json!(height)));
+            let snapshot = call(&connection, "dumpsnapshot", json!(params))?;
+            output(args.json, &snapshot, |snapshot| {
+                println!("Wrote {} coins at height {} to {}", snapshot["coins"], snapshot["height"], path);
+                println!("Snapshot hash: {}", snapshot["hash"].as_str().unwrap_or_default());
+            });
+            Ok(())
+        }
+        // one verbose block per line, genesis first
+        ["chain", "dump", path] => {
+            let tip = call(&connection, "getblockchaininfo", json!([]))?["blocks"].as_u64().unwrap_or_default();
+            let mut file = BufWriter::new(File::create(path).map_err(|error| file_error(path, error))?);
+            for height in 0..=tip {
+                let block = call(&connection, "getblock", json!([height, true]))?;
+                writeln!(file, "{}", block).map_err(|error| file_error(path, error))?;
+            }
+            file.flush().map_err(|error| file_error(path, error))?;
+            output(args.json, &json!(tip + 1), |blocks| println!("Wrote {} blocks to {}", blocks, path));
+            Ok(())
+        }
+        // blocks the node already has are skipped, so a dump of a longer chain can be imported again
+        ["chain", "import", path] => {
+            let file = File::open(path).map_err(|error| file_error(path, error))?;
+            let tip = call(&connection, "getblockchaininfo", json!([]))?["blocks"].as_u64().unwrap_or_default();
+            let mut imported = 0;
+            for (number, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(|error| file_error(path, error))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let block: BlockJson = serde_json::from_str(&line)
+                    .map_err(|error| CliError::Usage(format!("{} line {}: {}", path, number + 1, error)))?;
+                if block.height as u64 <= tip {
+                    let known = call(&connection, "getblockhash", json!([block.height]))?;
+                    if known.as_str() != Some(block.hash.as_str()) {
+                        return Err(CliError::Usage(format!("{} line {}: block {} conflicts with the node's chain", path, number + 1, block.height)));
+                    }
+                    continue;
+                }
+                call(&connection, "submitblock", json!([block]))?;
+                imported += 1;
+            }
+            output(args.json, &json!(imported), |blocks| println!("Imported {} blocks from {}", blocks, path));
+            Ok(())
+        }
+        ["mine"] => {
+            let blocks = option_u64(&args, "blocks")?.ok_or(CliError::Usage("mine needs --blocks N".to_string()))?;
+            let threads = option_u64(&args, "threads")?.unwrap_or(1);
+            let address = args.options.get("address").map(|address| json!(address)).unwrap_or(Value::Null);
+            let hashes = call(&connection, "generate", json!([blocks, address, threads]))?;
+            output(args.json, &hashes, |hashes| {
+                hashes.as_array().into_iter().flatten().for_each(|hash| println!("Mined block {}", hash.as_str().unwrap_or_default()));
+            });
+            Ok(())
+        }
+        ["mempool", "show"] => {
+            let transactions = call(&connection, "getrawmempool", json!([true]))?;
+            output(args.json, &transactions, |transactions| {
+                let transactions = transactions.as_array().cloned().unwrap_or_default();
+                println!("{:<66} {:>8} {:>12}", "Txid", "Size", "Fee");
+                transactions.iter().for_each(|tx| println!("{:<66} {:>8} {:>12}",
+                    tx["txid"].as_str().unwrap_or_default(),
+                    tx["size"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en),
+                    tx["fee"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en)));
+                let bytes: u64 = transactions.iter().map(|tx| tx["size"].as_u64().unwrap_or_default()).sum();
+                println!("\n{} transactions, {} Bytes", transactions.len(), bytes.to_formatted_string(&Locale::en));
+            });
+            Ok(())
+        }
+        ["multisig", "create", required, addresses @ ..] if !addresses.is_empty() => {
+            let required: u64 = required.parse().map_err(|_| CliError::Usage(format!("invalid count {}", required)))?;
+            let multisig = call(&connection, "createmultisig", json!([required, addresses]))?;
+            output(args.json, &multisig, |multisig| {
+                eprintln!("{} of {} keys", multisig["required"], multisig["keys"]);
+                println!("{}", multisig["script"].as_str().unwrap_or_default());
+            });
+            Ok(())
+        }
+        ["multisig", "send", script, amount] => {
+            let amount: u64 = amount.parse().map_err(|_| CliError::Usage(format!("invalid amount {}", amount)))?;
+            let fee: u64 = option_u64(&args, "fee")?.unwrap_or(rpc::DEFAULT_MINING_FEE);
+            let txid = call(&connection, "sendtomultisig", json!([script, amount, fee]))?;
+            output(args.json, &txid, |txid| println!("Locked {} to the script\nTxid: {}", amount.to_formatted_string(&Locale::en), txid.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["multisig", "spend", script, address, amount] => {
+            let amount: u64 = amount.parse().map_err(|_| CliError::Usage(format!("invalid amount {}", amount)))?;
+            let fee: u64 = option_u64(&args, "fee")?.unwrap_or(rpc::DEFAULT_MINING_FEE);
+            let pst = call(&connection, "createmultisigpst", json!([script, address, amount, fee]))?;
+            output(args.json, &pst, |pst| println!("{}", pst.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["htlc", "create", address, hash, timeout, amount] => {
+            let timeout: u32 = timeout.parse().map_err(|_| CliError::Usage(format!("invalid timeout {}", timeout)))?;
+            let amount: u64 = amount.parse().map_err(|_| CliError::Usage(format!("invalid amount {}", amount)))?;
+            let fee: u64 = option_u64(&args, "fee")?.unwrap_or(rpc::DEFAULT_MINING_FEE);
+            let htlc = call(&connection, "createhtlc", json!([address, hash, timeout, amount, fee]))?;
+            output(args.json, &htlc, |htlc| println!("Locked {} in an htlc\nOutput: {}:{}",
+                amount.to_formatted_string(&Locale::en), htlc["txid"].as_str().unwrap_or_default(), htlc["vout"]));
+            Ok(())
+        }
+        ["htlc", "claim", txid, vout, preimage] => {
+            let vout: u32 = vout.parse().map_err(|_| CliError::Usage(format!("invalid vout {}", vout)))?;
+            let fee: u64 = option_u64(&args, "fee")?.unwrap_or(rpc::DEFAULT_MINING_FEE);
+            let txid = call(&connection, "claimhtlc", json!([txid, vout, preimage, fee]))?;
+            output(args.json, &txid, |txid| println!("Claimed\nTxid: {}", txid.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["htlc", "refund", txid, vout] => {
+            let vout: u32 = vout.parse().map_err(|_| CliError::Usage(format!("invalid vout {}", vout)))?;
+            let fee: u64 = option_u64(&args, "fee")?.unwrap_or(rpc::DEFAULT_MINING_FEE);
+            let txid = call(&connection, "refundhtlc", json!([txid, vout, fee]))?;
+            output(args.json, &txid, |txid| println!("Refunded\nTxid: {}", txid.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["pst", "create", address, amount] => {
+            let amount: u64 = amount.parse().map_err(|_| CliError::Usage(format!("invalid amount {}", amount)))?;
+            let fee: u64 = option_u64(&args, "fee")?.unwrap_or(rpc::DEFAULT_MINING_FEE);
+            let mut locks = serde_json::Map::new();
+            for (option, name) in [("locktime", "locktime"), ("after-blocks", "after_blocks"), ("after-seconds", "after_seconds")] {
+                if let Some(value) = option_u64(&args, option)? {
+                    locks.insert(name.to_string(), json!(value));
+                }
+            }
+            let pst = call(&connection, "createpst", json!([address, amount, fee, locks]))?;
+            output(args.json, &pst, |pst| println!("{}", pst.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["pst", "sign", pst] => {
+            let signed = call(&connection, "signpst", json!([pst]))?;
+            output(args.json, &signed, |signed| {
+                eprintln!("Signed {} inputs", signed["signed"]);
+                println!("{}", signed["pst"].as_str().unwrap_or_default());
+            });
+            Ok(())
+        }
+        ["pst", "combine", psts @ ..] if psts.len() >= 2 => {
+            let combined = call(&connection, "combinepst", json!([psts]))?;
+            output(args.json, &combined, |combined| println!("{}", combined.as_str().unwrap_or_default()));
+            Ok(())
+        }
+        ["pst", "finalize", pst] => {
+            let mut finalized = call(&connection, "finalizepst", json!([pst]))?;
+            if finalized["complete"] == json!(true) {
+                finalized["txid"] = call(&connection, "sendrawtransaction", json!([finalized["hex"]]))?;
+            }
+            output(args.json, &finalized, |finalized| match finalized["txid"].as_str() {
+                Some(txid) => println!("Broadcast {}", txid),
+                None => {
+                    eprintln!("Not enough signatures yet");
+                    println!("{}", finalized["pst"].as_str().unwrap_or_default());
+                }
+            });
+            Ok(())
+        }
+        ["pst", "decode", pst] => {
+            let decoded = call(&connection, "decodepst", json!([pst]))?;
+            output(args.json, &decoded, |decoded| {
+                print_tx(&decoded["tx"]);
+                for (index, input) in decoded["inputs"].as_array().into_iter().flatten().enumerate() {
+                    println!("Input {} spends {} locked by {}", index, input["amount"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en), input["asm"].as_str().unwrap_or_default());
+                    input["hints"].as_array().into_iter().flatten().for_each(|hint| println!("    address {} ({})", hint["address"].as_str().unwrap_or_default(), hint["hint"].as_str().unwrap_or_default()));
+                    input["signers"].as_array().into_iter().flatten().for_each(|signer| println!("    signed by {}", signer.as_str().unwrap_or_default()));
+                }
+                println!("Fee: {}", decoded["fee"]);
+                println!("Complete: {}", decoded["complete"]);
+            });
+            Ok(())
+        }
+        _ => Err(CliError::Usage(USAGE.to_string())),
+    }
+}
+
+fn parse(args: &[String]) -> Result<Args, CliError> {
+    let mut parsed = Args { positional: vec![], options: HashMap::new(), json: false };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--") {
+            Some("json") => parsed.json = true,
+            Some(name) if VALUE_OPTIONS.contains(&name) => {
+                let value = args.next().ok_or(CliError::Usage(format!("--{} needs a value", name)))?;
+                parsed.options.insert(name.to_string(), value.clone());
+            }
+            Some(name) => return Err(CliError::Usage(format!("unknown option --{}\n\n{}", name, USAGE))),
+            None => parsed.positional.push(arg.clone()),
+        }
+    }
+    Ok(parsed)
+}
+
+// a config file takes precedence over a named network
+fn chain_params(args: &Args) -> Result<ChainParams, CliError> {
+    let mut params = match (args.options.get("config"), args.options.get("network")) {
+        (Some(path), _) => ChainParams::load(path)?,
+        (None, Some(network)) => ChainParams::from_name(network)?,
+        (None, None) => ChainParams::mainnet(),
+    };
+    if let Some(hash) = args.options.get("snapshot-hash") {
+        params.snapshot_hash = Some(from_hex(hash).and_then(|bytes| bytes.try_into().ok()).ok_or(CliError::Usage(format!("invalid --snapshot-hash {}", hash)))?);
+    }
+    Ok(params)
+}
+
+fn option_u64(args: &Args, name: &str) -> Result<Option<u64>, CliError> {
+    args.options.get(name)
+        .map(|value| value.parse().map_err(|_| CliError::Usage(format!("invalid --{} {}", name, value))))
+        .transpose()
+}
+
+// --skip and --count as rpc params, a count without a skip starts at the newest event
+fn page_options(args: &Args) -> Result<Vec<Value>, CliError> {
+    let skip = option_u64(args, "skip")?;
+    let count = option_u64(args, "count")?;
+    Ok(match (skip, count) {
+        (skip, Some(count)) => vec![json!(skip.unwrap_or(0)), json!(count)],
+        (Some(skip), None) => vec![json!(skip)],
+        (None, None) => vec![],
+    })
+}
+
+// the blocks of a chain dump as a chain that can serve them, the blocks are checked by whoever downloads them
+fn load_dump(path: &str, params: &ChainParams) -> Result<Blockchain, CliError> {
+    // serving the history takes every body, whatever the node itself prunes
+    let mut store = Blockchain::new(ChainParams { prune_depth: 0, prune_size: 0, ..params.clone() });
+    let file = File::open(path).map_err(|error| file_error(path, error))?;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|error| file_error(path, error))?;
+        let block = serde_json::from_str::<BlockJson>(&line).ok().and_then(|block| Block::from_json(&block).ok())
+            .ok_or(CliError::Usage(format!("{} line {}: invalid block", path, number + 1)))?;
+        if block.index == store.get_height() + 1 && block.previous_hash == store.get_current_hash() {
+            store.add_block(block);
+        }
+    }
+    Ok(store)
+}
+
+fn file_error(path: &str, error: io::Error) -> CliError { CliError::Usage(format!("{}: {}", path, error)) }
+
+fn output(json: bool, value: &Value, human: impl Fn(&Value)) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value).unwrap());
+    } else {
+        human(value);
+    }
+}
+
+// sends a single json-rpc request to the node and unwraps its result
+fn call(connection: &Connection, method: &str, params: Value) -> Result<Value, CliError> {
+    let cookie = fs::read_to_string(&connection.cookie)
+        .map_err(|error| CliError::Usage(format!("could not read cookie {}, is the node running? {}", connection.cookie, error)))?;
+    let body = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }).to_string();
+    let mut stream = TcpStream::connect(("127.0.0.1", connection.port))?;
+    write!(stream, "POST / HTTP/1.1\r\nHost: 127.0.0.1\r\nAuthorization: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        rpc::authorization(cookie.trim()), body.len(), body)?;
+    // the server closes the connection after every response
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    parse_response(&response)
+}
+
+// the result of an http response carrying a json-rpc reply, anything else the node sent is an error
+fn parse_response(response: &str) -> Result<Value, CliError> {
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body).ok_or(CliError::BadResponse)?;
+    let response: Value = serde_json::from_str(body).map_err(|_| CliError::BadResponse)?;
+    if let Some(error) = response.get("error").filter(|error| !error.is_null()) {
+        return Err(CliError::Rpc(error["code"].as_i64().unwrap_or_default(), error["message"].as_str().unwrap_or_default().to_string()));
+    }
+    Ok(response["result"].clone())
+}
+
+fn print_block(block: &Value) {
+    println!("\n-------------------------------------------------------------------------------");
+    println!("Block: {} {}", block["height"], block["hash"].as_str().unwrap_or_default());
+    println!("Header Data: ");
+    println!("\nPrevious block: {}", block["previousblockhash"].as_str().unwrap_or_default());
+    println!("Merkle root: {}", block["merkleroot"].as_str().unwrap_or_default());
+    println!("Unix Timestamp: {}", block["time"]);
+    println!("Target {}", block["target"].as_str().unwrap_or_default());
+    println!("Nonce: {}", block["nonce"].as_str().unwrap_or_default());
+    println!("\nTransactions: ");
+    block["tx"].as_array().into_iter().flatten().for_each(print_tx);
+    println!("\n\nTotal block size: {} Bytes", block["size"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+    println!("\nEnd Block: {}", block["height"]);
+    println!("-------------------------------------------------------------------------------");
+}
+
+fn print_history(history: &Value) {
+    let events = history["events"].as_array().cloned().unwrap_or_default();
+    println!("{:<66} {:>8} {:>14}", "Txid", "Height", "Change");
+    events.iter().for_each(|event| println!("{:<66} {:>8} {:>14}",
+        event["txid"].as_str().unwrap_or_default(),
+        event["height"],
+        event["delta"].as_i64().unwrap_or_default().to_formatted_string(&Locale::en)));
+    println!("\n{} of {} events", events.len(), history["total"]);
+}
+
+fn print_tx(tx: &Value) {
+    let txid = tx["txid"].as_str().unwrap_or_default();
+    print!("------------------------------------------------------------\nTransaction {}", txid);
+    for (index, input) in tx["inputs"].as_array().into_iter().flatten().enumerate() {
+        println!("\n\nInput {index}");
+        if let Some(coinbase) = input.get("coinbase") {
+            println!("Coinbase height: {}", coinbase["height"]);
+            print!("Extra nonce: {}", coinbase["extranonce"]);
+            continue;
+        }
+        println!("Txid: {}:{}", input["txid"].as_str().unwrap_or_default(), input["vout"]);
+        let witness: Vec<&str> = input["witness"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+        println!("Witness: {}", witness.join(" "));
+        print!("Sequence: {:08x}", input["sequence"].as_u64().unwrap_or_default());
+    }
+    if let Some(fee) = tx["fee"].as_u64() {
+        print!("\n\nFee: {}", fee.to_formatted_string(&Locale::en));
+    }
+    for (index, output) in tx["outputs"].as_array().into_iter().flatten().enumerate() {
+        println!("\n\nOutput {index}");
+        println!("Amount: {}", output["amount"].as_u64().unwrap_or_default().to_formatted_string(&Locale::en));
+        match output["address"].as_str() {
+            Some(address) => print!("Address: {}", address),
+            None => print!("Script: {}", output["asm"].as_str().unwrap_or_default()),
+        }
+    }
+    if tx["locktime"].as_u64().unwrap_or_default() != 0 {
+        print!("\n\nLocked until: {}", tx["locktime"]);
+    }
+    if let Some(height) = tx.get("blockheight").and_then(Value::as_u64) {
+        print!("\n\nIncluded in block {} ({} confirmations)", height, tx["confirmations"]);
+    }
+    println!("\n\nEnd Transaction {}", txid);
+    println!("------------------------------------------------------------");
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Params(ParamsError),
+    Io(io::Error),
+    Rpc(i64, String),
+    BadResponse,
+}
+
+impl From<ParamsError> for CliError {
+    fn from(error: ParamsError) -> CliError { CliError::Params(error) }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> CliError { CliError::Io(error) }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}", message),
+            CliError::Params(ParamsError::Io(error)) => write!(f, "could not read config: {}", error),
+            CliError::Params(ParamsError::Parse(error)) => write!(f, "invalid config: {}", error),
+            CliError::Params(ParamsError::UnknownNetwork(name)) => write!(f, "unknown network {}", name),
+            CliError::Params(ParamsError::Invalid(message)) => write!(f, "invalid config: {}", message),
+            CliError::Io(error) => write!(f, "could not reach node: {}", error),
+            CliError::Rpc(code, message) => write!(f, "{} (code {})", message, code),
+            CliError::BadResponse => write!(f, "malformed response from node"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{funded_node, params};
+    use crate::wallet::Wallet;
+
+    fn strings(args: &[&str]) -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() }
+
+    // a file in the temp dir named after the test, removed if a previous run left it
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("cli-{}-{}", name, process::id())).to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    // serves the node over json-rpc, returning the options that reach it
+    fn serve(node: &Arc<Mutex<Node>>, name: &str) -> Vec<String> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let cookie_path = temp_path(&format!("{}.cookie", name));
+        let cookie = rpc::write_cookie(&cookie_path).unwrap();
+        let node = Arc::clone(node);
+        thread::spawn(move || rpc::serve(node, listener, &cookie));
+        strings(&["--network", "regtest", "--port", &port.to_string(), "--cookie", &cookie_path])
+    }
+
+    fn run_with(options: &[String], command: &[&str]) -> Result<(), CliError> {
+        run(&[options.to_vec(), strings(command)].concat())
+    }
+
+    #[test]
+    fn parses_flags_options_and_commands() {
+        let args = parse(&strings(&["--json", "wallet", "send", "--fee", "10", "rtx1abc", "500"])).unwrap();
+        assert!(args.json);
+        assert_eq!(args.positional, ["wallet", "send", "rtx1abc", "500"]);
+        assert_eq!(args.options.get("fee").map(String::as_str), Some("10"));
+        assert!(matches!(parse(&strings(&["wallet", "send", "--fee"])), Err(CliError::Usage(message)) if message == "--fee needs a value"));
+        assert!(matches!(parse(&strings(&["--verbose", "chain", "info"])), Err(CliError::Usage(message)) if message.starts_with("unknown option --verbose")));
+        let args = parse(&strings(&["wallet", "history", "--count", "ten"])).unwrap();
+        assert!(matches!(option_u64(&args, "count"), Err(CliError::Usage(_))));
+        assert!(matches!(run(&strings(&["frobnicate"])), Err(CliError::Usage(message)) if message == USAGE));
+    }
+
+    #[test]
+    fn picks_the_params_from_a_config_or_a_network() {
+        let params = |args: &[&str]| chain_params(&parse(&strings(args)).unwrap());
+        assert_eq!(params(&[]).unwrap().name, "main");
+        assert_eq!(params(&["--network", "test"]).unwrap().name, "test");
+        assert_eq!(params(&["--network", "regtest"]).unwrap().address_prefix, "rtx");
+        assert!(matches!(params(&["--network", "moon"]), Err(CliError::Params(ParamsError::UnknownNetwork(_)))));
+        // a config file wins over the network
+        let config = temp_path("config.toml");
+        fs::write(&config, "base = \"regtest\"\nname = \"dev\"\n").unwrap();
+        assert_eq!(params(&["--network", "test", "--config", &config]).unwrap().name, "dev");
+        assert!(matches!(params(&["--config", &temp_path("missing.toml")]), Err(CliError::Params(ParamsError::Io(_)))));
+        let hash = "ab".repeat(32);
+        assert_eq!(params(&["--snapshot-hash", &hash]).unwrap().snapshot_hash, Some([0xab; 32]));
+        assert!(matches!(params(&["--snapshot-hash", "abcd"]), Err(CliError::Usage(_))));
+    }
+
+    #[test]
+    fn turns_bad_replies_into_errors() {
+        assert!(matches!(parse_response("HTTP/1.1 200 OK\r\n"), Err(CliError::BadResponse)));
+        assert!(matches!(parse_response("HTTP/1.1 200 OK\r\n\r\nnot json"), Err(CliError::BadResponse)));
+        assert_eq!(parse_response("HTTP/1.1 200 OK\r\n\r\n[1, 2]").unwrap(), Value::Null);
+        assert!(matches!(parse_response("HTTP/1.1 200 OK\r\n\r\n{\"error\": \"down\"}"), Err(CliError::Rpc(0, message)) if message.is_empty()));
+        let reply = parse_response("HTTP/1.1 200 OK\r\n\r\n{\"result\": 3, \"error\": null}");
+        assert_eq!(reply.unwrap(), json!(3));
+        // a node answering with something other than json-rpc fails the command instead of the cli
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n<html>");
+        });
+        let cookie = temp_path("bad.cookie");
+        fs::write(&cookie, "__cookie__:secret").unwrap();
+        assert!(matches!(run(&strings(&["--port", &port.to_string(), "--cookie", &cookie, "chain", "info"])), Err(CliError::BadResponse)));
+    }
+
+    #[test]
+    fn imports_what_it_dumped() {
+        let (mut source, address) = funded_node();
+        let tx = source.send_to_address(Wallet::new().address(), 1000, 10).unwrap();
+        source.submit_tx(tx).unwrap();
+        source.mine_block(address, 1).unwrap();
+        let tip = source.chain.get_current_hash();
+        let source = Arc::new(Mutex::new(source));
+        let path = temp_path("dump.jsonl");
+        run_with(&serve(&source, "dump"), &["chain", "dump", &path]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        let target = Arc::new(Mutex::new(Node::new(params())));
+        let options = serve(&target, "import");
+        run_with(&options, &["chain", "import", &path]).unwrap();
+        assert_eq!(target.lock().unwrap().chain.get_current_hash(), tip);
+        // importing again skips the blocks the node has
+        run_with(&options, &["chain", "import", &path]).unwrap();
+        assert_eq!(target.lock().unwrap().chain.get_height(), 2);
+
+        // a node on another chain refuses the dump instead of forking
+        let (other, _) = funded_node();
+        let options = serve(&Arc::new(Mutex::new(other)), "conflict");
+        assert!(matches!(run_with(&options, &["chain", "import", &path]), Err(CliError::Usage(message)) if message.contains("conflicts")));
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// source of the current unix time in seconds, injected so timestamp rules don't depend on the host clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() }
+}
+
+#[cfg(test)]
+pub use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::Clock;
+
+    // a clock that only moves when told to
+    pub struct MockClock {
+        time: AtomicU64,
+    }
+
+    impl MockClock {
+        pub fn new(time: u64) -> MockClock { MockClock { time: AtomicU64::new(time) } }
+
+        pub fn set(&self, time: u64) { self.time.store(time, Ordering::Relaxed); }
+
+        pub fn advance(&self, seconds: u64) { self.time.fetch_add(seconds, Ordering::Relaxed); }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 { self.time.load(Ordering::Relaxed) }
+    }
+}
@@ -0,0 +1,102 @@
+use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::traits::Identity;
+
+use crate::snapshot::Coin;
+
+// an order independent hash of a utxo set, coins are added and removed one at a time as blocks come and go
+// every coin is hashed to a point of the ristretto group and the commitment is their sum, so two sets with the same coins
+// end up at the same point however they were built, and removing a coin subtracts it again
+#[derive(Clone, Copy, PartialEq)]
+pub struct UtxoCommitment {
+    sum: RistrettoPoint,
+    pub coins: u64,
+    pub amount: u64,
+}
+
+impl Default for UtxoCommitment {
+    fn default() -> UtxoCommitment { UtxoCommitment::new() }
+}
+
+impl UtxoCommitment {
+    pub fn new() -> UtxoCommitment {
+        UtxoCommitment { sum: RistrettoPoint::identity(), coins: 0, amount: 0 }
+    }
+
+    // None if the amounts of the coins add up past u64
+    pub fn from_coins<'a>(coins: impl IntoIterator<Item = &'a Coin>) -> Option<UtxoCommitment> {
+        let mut commitment = UtxoCommitment::new();
+        coins.into_iter().try_for_each(|coin| commitment.add(coin))?;
+        Some(commitment)
+    }
+
+    // the hash of the coins alone, which doesn't depend on their amounts
+    pub fn hash_of<'a>(coins: impl IntoIterator<Item = &'a Coin>) -> [u8;32] {
+        coins.into_iter().map(point).sum::<RistrettoPoint>().compress().to_bytes()
+    }
+
+    // leaves the commitment as it was if the total amount would overflow
+    pub fn add(&mut self, coin: &Coin) -> Option<()> {
+        self.amount = self.amount.checked_add(coin.output.amount)?;
+        self.sum += point(coin);
+        self.coins += 1;
+        Some(())
+    }
+
+    // the coin has to be in the set, the counts don't go below zero if it wasn't
+    pub fn remove(&mut self, coin: &Coin) {
+        self.sum -= point(coin);
+        self.coins = self.coins.saturating_sub(1);
+        self.amount = self.amount.saturating_sub(coin.output.amount);
+    }
+
+    // the empty set hashes to all zeroes
+    pub fn hash(&self) -> [u8;32] { self.sum.compress().to_bytes() }
+}
+
+fn point(coin: &Coin) -> RistrettoPoint {
+    let mut bytes = [0; 64];
+    blake3::Hasher::new().update(&coin.serialize()).finalize_xof().fill(&mut bytes);
+    RistrettoPoint::from_uniform_bytes(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    fn coin(seed: u8, amount: u64) -> Coin {
+        Coin { txid: [seed; 32], vout: seed as u32, output: Output { amount, script_pubkey: vec![seed] }, height: 1, coinbase: false }
+    }
+
+    #[test]
+    fn hashes_the_same_coins_the_same_in_any_order() {
+        let coins: Vec<Coin> = (0..5).map(|seed| coin(seed, 100 * seed as u64)).collect();
+        let commitment = UtxoCommitment::from_coins(&coins).unwrap();
+        let reversed = UtxoCommitment::from_coins(coins.iter().rev()).unwrap();
+        assert!(commitment == reversed);
+        assert_eq!(commitment.hash(), UtxoCommitment::hash_of(&coins));
+        assert_eq!((commitment.coins, commitment.amount), (5, 1000));
+        assert_ne!(commitment.hash(), UtxoCommitment::from_coins(&coins[1..]).unwrap().hash());
+    }
+
+    #[test]
+    fn removing_what_was_added_gets_back_to_empty() {
+        let mut commitment = UtxoCommitment::new();
+        assert_eq!(commitment.hash(), [0; 32]);
+        let coins = [coin(1, 50), coin(2, 70)];
+        coins.iter().for_each(|coin| commitment.add(coin).unwrap());
+        commitment.remove(&coins[0]);
+        commitment.remove(&coins[1]);
+        assert!(commitment == UtxoCommitment::new());
+        assert_eq!(commitment.hash(), [0; 32]);
+    }
+
+    #[test]
+    fn refuses_amounts_past_u64() {
+        let mut commitment = UtxoCommitment::from_coins(&[coin(1, u64::MAX)]).unwrap();
+        let before = commitment;
+        assert!(commitment.add(&coin(2, 1)).is_none());
+        assert!(commitment == before);
+        assert!(UtxoCommitment::from_coins(&[coin(1, u64::MAX), coin(2, 1)]).is_none());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use rand::random;
+
+use crate::block::{Block, BlockHeader};
+use crate::mempool::Mempool;
+use crate::sync::Peer;
+use crate::transactions::Tx;
+
+pub const SHORT_ID_BYTES: usize = 6;
+
+// announces a block as its header plus short ids, so peers rebuild it from transactions they already have
+#[derive(Clone)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub salt: u64,
+    pub short_ids: Vec<[u8; SHORT_ID_BYTES]>,
+    // (position in block, tx) for transactions the receiver can't have, which is always at least the coinbase
+    pub prefilled: Vec<(usize, Tx)>,
+}
+
+// a block being rebuilt from a compact block, None marks transactions still missing
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    pub transactions: Vec<Option<Tx>>,
+}
+
+pub struct BlockTxnRequest {
+    pub hash: [u8;32],
+    pub indexes: Vec<usize>,
+}
+
+pub struct BlockTxn {
+    pub hash: [u8;32],
+    pub transactions: Vec<Tx>,
+}
+
+impl CompactBlock {
+    // a block without its body, like a pruned one, has no coinbase to prefill
+    pub fn from_block(block: &Block) -> Result<CompactBlock, CompactBlockError> {
+        let coinbase = block.transactions.first().ok_or(CompactBlockError::EmptyBlock)?;
+        let salt: u64 = random();
+        let key = CompactBlock::short_id_key(&block.hash, salt);
+        let short_ids = block.transactions.iter().skip(1).map(|tx| CompactBlock::short_id(&key, &tx.txid)).collect();
+        let prefilled = vec![(0, coinbase.clone())];
+        Ok(CompactBlock { header: block.header(), salt, short_ids, prefilled })
+    }
+
+    // the salt changes per announcement, so nobody can craft transactions that collide in every block
+    fn short_id_key(block_hash: &[u8;32], salt: u64) -> [u8;32] {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(block_hash);
+        hasher.update(&salt.to_be_bytes());
+        *hasher.finalize().as_bytes()
+    }
+
+    fn short_id(key: &[u8;32], txid: &[u8;32]) -> [u8; SHORT_ID_BYTES] {
+        let hash = blake3::keyed_hash(key, txid);
+        hash.as_bytes()[..SHORT_ID_BYTES].try_into().unwrap()
+    }
+
+    pub fn tx_count(&self) -> usize { self.short_ids.len() + self.prefilled.len() }
+
+    pub fn get_size(&self) -> u32 {
+        const HEADER_BYTES: u32 = 124;
+        const SALT_BYTES: u32 = 8;
+        // prefilled transactions carry a 2 byte index on top of the transaction itself
+        let prefilled_bytes: u32 = self.prefilled.iter().map(|(_, tx)| 2 + tx.get_size()).sum();
+        HEADER_BYTES + SALT_BYTES + self.short_ids.len() as u32 * SHORT_ID_BYTES as u32 + prefilled_bytes
+    }
+
+    pub fn reconstruct(&self, pool: &Mempool) -> PartialBlock {
+        let key = CompactBlock::short_id_key(&self.header.hash, self.salt);
+        // short ids matched by more than one mempool tx are ambiguous, and are treated as missing
+        let mut candidates: HashMap<[u8; SHORT_ID_BYTES], Option<&Tx>> = HashMap::new();
+        pool.pool.iter().for_each(|(_, tx)| {
+            candidates.entry(CompactBlock::short_id(&key, &tx.txid))
+                .and_modify(|candidate| *candidate = None)
+                .or_insert(Some(tx));
+        });
+
+        let mut transactions = vec![None; self.tx_count()];
+        for (index, tx) in self.prefilled.iter() {
+            if let Some(slot) = transactions.get_mut(*index) {
+                *slot = Some(tx.clone());
+            }
+        }
+        let mut short_ids = self.short_ids.iter();
+        for slot in transactions.iter_mut().filter(|slot| slot.is_none()) {
+            if let Some(short_id) = short_ids.next() {
+                *slot = candidates.get(short_id).copied().flatten().cloned();
+            }
+        }
+        PartialBlock { header: self.header, transactions }
+    }
+
+    // rebuilds the block from our mempool, asking the announcing peer only for transactions we lack
+    pub fn download(&self, pool: &Mempool, peer: &dyn Peer) -> Result<Block, CompactBlockError> {
+        let mut partial = self.reconstruct(pool);
+        let missing = partial.missing();
+        if !missing.is_empty() {
+            let request = BlockTxnRequest { hash: self.header.hash, indexes: missing };
+            let response = peer.get_block_transactions(&request).ok_or(CompactBlockError::MissingTransactions)?;
+            partial.fill(&request, response)?;
+        }
+        match partial.into_block() {
+            // the full block is only fetched when a short id collision leaves us with the wrong transactions
+            Err(CompactBlockError::BadMerkleRoot) => {
+                let block = peer.get_block(&self.header.hash).ok_or(CompactBlockError::BadMerkleRoot)?;
+                if block.header().calc_hash() != self.header.hash || Block::calc_merkle_root(&block.transactions) != self.header.merkle_root {
+                    return Err(CompactBlockError::WrongBlock);
+                }
+                Ok(block)
+            }
+            result => result,
+        }
+    }
+}
+
+impl PartialBlock {
+    pub fn missing(&self) -> Vec<usize> {
+        self.transactions.iter().enumerate().filter(|(_, tx)| tx.is_none()).map(|(index, _)| index).collect()
+    }
+
+    pub fn fill(&mut self, request: &BlockTxnRequest, response: BlockTxn) -> Result<(), CompactBlockError> {
+        if response.hash != request.hash || response.transactions.len() != request.indexes.len()
+            || request.indexes.iter().any(|index| *index >= self.transactions.len()) {
+            return Err(CompactBlockError::MissingTransactions);
+        }
+        request.indexes.iter().zip(response.transactions)
+            .for_each(|(index, tx)| self.transactions[*index] = Some(tx));
+        Ok(())
+    }
+
+    pub fn into_block(self) -> Result<Block, CompactBlockError> {
+        let transactions: Vec<Tx> = self.transactions.into_iter().collect::<Option<Vec<Tx>>>()
+            .ok_or(CompactBlockError::MissingTransactions)?;
+        // a short id collision with a mempool tx shows up as a merkle root mismatch
+        if Block::calc_merkle_root(&transactions) != self.header.merkle_root {
+            return Err(CompactBlockError::BadMerkleRoot);
+        }
+        Ok(Block::from_header(self.header, transactions))
+    }
+}
+
+#[derive(Debug)]
+pub enum CompactBlockError {
+    MissingTransactions,
+    BadMerkleRoot,
+    // the block has no transactions to announce
+    EmptyBlock,
+    // the full block sent after a collision isn't the one announced
+    WrongBlock,
+    // the peer doesn't have the block it announced
+    Unavailable,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::fixtures::{funded_chain, mine};
+    use crate::global_utxos::GlobalUtxos;
+    use crate::wallet::Wallet;
+
+    // a chain with a block of three payments, and a mempool that only saw the first and last of them
+    fn relayed_block() -> (Blockchain, Mempool, Block) {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        (2..=3).for_each(|_| mine(&mut chain, &miner, vec![]).unwrap());
+        let mut utxos = GlobalUtxos::new();
+        utxos.find_utxos(&chain);
+        let coinbases = utxos.get_utxos(&wallet.script_pubkey()).unwrap().clone();
+        let txs: Vec<Tx> = coinbases.iter().map(|coinbase| {
+            wallet.send_to_script(1000, 10, Wallet::new().script_pubkey(), &[*coinbase], 4, 1).unwrap()
+        }).collect();
+        let mut pool = Mempool::new();
+        for tx in [&txs[0], &txs[2]] {
+            pool.add_tx(tx.clone(), &chain, &utxos.find_spent_utxos(tx)).unwrap();
+        }
+        let block = miner.build_block(4, chain.get_current_hash(), txs, 30, &chain);
+        chain.connect_block(block.clone()).unwrap();
+        (chain, pool, block)
+    }
+
+    #[test]
+    fn rebuilds_a_block_from_a_partly_filled_mempool() {
+        let (chain, pool, block) = relayed_block();
+        let compact = CompactBlock::from_block(&block).unwrap();
+        assert!(compact.get_size() < block.get_size());
+        assert_eq!(compact.reconstruct(&pool).missing(), vec![2]);
+        let rebuilt = compact.download(&pool, &chain).unwrap();
+        assert_eq!(rebuilt.hash, block.hash);
+        assert!(rebuilt.transactions.iter().zip(&block.transactions).all(|(rebuilt, tx)| rebuilt.txid == tx.txid));
+    }
+
+    #[test]
+    fn refuses_to_announce_a_block_without_transactions() {
+        let (_, _, block) = relayed_block();
+        let pruned = Block::from_header(block.header(), vec![]);
+        assert!(matches!(CompactBlock::from_block(&pruned), Err(CompactBlockError::EmptyBlock)));
+    }
+
+    // answers requests for missing transactions with the wrong ones, and sends the given block in full
+    struct WrongPeer {
+        chain: Blockchain,
+        block: Block,
+    }
+
+    impl Peer for WrongPeer {
+        fn get_headers(&self, locator: &[[u8;32]], max: usize) -> Vec<BlockHeader> { self.chain.get_headers(locator, max) }
+
+        fn get_block(&self, _: &[u8;32]) -> Option<Block> { Some(self.block.clone()) }
+
+        fn get_block_transactions(&self, request: &BlockTxnRequest) -> Option<BlockTxn> {
+            Some(BlockTxn { hash: request.hash, transactions: request.indexes.iter().map(|_| self.chain.chain[1].transactions[0].clone()).collect() })
+        }
+
+        fn get_compact_block(&self, hash: &[u8;32]) -> Option<CompactBlock> { self.chain.get_compact_block(hash) }
+    }
+
+    #[test]
+    fn checks_the_full_block_sent_after_a_mismatch() {
+        let (chain, pool, block) = relayed_block();
+        let compact = CompactBlock::from_block(&block).unwrap();
+        let other = chain.chain[3].clone();
+        let peer = WrongPeer { chain, block: other };
+        assert!(matches!(compact.download(&pool, &peer), Err(CompactBlockError::WrongBlock)));
+        let peer = WrongPeer { block: block.clone(), ..peer };
+        assert_eq!(compact.download(&pool, &peer).unwrap().hash, block.hash);
+    }
+}
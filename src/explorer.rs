@@ -0,0 +1,338 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use num_format::{Locale, ToFormattedString};
+
+use crate::address;
+use crate::block::Block;
+use crate::htlc::Htlc;
+use crate::node::{self, Node};
+use crate::output::Output;
+use crate::script;
+use crate::transactions::{Tx, LOCKTIME_THRESHOLD};
+use crate::util::{from_hex, to_hex};
+
+pub const RECENT_BLOCKS: usize = 20;
+pub const HISTORY_PAGE_SIZE: usize = 25;
+
+// everything is inline, so the pages work without any outside assets
+const STYLE: &str = "body{font-family:sans-serif;margin:2em auto;max-width:72em;color:#222}\
+a{color:#0645ad;text-decoration:none}a:hover{text-decoration:underline}\
+table{border-collapse:collapse;width:100%;margin:1em 0}th,td{text-align:left;padding:.3em .6em;border-bottom:1px solid #ddd}\
+td.amount,th.amount{text-align:right}code,.hash{font-family:monospace;word-break:break-all}\
+nav{margin-bottom:1.5em}nav a{margin-right:1em}dl{display:grid;grid-template-columns:max-content auto;gap:.3em 1em}dd{margin:0}";
+
+// serves the explorer over plain http, one thread per connection like the rpc server
+// takes a bound listener, so a port that's taken is reported before the node starts
+pub fn serve(node: Arc<Mutex<Node>>, listener: TcpListener) {
+    for stream in listener.incoming().flatten() {
+        let node = Arc::clone(&node);
+        thread::spawn(move || handle_connection(stream, &node));
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, node: &Mutex<Node>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers don't matter, but they have to be read before answering
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or("/"));
+    if method != "GET" {
+        return write_response(&mut stream, "405 Method Not Allowed", &page("Not allowed", "<p>Only GET requests are served.</p>"));
+    }
+    match render(target, &mut node::lock(node)) {
+        Ok(html) => write_response(&mut stream, "200 OK", &html),
+        Err(PageError::NotFound) => write_response(&mut stream, "404 Not Found", &page("Not found", "<p>Nothing here, the chain doesn't know it.</p>")),
+        Err(PageError::BadRequest) => write_response(&mut stream, "400 Bad Request", &page("Bad request", "<p>The page asked for can't exist.</p>")),
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PageError {
+    NotFound,
+    // a query no page could answer, like a history page past the end of memory
+    BadRequest,
+}
+
+// the html of the page at target
+pub fn render(target: &str, node: &mut Node) -> Result<String, PageError> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+    match segments.as_slice() {
+        [] => Ok(recent_blocks(node)),
+        ["block", id] => block_page(node, id).ok_or(PageError::NotFound),
+        ["tx", txid] => from_hex(id_or_empty(txid)).and_then(|txid| tx_page(node, &txid.try_into().ok()?)).ok_or(PageError::NotFound),
+        ["address", address] => address_page(node, address, query_value(query, "page").and_then(|page| page.parse().ok()).unwrap_or(0)),
+        ["mempool"] => Ok(mempool_page(node)),
+        ["search"] => query_value(query, "q").and_then(|query| search(node, &query)).ok_or(PageError::NotFound),
+        _ => Err(PageError::NotFound),
+    }
+}
+
+fn id_or_empty(id: &str) -> &str { if id.len() == 64 { id } else { "" } }
+
+// a height, a block hash, a txid or an address, whichever the chain knows
+fn search(node: &mut Node, query: &str) -> Option<String> {
+    let query = query.trim();
+    if let Ok(height) = query.parse::<u32>() {
+        return block_page(node, &height.to_string());
+    }
+    if let Some(hash) = from_hex(id_or_empty(query)).and_then(|bytes| <[u8;32]>::try_from(bytes).ok()) {
+        return block_page(node, query).or_else(|| tx_page(node, &hash));
+    }
+    address_page(node, query, 0).ok()
+}
+
+fn recent_blocks(node: &Node) -> String {
+    let rows: String = node.chain.chain.iter().rev().take(RECENT_BLOCKS).map(|block| format!(
+        "<tr><td><a href=\"/block/{height}\">{height}</a></td><td class=\"hash\">{hash}</td><td>{time}</td><td class=\"amount\">{txs}</td><td class=\"amount\">{size}</td></tr>",
+        height = block.index, hash = block_link(&block.hash), time = block.time, txs = block.transactions.len(), size = block.get_size().to_formatted_string(&Locale::en),
+    )).collect();
+    page("Recent blocks", &format!(
+        "<p>{network} chain at height {height}, {pool} transactions waiting in the <a href=\"/mempool\">mempool</a>.</p>\
+         <table><tr><th>Height</th><th>Hash</th><th>Time</th><th class=\"amount\">Txs</th><th class=\"amount\">Bytes</th></tr>{rows}</table>",
+        network = escape(&node.chain.params.name), height = node.chain.get_height(), pool = node.pool.pool.len(), rows = rows,
+    ))
+}
+
+// id is a height or a block hash
+fn block_page(node: &Node, id: &str) -> Option<String> {
+    let block: &Block = match id.parse::<usize>() {
+        Ok(height) => node.chain.chain.get(height)?,
+        Err(_) => node.chain.get_block_by_hash(&from_hex(id_or_empty(id))?.try_into().ok()?)?,
+    };
+    let previous = if block.index == 0 { "none".to_string() } else { block_link(&block.previous_hash) };
+    let next = node.chain.chain.get(block.index as usize + 1).map(|next| block_link(&next.hash)).unwrap_or("none".to_string());
+    let rows: String = block.transactions.iter().map(|tx| format!(
+        "<tr><td class=\"hash\">{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td></tr>",
+        tx_link(&tx.txid), tx.outputs.iter().map(|output| output.amount).sum::<u64>().to_formatted_string(&Locale::en), tx.get_size(),
+    )).collect();
+    Some(page(&format!("Block {}", block.index), &format!(
+        "<dl><dt>Hash</dt><dd class=\"hash\">{hash}</dd><dt>Previous</dt><dd class=\"hash\">{previous}</dd><dt>Next</dt><dd class=\"hash\">{next}</dd>\
+         <dt>Merkle root</dt><dd class=\"hash\">{merkle}</dd><dt>Time</dt><dd>{time}</dd><dt>Target</dt><dd><code>{target:016x}</code></dd>\
+         <dt>Nonce</dt><dd><code>{nonce:016x}</code></dd><dt>Size</dt><dd>{size} bytes</dd><dt>Confirmations</dt><dd>{confirmations}</dd></dl>\
+         <h2>Transactions</h2><table><tr><th>Txid</th><th class=\"amount\">Output total</th><th class=\"amount\">Bytes</th></tr>{rows}</table>",
+        hash = to_hex(&block.hash), previous = previous, next = next, merkle = to_hex(&block.merkle_root), time = block.time,
+        target = block.target, nonce = block.nonce, size = block.get_size().to_formatted_string(&Locale::en),
+        confirmations = node.chain.get_height() - block.index + 1, rows = rows,
+    )))
+}
+
+// mined txs show the block they're in, txs still in the mempool say so
+fn tx_page(node: &Node, txid: &[u8;32]) -> Option<String> {
+    let (tx, status): (&Tx, String) = match node.chain.get_tx(txid) {
+        Some((tx, block)) => (tx, format!("Mined in block <a href=\"/block/{0}\">{0}</a>, {1} confirmations", block.index, node.chain.get_height() - block.index + 1)),
+        None => (node.pool.pool.iter().map(|(_, tx)| tx).find(|tx| tx.txid == *txid)?, "Waiting in the <a href=\"/mempool\">mempool</a>".to_string()),
+    };
+    let prefix = &node.chain.params.address_prefix;
+    let inputs: String = tx.inputs.iter().map(|input| if tx.is_coinbase() {
+        format!("<tr><td>Coinbase of height {}</td><td></td><td class=\"amount\"></td></tr>", input.coinbase_height().unwrap_or_default())
+    } else {
+        // inputs link to the output they spend, which is shown with its amount and owner when the chain has it
+        // claiming an htlc reveals its preimage, which the other side of a swap needs
+        let source = node.chain.find_output(&input.txid, input.vout);
+        let revealed = source.and_then(|output| Htlc::parse(&output.script_pubkey)).and_then(|htlc| htlc.find_preimage(tx))
+            .map(|preimage| format!(", claimed with the preimage <code>{}</code>", to_hex(&preimage))).unwrap_or_default();
+        format!("<tr><td class=\"hash\"><a href=\"/tx/{txid}#output-{vout}\">{txid}:{vout}</a></td><td class=\"hash\">{owner}{revealed}</td><td class=\"amount\">{amount}</td></tr>",
+            txid = to_hex(&input.txid), vout = input.vout, revealed = revealed,
+            owner = source.map(|output| owner(output, prefix)).unwrap_or_default(),
+            amount = source.map(|output| output.amount.to_formatted_string(&Locale::en)).unwrap_or_default())
+    }).collect();
+    let outputs: String = tx.outputs.iter().enumerate().map(|(vout, output)| format!(
+        "<tr id=\"output-{}\"><td>{}</td><td class=\"hash\">{}</td><td class=\"amount\">{}</td></tr>",
+        vout, vout, owner(output, prefix), output.amount.to_formatted_string(&Locale::en),
+    )).collect();
+    let fee = if tx.is_coinbase() { "none".to_string() } else { fee_or_unknown(tx, node) };
+    Some(page("Transaction", &format!(
+        "<dl><dt>Txid</dt><dd class=\"hash\">{txid}</dd><dt>Status</dt><dd>{status}</dd><dt>Size</dt><dd>{size} bytes</dd>\
+         <dt>Fee</dt><dd>{fee}</dd><dt>Lock time</dt><dd>{lock_time}</dd></dl>\
+         <h2>Inputs</h2><table><tr><th>Spends</th><th>From</th><th class=\"amount\">Amount</th></tr>{inputs}</table>\
+         <h2>Outputs</h2><table><tr><th>#</th><th>To</th><th class=\"amount\">Amount</th></tr>{outputs}</table>",
+        txid = to_hex(&tx.txid), status = status, size = tx.get_size(), fee = fee, lock_time = tx.lock_time, inputs = inputs, outputs = outputs,
+    )))
+}
+
+// the balance and unspent outputs come from the utxo set, the history needs the address index
+fn address_page(node: &mut Node, encoded: &str, page_number: usize) -> Result<String, PageError> {
+    let key = address::decode(&node.chain.params.address_prefix, encoded).map_err(|_| PageError::NotFound)?;
+    let skip = page_number.checked_mul(HISTORY_PAGE_SIZE).ok_or(PageError::BadRequest)?;
+    let script_pubkey = script::pay_to_pubkey(&key);
+    let utxos = node.utxos.get_utxos(&script_pubkey).cloned().unwrap_or_default();
+    let balance: u64 = utxos.iter().map(|utxo| utxo.amount).sum();
+    let utxo_rows: String = utxos.iter().map(|utxo| format!(
+        "<tr><td class=\"hash\"><a href=\"/tx/{txid}#output-{vout}\">{txid}:{vout}</a></td><td><a href=\"/block/{height}\">{height}</a></td><td class=\"amount\">{amount}</td></tr>",
+        txid = to_hex(&utxo.txid), vout = utxo.vout, height = utxo.height, amount = utxo.amount.to_formatted_string(&Locale::en),
+    )).collect();
+    let history = match node.chain.index.address_history(&script_pubkey, skip, HISTORY_PAGE_SIZE) {
+        None => "<p>The address index is disabled, set address_index in the config to see the history.</p>".to_string(),
+        Some((total, events)) => {
+            let rows: String = events.iter().map(|event| format!(
+                "<tr><td class=\"hash\">{txid}</td><td><a href=\"/block/{height}\">{height}</a></td><td class=\"amount\">{delta}</td></tr>",
+                txid = tx_link(&event.txid), height = event.height, delta = event.delta.to_formatted_string(&Locale::en),
+            )).collect();
+            let mut pages = vec![];
+            if page_number > 0 {
+                pages.push(format!("<a href=\"/address/{}?page={}\">newer</a>", encoded, page_number - 1));
+            }
+            if skip.saturating_add(HISTORY_PAGE_SIZE) < total {
+                pages.push(format!("<a href=\"/address/{}?page={}\">older</a>", encoded, page_number + 1));
+            }
+            format!("<p>{} events</p><table><tr><th>Txid</th><th>Height</th><th class=\"amount\">Change</th></tr>{}</table><p>{}</p>", total, rows, pages.join(" "))
+        }
+    };
+    Ok(page("Address", &format!(
+        "<dl><dt>Address</dt><dd class=\"hash\">{address}</dd><dt>Balance</dt><dd>{balance}</dd></dl>\
+         <h2>Unspent outputs</h2><table><tr><th>Output</th><th>Height</th><th class=\"amount\">Amount</th></tr>{utxos}</table>\
+         <h2>History</h2>{history}",
+        address = escape(encoded), balance = balance.to_formatted_string(&Locale::en), utxos = utxo_rows, history = history,
+    )))
+}
+
+// a pruned chain may no longer have the outputs a tx spent
+fn fee_or_unknown(tx: &Tx, node: &Node) -> String {
+    tx.checked_mining_fee(&node.chain).map(|fee| fee.to_formatted_string(&Locale::en)).unwrap_or("unknown".to_string())
+}
+
+fn mempool_page(node: &Node) -> String {
+    // highest fee per byte first, the order a miner would take them in
+    let rows: String = node.pool.pool.iter().rev().map(|(fee_per_byte, tx)| format!(
+        "<tr><td class=\"hash\">{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td></tr>",
+        tx_link(&tx.txid), tx.get_size(), fee_or_unknown(tx, node), fee_per_byte,
+    )).collect();
+    page("Mempool", &format!(
+        "<p>{} transactions, {} bytes</p><table><tr><th>Txid</th><th class=\"amount\">Bytes</th><th class=\"amount\">Fee</th><th class=\"amount\">Fee per byte</th></tr>{}</table>",
+        node.pool.pool.len(), node.pool.get_size().to_formatted_string(&Locale::en), rows,
+    ))
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title><style>{style}</style></head><body>\
+         <nav><a href=\"/\">Blocks</a><a href=\"/mempool\">Mempool</a>\
+         <form action=\"/search\" style=\"display:inline\"><input name=\"q\" size=\"50\" placeholder=\"height, hash, txid or address\"></form></nav>\
+         <h1>{title}</h1>{body}</body></html>",
+        title = escape(title), style = STYLE, body = body,
+    )
+}
+
+// where an output goes, its address when it has one and its script otherwise
+// an htlc names both sides and when the sender can take the coins back
+fn owner(output: &Output, prefix: &str) -> String {
+    if let Some(key) = output.address() {
+        return address_link(&key, prefix);
+    }
+    match Htlc::parse(&output.script_pubkey) {
+        Some(htlc) => format!("htlc to {} for the preimage of <code>{}</code>, refundable to {} from {}",
+            address_link(&htlc.recipient, prefix), to_hex(&htlc.preimage_hash), address_link(&htlc.sender, prefix), lock_description(htlc.timeout)),
+        None => format!("<code>{}</code>", escape(&script::to_asm(&output.script_pubkey))),
+    }
+}
+
+fn address_link(key: &[u8;32], prefix: &str) -> String { format!("<a href=\"/address/{0}\">{0}</a>", address::encode(prefix, key)) }
+
+// a lock time is a height below the threshold and a unix time above it
+fn lock_description(lock_time: u32) -> String {
+    if lock_time < LOCKTIME_THRESHOLD {
+        format!("height {}", lock_time)
+    } else {
+        format!("unix time {}", lock_time)
+    }
+}
+
+fn block_link(hash: &[u8;32]) -> String { format!("<a href=\"/block/{0}\">{0}</a>", to_hex(hash)) }
+
+fn tx_link(txid: &[u8;32]) -> String { format!("<a href=\"/tx/{0}\">{0}</a>", to_hex(txid)) }
+
+fn escape(text: &str) -> String {
+    text.chars().map(|c| match c {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '"' => "&quot;".to_string(),
+        '\'' => "&#39;".to_string(),
+        c => c.to_string(),
+    }).collect()
+}
+
+// the decoded value of a query parameter, forms send spaces as + and everything else percent encoded
+fn query_value(query: &str, name: &str) -> Option<String> {
+    let value = query.split('&').find_map(|pair| pair.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value))?;
+    let mut bytes = vec![];
+    let mut chars = value.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{funded_node};
+    use crate::chain_params::ChainParams;
+    use crate::input::{Input, SEQUENCE_FINAL};
+
+    #[test]
+    fn shows_an_unknown_fee_instead_of_failing() {
+        let mut node = Node::new(ChainParams::regtest());
+        // spends an output the chain doesn't have, like one whose block was pruned
+        let input = Input { txid: [7; 32], vout: 0, witness: vec![], sequence: SEQUENCE_FINAL };
+        let outputs = vec![Output::to_address(1000, [1; 32])];
+        let tx = Tx { txid: Tx::generate_txid(std::slice::from_ref(&input), &outputs, 0), inputs: vec![input], outputs, lock_time: 0 };
+        node.pool.pool.insert((0, tx.clone()));
+        assert!(render("/mempool", &mut node).unwrap().contains("unknown"));
+        assert!(render(&format!("/tx/{}", to_hex(&tx.txid)), &mut node).unwrap().contains("<dd>unknown</dd>"));
+    }
+
+    #[test]
+    fn rejects_a_history_page_past_the_end() {
+        let mut node = Node::new(ChainParams::regtest());
+        let address = address::encode(&node.chain.params.address_prefix, &node.wallets[0].address());
+        assert!(render(&format!("/address/{}?page=1", address), &mut node).is_ok());
+        assert_eq!(render(&format!("/address/{}?page={}", address, usize::MAX), &mut node), Err(PageError::BadRequest));
+        assert_eq!(render("/address/nonsense", &mut node), Err(PageError::NotFound));
+    }
+
+    #[test]
+    fn names_both_sides_of_an_htlc() {
+        let prefix = ChainParams::regtest().address_prefix;
+        let htlc = Htlc { preimage_hash: [3; 32], recipient: [1; 32], sender: [2; 32], timeout: 120 };
+        let shown = owner(&Output { amount: 1000, script_pubkey: htlc.script_pubkey() }, &prefix);
+        assert!(shown.starts_with(&format!("htlc to {}", address_link(&[1; 32], &prefix))));
+        assert!(shown.ends_with(&format!("refundable to {} from height 120", address_link(&[2; 32], &prefix))));
+        assert_eq!(Htlc::parse(&htlc.script_pubkey()), Some(htlc));
+    }
+
+    #[test]
+    fn shows_the_preimage_an_htlc_claim_reveals() {
+        let (mut node, address) = funded_node();
+        let recipient = node.new_address();
+        let (_, tx) = node.create_htlc(recipient, script::hash(b"secret"), 100, 5000, 10).unwrap();
+        node.submit_tx(tx.clone()).unwrap();
+        node.mine_block(address, 1).unwrap();
+        let (htlc, utxo) = node.find_htlc(&tx.txid, 0).unwrap();
+        let claim = node.claim_htlc(&htlc, &utxo, b"secret", 10).unwrap();
+        node.submit_tx(claim.clone()).unwrap();
+        let shown = render(&format!("/tx/{}", to_hex(&claim.txid)), &mut node).unwrap();
+        assert!(shown.contains(&format!("claimed with the preimage <code>{}</code>", to_hex(b"secret"))));
+    }
+}
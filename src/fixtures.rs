@@ -0,0 +1,43 @@
+// the chains, nodes and miners the tests of every module build on
+use crate::block::BlockError;
+use crate::blockchain::Blockchain;
+use crate::chain_params::ChainParams;
+use crate::global_utxos::{GlobalUtxos, Utxo};
+use crate::miner::Miner;
+use crate::node::Node;
+use crate::transactions::Tx;
+use crate::wallet::Wallet;
+
+// regtest, with coinbases spendable in the next block
+pub fn params() -> ChainParams { ChainParams { coinbase_maturity: 1, ..ChainParams::regtest() } }
+
+pub fn miner(address: [u8;32]) -> Miner { Miner { address, threads: 1, extra_data: vec![] } }
+
+// mines the txs on the tip, the coinbase claims no fees
+pub fn mine(chain: &mut Blockchain, miner: &Miner, transactions: Vec<Tx>) -> Result<(), BlockError> {
+    let block = miner.build_block(chain.get_height() + 1, chain.get_current_hash(), transactions, 0, chain);
+    chain.connect_block(block)
+}
+
+// a chain whose first coinbase, paid to the wallet, can be spent in the next block
+pub fn funded_chain() -> (Blockchain, Wallet, Miner) {
+    let mut chain = Blockchain::new(params());
+    let wallet = Wallet::new();
+    let miner = miner(wallet.address());
+    mine(&mut chain, &miner, vec![]).unwrap();
+    (chain, wallet, miner)
+}
+
+pub fn utxos_of(chain: &Blockchain, wallet: &Wallet) -> Vec<Utxo> {
+    let mut utxos = GlobalUtxos::new();
+    utxos.find_utxos(chain);
+    utxos.get_utxos(&wallet.script_pubkey()).cloned().unwrap_or_default()
+}
+
+// a node whose first wallet can spend the first coinbase, returning the wallet's address
+pub fn funded_node() -> (Node, [u8;32]) {
+    let mut node = Node::new(params());
+    let address = node.wallets[0].address();
+    node.mine_block(address, 1).unwrap();
+    (node, address)
+}
@@ -1,62 +1,99 @@
 use std::collections::HashMap;
-use std::sync::{Mutex, MutexGuard};
-use std::sync::Arc;
-
-use ed25519_dalek::{Signature, Verifier, VerifyingKey};
-use rayon::prelude::*;
 
 use crate::blockchain::Blockchain;
+use crate::output::Output;
+use crate::snapshot::Coin;
+use crate::transactions::Tx;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Utxo {
+    pub amount: u64,
+    pub txid: [u8;32],
+    pub vout: u32,
+    // height of the block that created the output
+    pub height: u32,
+    pub coinbase: bool,
+}
+
+impl Utxo {
+    // coinbase outputs can only be spent once they are buried maturity blocks deep
+    pub fn is_mature(&self, spend_height: u32, maturity: u32) -> bool {
+        !self.coinbase || spend_height.saturating_sub(self.height) >= maturity
+    }
+}
 
 pub struct GlobalUtxos {
-    // hash_table stores wallets addresses as keys, and utxos and values for quick lookup
-    pub utxos: HashMap<[u8;32],Vec<(u64, [u8;32])>>,
+    // hash_table stores locking scripts as keys, and utxos and values for quick lookup
+    pub utxos: HashMap<Vec<u8>,Vec<Utxo>>,
     known_blockchain_height: u32,
-    verifying_keys: Vec<[u8;32]>,
+    // the script each unspent (txid, vout) is filed under
+    outpoints: HashMap<([u8;32], u32), Vec<u8>>,
+}
+
+impl Default for GlobalUtxos {
+    fn default() -> GlobalUtxos { GlobalUtxos::new() }
 }
 
 impl GlobalUtxos {
     pub fn new() -> GlobalUtxos {
-        GlobalUtxos { utxos: HashMap::new(), verifying_keys: Vec::new(), known_blockchain_height: 0}
+        GlobalUtxos { utxos: HashMap::new(), outpoints: HashMap::new(), known_blockchain_height: 0}
     }
 
-    pub fn get_utxos(&mut self, address: &[u8;32]) -> Option<&Vec<(u64,[u8;32])>> { self.utxos.get(address) }
-
-    pub fn find_utxos(&mut self, chain: &Blockchain){
-        // updates verifying keys with all wallet addresses found in unknown part of blockchain
-        chain.chain.iter().enumerate().filter(|(index,_)| *index as u32 > self.known_blockchain_height)
-            .flat_map(|(_,block)| block.transactions.iter()).for_each(| tx| {
-            tx.outputs.iter().for_each(|out| {
-                self.utxos.entry(out.address)
-                    .and_modify(|value| {
-                        if !value.iter().any(|(_, txid)| *txid == tx.txid) {
-                            value.push((out.amount, tx.txid));
-                        }
-                    })
-                    .or_insert(vec![(out.amount, tx.txid)]);
-                // if an output address is found that isn't known, it as added to the vector of keys
-                if !self.verifying_keys.contains(&out.address) {
-                    self.verifying_keys.push(out.address);
-                }
-            })
+    // the utxo set of a snapshot taken at height, blocks after it are scanned as usual
+    pub fn from_coins<'a>(coins: impl IntoIterator<Item = &'a Coin>, height: u32) -> GlobalUtxos {
+        let mut utxos = GlobalUtxos { known_blockchain_height: height, ..GlobalUtxos::new() };
+        coins.into_iter().for_each(|coin| {
+            let utxo = Utxo { amount: coin.output.amount, txid: coin.txid, vout: coin.vout, height: coin.height, coinbase: coin.coinbase };
+            utxos.outpoints.insert((coin.txid, coin.vout), coin.output.script_pubkey.clone());
+            utxos.utxos.entry(coin.output.script_pubkey.clone()).or_default().push(utxo);
         });
-        let utxos = Arc::new(Mutex::new(&mut self.utxos));
-        chain.chain.iter().enumerate().filter(|(index, _)| *index as u32 > self.known_blockchain_height)
-            .flat_map(|(_, block)| block.transactions.iter()).for_each(|tx| {
-            tx.inputs.iter().for_each(|input| {
-                // for each public key, we search for spent txs
-                // only searches blockchain for blocks that are not known to optimize performance
-                self.verifying_keys.par_iter().for_each(|key|
-                {
-                    // if an input signature is valid for a key, the transaction associated with it has been spent
-                    // thus we remove it from the known unspent tx outputs
-                    if VerifyingKey::from_bytes(&key).unwrap().verify(&input.txid, &Signature::from_bytes(&input.signature)).is_ok() {
-                        let mut utxos_lock: MutexGuard<&mut HashMap<[u8;32],Vec<(u64, [u8;32])>>> = utxos.lock().unwrap();
-                        utxos_lock.get_mut(key).unwrap().retain(|(_,txid)|*txid!=input.txid);
+        utxos
+    }
+
+    // every utxo with its script, sorted by outpoint
+    pub fn coins(&self) -> Vec<Coin> {
+        let mut coins: Vec<Coin> = self.utxos.iter().flat_map(|(script_pubkey, utxos)| utxos.iter().map(|utxo| Coin {
+            txid: utxo.txid,
+            vout: utxo.vout,
+            output: Output { amount: utxo.amount, script_pubkey: script_pubkey.clone() },
+            height: utxo.height,
+            coinbase: utxo.coinbase,
+        })).collect();
+        coins.sort_by_key(|coin| (coin.txid, coin.vout));
+        coins
+    }
+
+    pub fn get_utxos(&self, script_pubkey: &[u8]) -> Option<&Vec<Utxo>> { self.utxos.get(script_pubkey) }
+
+    // finds the unspent outputs a transaction's inputs spend, whether the witnesses are valid is left to script verification
+    pub fn find_spent_utxos(&self, tx: &Tx) -> Vec<Utxo> {
+        tx.inputs.iter().filter_map(|input| {
+            let script_pubkey = self.outpoints.get(&(input.txid, input.vout))?;
+            self.utxos.get(script_pubkey)?.iter().find(|utxo| utxo.txid == input.txid && utxo.vout == input.vout).copied()
+        }).collect()
+    }
+
+    pub fn find_utxos(&mut self, chain: &Blockchain) { self.find_utxos_up_to(chain, chain.get_height()) }
+
+    // leaves the set as it was after the block at height, which has to be in the chain
+    pub fn find_utxos_up_to(&mut self, chain: &Blockchain, height: u32) {
+        // only scans blocks that are not known yet, a tx at a time so outputs spent later in the same block are removed again
+        chain.chain.iter().filter(|block| block.index > self.known_blockchain_height && block.index <= height)
+            .flat_map(|block| block.transactions.iter().map(move |tx| (block.index, tx))).for_each(|(height, tx)| {
+            tx.inputs.iter().filter(|_| !tx.is_coinbase()).for_each(|input| {
+                if let Some(script_pubkey) = self.outpoints.remove(&(input.txid, input.vout)) {
+                    if let Some(utxos) = self.utxos.get_mut(&script_pubkey) {
+                        utxos.retain(|utxo| utxo.txid != input.txid || utxo.vout != input.vout);
                     }
-                })
+                }
             });
+            tx.outputs.iter().enumerate().for_each(|(vout, out)| {
+                let utxo = Utxo { amount: out.amount, txid: tx.txid, vout: vout as u32, height, coinbase: tx.is_coinbase() };
+                if self.outpoints.insert((tx.txid, utxo.vout), out.script_pubkey.clone()).is_none() {
+                    self.utxos.entry(out.script_pubkey.clone()).or_default().push(utxo);
+                }
+            })
         });
-        self.known_blockchain_height = chain.get_height();
+        self.known_blockchain_height = self.known_blockchain_height.max(height);
     }
 }
-
@@ -0,0 +1,134 @@
+use crate::script::{self, OP_CHECKLOCKTIMEVERIFY, OP_CHECKSIG, OP_DROP, OP_ELSE, OP_ENDIF, OP_EQUALVERIFY, OP_HASH, OP_IF};
+use crate::transactions::Tx;
+
+// terms of a hash time locked contract, the recipient can claim the coins by revealing the preimage of the hash
+// and the sender can take them back once the timeout has passed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Htlc {
+    pub preimage_hash: [u8;32],
+    pub recipient: [u8;32],
+    pub sender: [u8;32],
+    // absolute lock time, a height or a timestamp just like Tx::lock_time
+    pub timeout: u32,
+}
+
+impl Htlc {
+    // claimed with a witness of [signature, preimage, 1], refunded with [signature, empty]
+    pub fn script_pubkey(&self) -> Vec<u8> {
+        let mut script = vec![OP_IF, OP_HASH];
+        script::push_data(&mut script, &self.preimage_hash);
+        script.push(OP_EQUALVERIFY);
+        script::push_data(&mut script, &self.recipient);
+        script.extend_from_slice(&[OP_CHECKSIG, OP_ELSE]);
+        script::push_number(&mut script, self.timeout);
+        script.extend_from_slice(&[OP_CHECKLOCKTIMEVERIFY, OP_DROP]);
+        script::push_data(&mut script, &self.sender);
+        script.extend_from_slice(&[OP_CHECKSIG, OP_ENDIF]);
+        script
+    }
+
+    pub fn parse(script: &[u8]) -> Option<Htlc> {
+        let items = script::instructions(script)?;
+        let [(OP_IF, _), (OP_HASH, _), (32, preimage_hash), (OP_EQUALVERIFY, _), (32, recipient), (OP_CHECKSIG, _), (OP_ELSE, _),
+            timeout, (OP_CHECKLOCKTIMEVERIFY, _), (OP_DROP, _), (32, sender), (OP_CHECKSIG, _), (OP_ENDIF, _)] = items.as_slice() else {
+            return None;
+        };
+        Some(Htlc {
+            preimage_hash: (*preimage_hash).try_into().ok()?,
+            recipient: (*recipient).try_into().ok()?,
+            sender: (*sender).try_into().ok()?,
+            timeout: script::read_number(*timeout)?,
+        })
+    }
+
+    // claiming reveals the preimage, which is what lets the other side of a swap claim too
+    pub fn find_preimage(&self, tx: &Tx) -> Option<Vec<u8>> {
+        tx.inputs.iter().find_map(|input| match input.witness.as_slice() {
+            [_, preimage, branch] if script::cast_to_bool(branch) && script::hash(preimage) == self.preimage_hash => Some(preimage.clone()),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::params;
+    use crate::mempool::MempoolError;
+    use crate::node::Node;
+    use crate::transactions::TxError;
+    use crate::wallet::Wallet;
+
+    const FEE: u64 = 10;
+
+    // a chain whose first coinbase, paid to the wallet, is spendable, later blocks pay a throwaway miner
+    fn funded_node_of(wallet: &Wallet) -> (Node, [u8;32]) {
+        let mut node = Node::new(params());
+        let miner = Wallet::new().address();
+        node.mine_block(wallet.address(), 1).unwrap();
+        node.mine_block(miner, 1).unwrap();
+        (node, miner)
+    }
+
+    // locks amount of the wallet's coins in the htlc and mines it
+    fn lock(node: &mut Node, miner: [u8;32], wallet: &mut Wallet, htlc: &Htlc, amount: u64) {
+        let utxos = node.utxos.get_utxos(&wallet.script_pubkey()).unwrap().clone();
+        let tx = wallet.create_htlc(htlc, amount, FEE, &utxos, node.chain.get_height() + 1, node.chain.params.coinbase_maturity).unwrap();
+        node.submit_tx(tx).unwrap();
+        node.mine_block(miner, 1).unwrap();
+    }
+
+    fn balance(node: &Node, script_pubkey: &[u8]) -> u64 {
+        node.utxos.get_utxos(script_pubkey).into_iter().flatten().map(|utxo| utxo.amount).sum()
+    }
+
+    // alice trades coins on one chain for bob's coins on another, without either of them being able to cheat
+    #[test]
+    fn swaps_coins_between_two_chains() {
+        let (mut alice, mut bob) = (Wallet::new(), Wallet::new());
+        let (mut chain_a, miner) = funded_node_of(&alice);
+        let (mut chain_b, _) = funded_node_of(&bob);
+        // alice picks the secret and locks her coins first, with the longer timeout
+        let secret: [u8;32] = rand::random();
+        let alice_htlc = Htlc { preimage_hash: script::hash(&secret), recipient: bob.address(), sender: alice.address(), timeout: chain_a.chain.get_height() + 20 };
+        lock(&mut chain_a, miner, &mut alice, &alice_htlc, 1000000);
+        // bob sees alice's htlc and locks his coins to the same hash, refundable sooner so he's safe if alice never claims
+        assert_eq!(balance(&chain_a, &alice_htlc.script_pubkey()), 1000000);
+        let bob_htlc = Htlc { recipient: alice.address(), sender: bob.address(), timeout: chain_b.chain.get_height() + 10, ..alice_htlc };
+        lock(&mut chain_b, miner, &mut bob, &bob_htlc, 2000000);
+        // only the secret claims, and only for the recipient
+        let utxo = chain_b.utxos.get_utxos(&bob_htlc.script_pubkey()).unwrap()[0];
+        assert!(matches!(alice.claim_htlc(&bob_htlc, &utxo, b"guess", FEE), Err(TxError::BadPreimage)));
+        assert!(matches!(bob.claim_htlc(&bob_htlc, &utxo, &secret, FEE), Err(TxError::NotASigner)));
+        // claiming bob's coins reveals the secret on chain B
+        chain_b.submit_tx(alice.claim_htlc(&bob_htlc, &utxo, &secret, FEE).unwrap()).unwrap();
+        chain_b.mine_block(miner, 1).unwrap();
+        // which bob reads back from the chain to claim alice's coins
+        let revealed = chain_b.chain.chain.last().unwrap().transactions.iter().find_map(|tx| bob_htlc.find_preimage(tx)).unwrap();
+        let utxo = chain_a.utxos.get_utxos(&alice_htlc.script_pubkey()).unwrap()[0];
+        chain_a.submit_tx(bob.claim_htlc(&alice_htlc, &utxo, &revealed, FEE).unwrap()).unwrap();
+        chain_a.mine_block(miner, 1).unwrap();
+        assert_eq!(balance(&chain_b, &alice.script_pubkey()), 2000000 - FEE);
+        assert_eq!(balance(&chain_a, &bob.script_pubkey()), 1000000 - FEE);
+        assert_eq!(balance(&chain_a, &alice_htlc.script_pubkey()) + balance(&chain_b, &bob_htlc.script_pubkey()), 0);
+    }
+
+    // a swap bob walks away from, alice gets her coins back but not before the timeout
+    #[test]
+    fn refunds_only_after_the_timeout() {
+        let mut alice = Wallet::new();
+        let (mut node, miner) = funded_node_of(&alice);
+        let htlc = Htlc { preimage_hash: script::hash(b"secret"), recipient: Wallet::new().address(), sender: alice.address(), timeout: node.chain.get_height() + 3 };
+        lock(&mut node, miner, &mut alice, &htlc, 1000000);
+        let utxo = node.utxos.get_utxos(&htlc.script_pubkey()).unwrap()[0];
+        assert!(matches!(Wallet::new().refund_htlc(&htlc, &utxo, FEE), Err(TxError::NotASigner)));
+        let refund = alice.refund_htlc(&htlc, &utxo, FEE).unwrap();
+        while node.chain.get_height() < htlc.timeout {
+            assert!(matches!(node.submit_tx(refund.clone()), Err(MempoolError::TimeLocked)));
+            node.mine_block(miner, 1).unwrap();
+        }
+        node.submit_tx(refund).unwrap();
+        node.mine_block(miner, 1).unwrap();
+        assert_eq!(balance(&node, &htlc.script_pubkey()), 0);
+    }
+}
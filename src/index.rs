@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+
+use crate::block::Block;
+use crate::snapshot::Coin;
+
+const CONNECT: u8 = 1;
+const DISCONNECT: u8 = 0;
+
+// the hash of the block a tx was mined in and its position there
+pub type TxLocation = ([u8;32], u32);
+
+// an output paying to an address, or an input spending one of its outputs, with the amount it added or took away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AddressEvent {
+    pub txid: [u8;32],
+    pub height: u32,
+    pub delta: i64,
+}
+
+struct Record {
+    connect: bool,
+    hash: [u8;32],
+    height: u32,
+    txids: Vec<[u8;32]>,
+}
+
+// where blocks and txs sit in the chain, so looking them up doesn't scan every block
+pub struct ChainIndex {
+    // block hash -> height
+    pub blocks: HashMap<[u8;32], u32>,
+    // only kept when the tx index is enabled
+    pub txs: Option<HashMap<[u8;32], TxLocation>>,
+    // script_pubkey -> every event touching it in chain order, only kept when the address index is enabled
+    pub addresses: Option<HashMap<Vec<u8>, Vec<AddressEvent>>>,
+    // every connect and disconnect is appended here, so the index can be reopened without reindexing
+    log: Option<File>,
+}
+
+impl ChainIndex {
+    pub fn new(tx_index: bool, address_index: bool) -> ChainIndex {
+        ChainIndex { blocks: HashMap::new(), txs: tx_index.then(HashMap::new), addresses: address_index.then(HashMap::new), log: None }
+    }
+
+    // replays the log at path if there is one, later changes are appended to it
+    // blocks of the log that keep rejects, like ones lost in a reorg the log never saw, are disconnected again
+    // the log only keeps txids, address events of the blocks it knows have to be added again with restore
+    pub fn open(path: &str, tx_index: bool, address_index: bool, keep: impl Fn(&[u8;32], u32) -> bool) -> io::Result<ChainIndex> {
+        let mut index = ChainIndex::new(tx_index, address_index);
+        let mut bytes = vec![];
+        match File::open(path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut bytes)?;
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error),
+        }
+        // a record cut short by a crash is dropped, along with anything after it
+        let mut reader = bytes.as_slice();
+        let mut valid_len = 0;
+        let mut connected = HashMap::new();
+        while let Some(record) = read_record(&mut reader) {
+            if record.connect {
+                index.insert(record.hash, record.height, &record.txids);
+                connected.insert(record.hash, (record.height, record.txids));
+            } else {
+                index.remove(&record.hash, &record.txids);
+                connected.remove(&record.hash);
+            }
+            valid_len = bytes.len() - reader.len();
+        }
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        log.set_len(valid_len as u64)?;
+        index.log = Some(log);
+        for (hash, (height, txids)) in connected.into_iter().filter(|(hash, (height, _))| !keep(hash, *height)) {
+            index.remove(&hash, &txids);
+            index.append(DISCONNECT, hash, height, &txids);
+        }
+        Ok(index)
+    }
+
+    // spent lists the coins the inputs of the block spend in order, it's only needed for the address index
+    pub fn connect(&mut self, block: &Block, spent: &[Coin]) {
+        let txids: Vec<[u8;32]> = block.transactions.iter().map(|tx| tx.txid).collect();
+        self.insert(block.hash, block.index, &txids);
+        self.add_address_events(block, spent);
+        self.append(CONNECT, block.hash, block.index, &txids);
+    }
+
+    // only the tip can be disconnected, its address events are the last ones of every address it touched
+    pub fn disconnect(&mut self, block: &Block, spent: &[Coin]) {
+        let txids: Vec<[u8;32]> = block.transactions.iter().map(|tx| tx.txid).collect();
+        self.remove(&block.hash, &txids);
+        if let Some(addresses) = self.addresses.as_mut() {
+            let scripts = block.transactions.iter().flat_map(|tx| tx.outputs.iter()).chain(spent.iter().map(|coin| &coin.output)).map(|output| &output.script_pubkey);
+            for script_pubkey in scripts {
+                if let Some(events) = addresses.get_mut(script_pubkey) {
+                    while events.last().is_some_and(|event| event.height == block.index) {
+                        events.pop();
+                    }
+                }
+            }
+        }
+        self.append(DISCONNECT, block.hash, block.index, &txids);
+    }
+
+    // indexes a block the log already knows without logging it again
+    // its txs are inserted again too, a block that was dropped on open may have held the entry of a tx it shares with this one
+    pub fn restore(&mut self, block: &Block, spent: &[Coin]) {
+        let txids: Vec<[u8;32]> = block.transactions.iter().map(|tx| tx.txid).collect();
+        self.insert(block.hash, block.index, &txids);
+        self.add_address_events(block, spent);
+    }
+
+    fn add_address_events(&mut self, block: &Block, spent: &[Coin]) {
+        let Some(addresses) = self.addresses.as_mut() else {
+            return;
+        };
+        let mut spent = spent.iter();
+        for tx in &block.transactions {
+            let event = |delta: u64, sign: i64| AddressEvent { txid: tx.txid, height: block.index, delta: sign * delta as i64 };
+            if !tx.is_coinbase() {
+                for coin in spent.by_ref().take(tx.inputs.len()) {
+                    addresses.entry(coin.output.script_pubkey.clone()).or_default().push(event(coin.output.amount, -1));
+                }
+            }
+            for output in &tx.outputs {
+                addresses.entry(output.script_pubkey.clone()).or_default().push(event(output.amount, 1));
+            }
+        }
+    }
+
+    pub fn get_height(&self, hash: &[u8;32]) -> Option<u32> { self.blocks.get(hash).copied() }
+
+    // None if the tx isn't indexed, or the tx index is disabled
+    pub fn get_tx(&self, txid: &[u8;32]) -> Option<TxLocation> { self.txs.as_ref()?.get(txid).copied() }
+
+    pub fn has_tx_index(&self) -> bool { self.txs.is_some() }
+
+    // the total number of events of the address and a page of them, newest first
+    // None if the address index is disabled
+    pub fn address_history(&self, script_pubkey: &[u8], skip: usize, count: usize) -> Option<(usize, Vec<AddressEvent>)> {
+        let events = self.addresses.as_ref()?.get(script_pubkey).map(|events| events.as_slice()).unwrap_or(&[]);
+        Some((events.len(), events.iter().rev().skip(skip).take(count).copied().collect()))
+    }
+
+    fn insert(&mut self, hash: [u8;32], height: u32, txids: &[[u8;32]]) {
+        self.blocks.insert(hash, height);
+        if let Some(txs) = self.txs.as_mut() {
+            txids.iter().enumerate().for_each(|(position, txid)| {
+                txs.insert(*txid, (hash, position as u32));
+            });
+        }
+    }
+
+    fn remove(&mut self, hash: &[u8;32], txids: &[[u8;32]]) {
+        self.blocks.remove(hash);
+        if let Some(txs) = self.txs.as_mut() {
+            txids.iter().for_each(|txid| {
+                // a tx mined again in a later block keeps that entry
+                if txs.get(txid).is_some_and(|(block, _)| block == hash) {
+                    txs.remove(txid);
+                }
+            });
+        }
+    }
+
+    // the index only speeds up lookups, so a log that can't be written is given up on rather than failing the block
+    fn append(&mut self, op: u8, hash: [u8;32], height: u32, txids: &[[u8;32]]) {
+        let Some(log) = self.log.as_mut() else {
+            return;
+        };
+        let mut record = vec![op];
+        record.extend_from_slice(&hash);
+        record.extend_from_slice(&height.to_be_bytes());
+        record.extend_from_slice(&(txids.len() as u32).to_be_bytes());
+        txids.iter().for_each(|txid| record.extend_from_slice(txid));
+        if let Err(error) = log.write_all(&record) {
+            eprintln!("index log disabled: {}", error);
+            self.log = None;
+        }
+    }
+}
+
+// an op, the block hash and height, then the txids of the block in order
+fn read_record(reader: &mut &[u8]) -> Option<Record> {
+    let (&op, rest) = reader.split_first()?;
+    if op != CONNECT && op != DISCONNECT {
+        return None;
+    }
+    let hash: [u8;32] = rest.get(..32)?.try_into().ok()?;
+    let height = u32::from_be_bytes(rest.get(32..36)?.try_into().ok()?);
+    let count = u32::from_be_bytes(rest.get(36..40)?.try_into().ok()?) as usize;
+    let txids = rest.get(40..40 + count.checked_mul(32)?)?.chunks(32).map(|txid| txid.try_into().unwrap()).collect();
+    *reader = &rest[40 + count * 32..];
+    Some(Record { connect: op == CONNECT, hash, height, txids })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{Input, SEQUENCE_FINAL};
+    use crate::output::Output;
+    use crate::transactions::Tx;
+
+    fn tx(inputs: Vec<Input>, outputs: Vec<Output>) -> Tx {
+        let txid = Tx::generate_txid(&inputs, &outputs, 0);
+        Tx { txid, inputs, outputs, lock_time: 0 }
+    }
+
+    // only the hash, height and txs matter to the index
+    fn block(height: u32, transactions: Vec<Tx>) -> Block {
+        Block { index: height, hash: [height as u8; 32], previous_hash: [0; 32], merkle_root: [0; 32], time: 0, target: 0, nonce: 0, transactions }
+    }
+
+    fn coinbase(height: u32, script_pubkey: &[u8], amount: u64) -> Tx {
+        tx(vec![Input::coinbase(height, 0, &[])], vec![Output { amount, script_pubkey: script_pubkey.to_vec() }])
+    }
+
+    fn total(index: &ChainIndex, script_pubkey: &[u8]) -> (usize, i64) {
+        let (count, events) = index.address_history(script_pubkey, 0, usize::MAX).unwrap();
+        (count, events.iter().map(|event| event.delta).sum())
+    }
+
+    #[test]
+    fn pages_address_history_newest_first() {
+        let (a, b) = (b"a".as_slice(), b"b".as_slice());
+        let mut index = ChainIndex::new(false, true);
+        let first = block(1, vec![coinbase(1, a, 50)]);
+        index.connect(&first, &[]);
+        // a spends its coinbase, paying 30 to b and 20 back to itself
+        let coin = Coin { txid: first.transactions[0].txid, vout: 0, output: first.transactions[0].outputs[0].clone(), height: 1, coinbase: true };
+        let input = Input { txid: coin.txid, vout: 0, witness: vec![], sequence: SEQUENCE_FINAL };
+        let payment = tx(vec![input], vec![Output { amount: 30, script_pubkey: b.to_vec() }, Output { amount: 20, script_pubkey: a.to_vec() }]);
+        let second = block(2, vec![coinbase(2, b, 50), payment]);
+        index.connect(&second, std::slice::from_ref(&coin));
+        let third = block(3, vec![coinbase(3, a, 10)]);
+        index.connect(&third, &[]);
+
+        let page = |skip, count| index.address_history(a, skip, count).unwrap();
+        let deltas = |events: Vec<AddressEvent>| events.iter().map(|event| event.delta).collect::<Vec<i64>>();
+        assert_eq!(page(0, 2).0, 4);
+        assert_eq!(deltas(page(0, 2).1), [10, 20]);
+        assert_eq!(deltas(page(2, 2).1), [-50, 50]);
+        assert_eq!(deltas(page(3, 10).1), [50]);
+        assert_eq!(page(0, 0).1, []);
+        // past the end there is nothing left, but the total still counts everything
+        assert_eq!(page(4, 10), (4, vec![]));
+        assert_eq!(page(usize::MAX, usize::MAX), (4, vec![]));
+        assert_eq!(index.address_history(b"c", 0, 10), Some((0, vec![])));
+        assert_eq!(ChainIndex::new(true, false).address_history(a, 0, 10), None);
+
+        assert_eq!((total(&index, a), total(&index, b)), ((4, 30), (2, 80)));
+        index.disconnect(&third, &[]);
+        assert_eq!((total(&index, a), total(&index, b)), ((3, 20), (2, 80)));
+        index.disconnect(&second, &[coin]);
+        assert_eq!((total(&index, a), total(&index, b)), ((1, 50), (0, 0)));
+    }
+}
@@ -1,5 +1,66 @@
-#[derive(Clone, Copy, Hash)]
+// a coinbase input spends nothing, so its only witness item carries the block height, an extra nonce and free form data
+pub const COINBASE_EXTRA_DATA_BYTES: usize = 52;
+
+// sequence values at or above this disable the relative lock of the input, and SEQUENCE_FINAL also opts out of the tx lock time
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+// when set the relative lock counts 512 second units of median time past, otherwise blocks
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+#[derive(Clone, Hash)]
 pub struct Input {
     pub txid: [u8;32],
-    pub signature: [u8;64],
-}
\ No newline at end of file
+    // index of the spent output in the tx it comes from
+    pub vout: u32,
+    // stack items that satisfy the script of the spent output
+    pub witness: Vec<Vec<u8>>,
+    pub sequence: u32,
+}
+
+impl Input {
+    pub fn coinbase(height: u32, extra_nonce: u64, extra_data: &[u8]) -> Input {
+        let mut data = vec![];
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&extra_nonce.to_be_bytes());
+        // extra data longer than the space left is cut off
+        data.extend_from_slice(&extra_data[..extra_data.len().min(COINBASE_EXTRA_DATA_BYTES)]);
+        Input { txid: [0; 32], vout: 0, witness: vec![data], sequence: SEQUENCE_FINAL }
+    }
+
+    // relative lock of at least this many blocks since the spent output was mined
+    pub fn relative_height_lock(blocks: u16) -> u32 { blocks as u32 }
+
+    // relative lock of at least this many seconds since the spent output was mined, rounded up to 512 second units
+    pub fn relative_time_lock(seconds: u32) -> u32 {
+        SEQUENCE_LOCKTIME_TYPE_FLAG | seconds.div_ceil(1 << SEQUENCE_LOCKTIME_GRANULARITY).min(SEQUENCE_LOCKTIME_MASK)
+    }
+
+    fn coinbase_data(&self) -> &[u8] { self.witness.first().map(Vec::as_slice).unwrap_or(&[]) }
+
+    // None when the coinbase witness is too short to hold the field
+    pub fn coinbase_height(&self) -> Option<u32> { self.coinbase_data().get(..4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap())) }
+
+    pub fn coinbase_extra_nonce(&self) -> Option<u64> { self.coinbase_data().get(4..12).map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap())) }
+
+    pub fn coinbase_extra_data(&self) -> &[u8] { self.coinbase_data().get(12..).unwrap_or(&[]) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_a_coinbase_commits_to() {
+        let input = Input::coinbase(7, u64::MAX - 1, b"pool");
+        assert_eq!(input.coinbase_height(), Some(7));
+        assert_eq!(input.coinbase_extra_nonce(), Some(u64::MAX - 1));
+        assert_eq!(input.coinbase_extra_data(), b"pool");
+        // data past the space left is cut off
+        let long = [9; COINBASE_EXTRA_DATA_BYTES + 10];
+        assert_eq!(Input::coinbase(7, 0, &long).coinbase_extra_data(), &long[..COINBASE_EXTRA_DATA_BYTES]);
+        let short = Input { txid: [0; 32], vout: 0, witness: vec![vec![0; 6]], sequence: SEQUENCE_FINAL };
+        assert_eq!((short.coinbase_height(), short.coinbase_extra_nonce(), short.coinbase_extra_data()), (Some(0), None, &[][..]));
+    }
+}
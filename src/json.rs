@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+
+use crate::address;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::input::Input;
+use crate::output::Output;
+use crate::script;
+use crate::transactions::Tx;
+use crate::util::{from_hex, to_hex};
+
+// json forms of blocks and txs, hashes and scripts in hex
+// fields like sizes, fees, asm and addresses are only there for readers, from_json rebuilds everything from the rest
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockJson {
+    pub hash: String,
+    pub height: u32,
+    pub previousblockhash: String,
+    pub merkleroot: String,
+    pub time: u64,
+    pub target: String,
+    pub nonce: String,
+    pub size: u32,
+    pub tx: Vec<TxJson>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TxJson {
+    pub txid: String,
+    pub size: u32,
+    // None for coinbases and txs spending outputs the chain doesn't have
+    #[serde(default)]
+    pub fee: Option<u64>,
+    pub locktime: u32,
+    pub inputs: Vec<InputJson>,
+    pub outputs: Vec<OutputJson>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InputJson {
+    pub txid: String,
+    pub vout: u32,
+    pub witness: Vec<String>,
+    pub sequence: u32,
+    // the decoded witness of a coinbase input
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase: Option<CoinbaseJson>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CoinbaseJson {
+    pub height: Option<u32>,
+    pub extranonce: Option<u64>,
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OutputJson {
+    pub amount: u64,
+    pub script: String,
+    #[serde(default)]
+    pub asm: String,
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+impl Block {
+    // the chain provides the address format and the fees of the txs
+    pub fn to_json(&self, chain: &Blockchain) -> BlockJson {
+        BlockJson {
+            hash: to_hex(&self.hash),
+            height: self.index,
+            previousblockhash: to_hex(&self.previous_hash),
+            merkleroot: to_hex(&self.merkle_root),
+            time: self.time,
+            target: format!("{:016x}", self.target),
+            nonce: format!("{:016x}", self.nonce),
+            size: self.get_size(),
+            tx: self.transactions.iter().map(|tx| tx.to_json(chain)).collect(),
+        }
+    }
+
+    // fails if the hash doesn't match the header, the body is left for validation to check
+    pub fn from_json(json: &BlockJson) -> Result<Block, JsonError> {
+        let transactions = json.tx.iter().map(Tx::from_json).collect::<Result<Vec<Tx>, JsonError>>()?;
+        let block = Block {
+            index: json.height,
+            hash: hash_from_hex(&json.hash)?,
+            previous_hash: hash_from_hex(&json.previousblockhash)?,
+            merkle_root: hash_from_hex(&json.merkleroot)?,
+            time: json.time,
+            target: u64_from_hex(&json.target)?,
+            nonce: u64_from_hex(&json.nonce)?,
+            transactions,
+        };
+        if block.header().calc_hash() != block.hash {
+            return Err(JsonError::Mismatch);
+        }
+        Ok(block)
+    }
+}
+
+impl Tx {
+    pub fn to_json(&self, chain: &Blockchain) -> TxJson {
+        let prefix = &chain.params.address_prefix;
+        let inputs = self.inputs.iter().map(|input| InputJson {
+            txid: to_hex(&input.txid),
+            vout: input.vout,
+            witness: input.witness.iter().map(|item| to_hex(item)).collect(),
+            sequence: input.sequence,
+            coinbase: self.is_coinbase().then(|| CoinbaseJson {
+                height: input.coinbase_height(),
+                extranonce: input.coinbase_extra_nonce(),
+                data: to_hex(input.coinbase_extra_data()),
+            }),
+        }).collect();
+        let outputs = self.outputs.iter().map(|output| OutputJson {
+            amount: output.amount,
+            script: to_hex(&output.script_pubkey),
+            asm: script::to_asm(&output.script_pubkey),
+            address: output.address().map(|key| address::encode(prefix, &key)),
+        }).collect();
+        // only a fee every spent output is known for
+        let known = !self.is_coinbase() && self.inputs.iter().all(|input| chain.find_output(&input.txid, input.vout).is_some());
+        let fee = known.then(|| self.checked_mining_fee(chain)).flatten();
+        TxJson { txid: to_hex(&self.txid), size: self.get_size(), fee, locktime: self.lock_time, inputs, outputs }
+    }
+
+    // fails if the txid doesn't match the contents
+    pub fn from_json(json: &TxJson) -> Result<Tx, JsonError> {
+        let inputs = json.inputs.iter().map(|input| Ok(Input {
+            txid: hash_from_hex(&input.txid)?,
+            vout: input.vout,
+            witness: input.witness.iter().map(|item| from_hex(item).ok_or(JsonError::BadHex)).collect::<Result<_, _>>()?,
+            sequence: input.sequence,
+        })).collect::<Result<Vec<Input>, JsonError>>()?;
+        let outputs = json.outputs.iter().map(|output| Ok(Output {
+            amount: output.amount,
+            script_pubkey: from_hex(&output.script).ok_or(JsonError::BadHex)?,
+        })).collect::<Result<Vec<Output>, JsonError>>()?;
+        let txid = Tx::generate_txid(&inputs, &outputs, json.locktime);
+        if to_hex(&txid) != json.txid.to_ascii_lowercase() {
+            return Err(JsonError::Mismatch);
+        }
+        Ok(Tx { txid, inputs, outputs, lock_time: json.locktime })
+    }
+}
+
+fn hash_from_hex(hex: &str) -> Result<[u8;32], JsonError> {
+    from_hex(hex).and_then(|bytes| bytes.try_into().ok()).ok_or(JsonError::BadHex)
+}
+
+// from_str_radix also takes a sign, so the digits are checked first
+fn u64_from_hex(hex: &str) -> Result<u64, JsonError> {
+    if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(JsonError::BadHex);
+    }
+    u64::from_str_radix(hex, 16).map_err(|_| JsonError::BadHex)
+}
+
+#[derive(Debug)]
+pub enum JsonError {
+    BadHex,
+    // a txid or block hash that doesn't belong to the contents next to it
+    Mismatch,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{funded_chain, mine, utxos_of};
+    use crate::wallet::Wallet;
+
+    // a chain whose tip has a coinbase and a payment
+    fn chain_with_a_payment() -> Blockchain {
+        let (mut chain, mut wallet, miner) = funded_chain();
+        let utxos = utxos_of(&chain, &wallet);
+        let tx = wallet.send_to_script(1000, 0, Wallet::new().script_pubkey(), &utxos, chain.get_height() + 1, chain.params.coinbase_maturity).unwrap();
+        mine(&mut chain, &miner, vec![tx]).unwrap();
+        chain
+    }
+
+    #[test]
+    fn round_trips_blocks_and_txs() {
+        let chain = chain_with_a_payment();
+        let block = chain.chain.last().unwrap();
+        let json = block.to_json(&chain);
+        // everything to_json shows comes back, and so the same hash
+        assert_eq!(Block::from_json(&json).unwrap().to_json(&chain), json);
+        // through text too, as a dump stores it
+        let text = serde_json::to_string(&json).unwrap();
+        assert_eq!(Block::from_json(&serde_json::from_str(&text).unwrap()).unwrap().hash, block.hash);
+        assert_eq!(json.tx.len(), 2);
+        assert!(json.tx[0].inputs[0].coinbase.as_ref().is_some_and(|coinbase| coinbase.height == Some(block.index)));
+        assert_eq!(json.tx[1].fee, Some(0));
+        for tx in &block.transactions {
+            let json = tx.to_json(&chain);
+            assert_eq!(Tx::from_json(&json).unwrap().to_json(&chain), json);
+        }
+    }
+
+    #[test]
+    fn rejects_edited_contents() {
+        let chain = chain_with_a_payment();
+        let block = chain.chain.last().unwrap();
+        let mut json = block.to_json(&chain);
+        json.time += 1;
+        assert!(matches!(Block::from_json(&json), Err(JsonError::Mismatch)));
+        let mut json = block.to_json(&chain);
+        json.hash = to_hex(&[0; 32]);
+        assert!(matches!(Block::from_json(&json), Err(JsonError::Mismatch)));
+        // a tx edited in a block fails on its txid before the merkle root is checked
+        let mut json = block.to_json(&chain);
+        json.tx[1].outputs[0].amount += 1;
+        assert!(matches!(Block::from_json(&json), Err(JsonError::Mismatch)));
+        let mut tx = block.transactions[1].to_json(&chain);
+        tx.txid = to_hex(&[0; 32]);
+        assert!(matches!(Tx::from_json(&tx), Err(JsonError::Mismatch)));
+    }
+
+    #[test]
+    fn rejects_signs_and_bad_digits() {
+        let chain = chain_with_a_payment();
+        let block = chain.chain.last().unwrap();
+        for (target, nonce) in [("+1", "0"), ("0", "-1"), ("0x1", "0"), ("", "0"), ("0", "10000000000000000")] {
+            let mut json = block.to_json(&chain);
+            json.target = target.to_string();
+            json.nonce = nonce.to_string();
+            assert!(matches!(Block::from_json(&json), Err(JsonError::BadHex)), "{} {}", target, nonce);
+        }
+        let mut json = block.to_json(&chain);
+        json.previousblockhash = format!("+1{}", &json.previousblockhash[2..]);
+        assert!(matches!(Block::from_json(&json), Err(JsonError::BadHex)));
+    }
+}
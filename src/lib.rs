@@ -0,0 +1,30 @@
+// the node, wallet and chain, the binary is the cli on top of them
+pub mod transactions;
+pub mod wallet;
+pub mod input;
+pub mod output;
+pub mod block;
+pub mod miner;
+pub mod blockchain;
+pub mod mempool;
+pub mod global_utxos;
+pub mod chain_params;
+pub mod sync;
+pub mod compact_block;
+pub mod node;
+pub mod rpc;
+pub mod explorer;
+pub mod json;
+pub mod cli;
+pub mod clock;
+pub mod script;
+pub mod sig_cache;
+pub mod snapshot;
+pub mod commitment;
+pub mod index;
+pub mod pst;
+pub mod htlc;
+pub mod address;
+pub mod util;
+#[cfg(test)]
+mod fixtures;
@@ -1,52 +1,60 @@
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use num_format::{Locale, ToFormattedString};
-use rayon::prelude::*;
 
-use blockchain::Blockchain;
-use miner::Miner;
-use wallet::Wallet;
-
-use crate::block::Block;
-use crate::global_utxos::GlobalUtxos;
-
-mod transactions;
-mod wallet;
-mod input;
-mod output;
-mod block;
-mod miner;
-mod blockchain;
-mod mempool;
-mod global_utxos;
+use transactions::blockchain::Blockchain;
+use transactions::chain_params::ChainParams;
+use transactions::cli;
+use transactions::commitment::UtxoCommitment;
+use transactions::compact_block::CompactBlock;
+use transactions::global_utxos::GlobalUtxos;
+use transactions::mempool;
+use transactions::miner::Miner;
+use transactions::node::Node;
+use transactions::snapshot::{self, UtxoSnapshot};
+use transactions::sync::{relay_blocks, InitialBlockDownload, Peer};
+use transactions::wallet::Wallet;
 
 const BLOCKS : u64=100;
 const WALLETS: u64 = 500;
 const OUTS_PER_WALLET: usize = 2;
-const BOB_TX_AMOUNT: u64 = 5000000 / (WALLETS+1);
 const NON_BOB_TX_AMOUNT: u64 = 1;
 const MINING_FEE: u64 = 10;
-const TARGET: u64 = 2u64.pow(64-5);
 fn main() {
-    test();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // the original benchmark is kept around as its own subcommand
+    if args.first().map(String::as_str) == Some("bench") {
+        bench();
+        return;
+    }
+    if let Err(error) = cli::run(&args) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
 }
 
-fn test() {
-    let genesis_block = Block { index: 0, hash: [0; 32], previous_hash: [0; 32], time: 3, target: 4, nonce: 5, transactions: Vec::new() };
-    let mut chain = Blockchain::create_from_genesis(genesis_block);
+fn bench() {
+    // bob pays everyone out of the first block reward right away, so coinbases mature after a single block
+    let params = ChainParams { coinbase_maturity: 1, tx_index: true, ..ChainParams::mainnet() };
+    let bob_tx_amount = params.subsidy(1) / (WALLETS+1);
+    let mut chain = Blockchain::new(params.clone());
 
     let mut bob = Wallet::new();
-    let bob_miner = Miner { address: bob.address(), threads: 1 };
+    let bob_miner = Miner { address: bob.address(), threads: 1, extra_data: Vec::new() };
 
     let mut wallets = vec![];
     for _ in 0..WALLETS {
         wallets.push(Wallet::new());
     }
     let mut pool = mempool::Mempool::new();
+    // follows the chain through compact block relay
+    let mut relay_node = Node::new(params.clone());
+    let mut relay_valid = true;
     let mut utxo_generator = GlobalUtxos::new();
     let  blockchain_start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-    let mut start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-    let mut end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let mut start;
+    let mut end;
 
     let mut block_times = vec![];
     let mut utxo_generation_times = vec![];
@@ -54,12 +62,21 @@ fn test() {
     let mut mempool_times = vec![];
 
     for block in 0..BLOCKS - 1 {
+        // a peer that saw the same transactions relayed keeps its own copy of the mempool
+        relay_node.pool = pool.clone();
         start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-        chain.add_block(bob_miner.generate_candidate_block(chain.get_height() + 1, chain.get_current_hash(), TARGET, &mut pool, &chain));
+        chain.add_block(bob_miner.generate_candidate_block(chain.get_height() + 1, chain.get_current_hash(), &pool, &chain));
+        pool.remove_mined(chain.chain.last().unwrap());
         end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
         block_times.push(end-start);
         println!("Block: {:<4} added to the chain! {:>10} nanos ", block,(end-start).to_formatted_string(&Locale::en));
 
+        let compact = CompactBlock::from_block(chain.chain.last().unwrap()).unwrap();
+        let missing = compact.reconstruct(&relay_node.pool).missing().len();
+        relay_valid &= relay_blocks(&mut relay_node, &chain).is_ok_and(|connected| connected == 1);
+        println!("Compact block relay:           {:>10} / {} bytes, {} txs missing",
+                 compact.get_size().to_formatted_string(&Locale::en), chain.chain.last().unwrap().get_size().to_formatted_string(&Locale::en), missing);
+
         start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
         utxo_generator.find_utxos(&chain);
         end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
@@ -68,7 +85,7 @@ fn test() {
         if block > 0 {
             wallets.iter().for_each(|wallet| {
                 start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-                let utxos = utxo_generator.get_utxos(&wallet.address()).unwrap().clone();
+                let utxos = utxo_generator.get_utxos(&wallet.script_pubkey()).unwrap().clone();
                 end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
                 utxo_times.push(end-start);
 
@@ -77,25 +94,28 @@ fn test() {
                 wallets.iter().for_each(|w| if w.address() != wallet.address() && addresses.len() < OUTS_PER_WALLET-1  {addresses.push(w.address())});
 
                 start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-                pool.add_tx(wallet.clone().send_amounts(amounts, MINING_FEE, addresses.clone(), &utxos).unwrap(),&chain,&utxos);
+                // once the mempool is full, transactions that don't pay more than what they'd replace are dropped
+                let _ = pool.add_tx(wallet.clone().send_amounts(amounts, MINING_FEE, addresses.clone(), &utxos, chain.get_height() + 1, params.coinbase_maturity).unwrap(),&chain,&utxos);
                 end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
                 mempool_times.push(end-start);
             });
-            println!("Mempool usage:                {} / {}    {:.2}%\n",pool.get_size().to_formatted_string(&Locale::en),mempool::MAX_MEMPOOL_SIZE, pool.get_size() as f64 *100.0  / mempool::MAX_MEMPOOL_SIZE as f64);
+            println!("Mempool usage:                {} / {}    {:.2}%\n",pool.get_size().to_formatted_string(&Locale::en),params.max_mempool_size, pool.get_size() as f64 *100.0  / params.max_mempool_size as f64);
         }
         else {
-            let utxos = utxo_generator.get_utxos(&bob.address()).unwrap();
+            let utxos = utxo_generator.get_utxos(&bob.script_pubkey()).unwrap();
             let mut addresses = vec![];
-            let amounts = vec![BOB_TX_AMOUNT;WALLETS as usize];
+            let amounts = vec![bob_tx_amount;WALLETS as usize];
             wallets.iter().for_each(|w|addresses.push(w.address()));
-            pool.add_tx(bob.send_amounts(amounts, MINING_FEE, addresses, utxos).unwrap(),&chain, utxos);
+            pool.add_tx(bob.send_amounts(amounts, MINING_FEE, addresses, utxos, chain.get_height() + 1, params.coinbase_maturity).unwrap(),&chain, utxos).unwrap();
         }
     }
-    chain.add_block(bob_miner.generate_candidate_block(chain.get_height() + 1, chain.get_current_hash(), TARGET, &mut pool, &chain));
+    relay_node.pool = pool.clone();
+    chain.add_block(bob_miner.generate_candidate_block(chain.get_height() + 1, chain.get_current_hash(), &pool, &chain));
+    pool.remove_mined(chain.chain.last().unwrap());
     end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
-    //chain.chain.last().unwrap().print();
+    relay_valid &= relay_blocks(&mut relay_node, &chain).is_ok_and(|connected| connected == 1);
     utxo_generator.find_utxos(&chain);
-    bob.calc_balance(utxo_generator.get_utxos(&bob.address()).unwrap());
+    bob.calc_balance(utxo_generator.get_utxos(&bob.script_pubkey()).unwrap(), chain.get_height() + 1, params.coinbase_maturity);
 
     println!("\n\n\nTime to generate {} blocks {} nanos", BLOCKS,(end - blockchain_start).to_formatted_string(&Locale::en));
     println!("Total Wallets:   {}   \nOutputs per Wallet: {}", WALLETS, OUTS_PER_WALLET);
@@ -104,7 +124,7 @@ fn test() {
     println!("Total size of blockchain:  {} bytes ",blockchain_size.to_formatted_string(&Locale::en));
     println!("Average size of block:     {} bytes \n",(blockchain_size as u64 / BLOCKS ).to_formatted_string(&Locale::en));
     let transaction_count = chain.chain.iter().flat_map(|block| {
-        block.transactions.iter().map(|tx|tx)
+        block.transactions.iter()
     }).count();
     let transaction_sizes: u32 = chain.chain.iter().flat_map(|block|{
         block.transactions.iter().map(|tx|tx.get_size())
@@ -114,10 +134,10 @@ fn test() {
     println!("Average transaction size: {} Bytes\n",(transaction_sizes / transaction_count as u32).to_formatted_string(&Locale::en));
     println!("Bob's balance of {} equals {} - {} + {} = {}",
         bob.get_balance().to_formatted_string(&Locale::en),
-        (BLOCKS*5000000).to_formatted_string(&Locale::en),
-        (BOB_TX_AMOUNT*WALLETS).to_formatted_string(&Locale::en),
+        params.total_supply_at(BLOCKS as u32).to_formatted_string(&Locale::en),
+        (bob_tx_amount*WALLETS).to_formatted_string(&Locale::en),
         (MINING_FEE*(transaction_count as u64 -BLOCKS-1)).to_formatted_string(&Locale::en),
-        bob.get_balance() == BLOCKS*5000000-BOB_TX_AMOUNT*WALLETS+MINING_FEE*(transaction_count as u64 -BLOCKS-1));
+        bob.get_balance() == params.total_supply_at(BLOCKS as u32)-bob_tx_amount*WALLETS+MINING_FEE*(transaction_count as u64 -BLOCKS-1));
 
     let min: u128 = utxo_generation_times.iter().cloned().min().unwrap();
     let sum: u128 = utxo_generation_times.iter().sum();
@@ -157,4 +177,29 @@ fn test() {
     println!("\nAverage Mempool update time per Block {} nanos",(sum/BLOCKS as u128).to_formatted_string(&Locale::en));
     println!("Mempool is handling around {} Txs per second",((transaction_count as u128-BLOCKS as u128) * 1000000000 / sum ).to_formatted_string(&Locale::en));
 
+    println!("\nRelayed {} compact blocks, valid = {}", relay_node.chain.get_height(), relay_valid && relay_node.chain.get_current_hash() == chain.get_current_hash());
+
+    // a fresh node catches up by downloading the chain from two peers serving the same blocks
+    let mut fresh_chain = Blockchain::new(params.clone());
+    let mut fresh_utxos = GlobalUtxos::new();
+    let peer: Arc<dyn Peer> = Arc::new(chain);
+    start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let synced = InitialBlockDownload::new().run(&mut fresh_chain, &mut fresh_utxos, &[Arc::clone(&peer), Arc::clone(&peer)]).unwrap();
+    end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    println!("\nSynced {} blocks from peers in {} nanos", synced, (end-start).to_formatted_string(&Locale::en));
+    println!("Synced UTXO set matches: {}", fresh_utxos.get_utxos(&bob.script_pubkey()) == utxo_generator.get_utxos(&bob.script_pubkey()));
+    println!("Synced UTXO commitment matches: {}", fresh_chain.utxo_commitment.hash() == UtxoCommitment::hash_of(&utxo_generator.coins()));
+
+    // another node starts from a snapshot of the tip instead, trusting its hash as published by the synced node
+    // and checking it by replaying the history from the peer afterwards
+    let snapshot = UtxoSnapshot::from_chain(&fresh_chain, fresh_chain.get_height()).unwrap();
+    start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let snapshot_node = Mutex::new(Node::from_snapshot(ChainParams { snapshot_hash: Some(snapshot.hash()), ..params.clone() }, &snapshot).unwrap());
+    end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    println!("\nLoaded a snapshot of {} coins in {} nanos", snapshot.coins.len(), (end-start).to_formatted_string(&Locale::en));
+    start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let validated = snapshot::validate_history(&snapshot_node, &[peer]).unwrap();
+    end = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    println!("Replayed the snapshot history in {} nanos, valid = {}", (end-start).to_formatted_string(&Locale::en), validated);
+
 }
@@ -1,29 +1,59 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use crate::block;
+use crate::block::Block;
 use crate::blockchain::Blockchain;
+use crate::global_utxos::Utxo;
 use crate::transactions::Tx;
 
-pub const MAX_MEMPOOL_SIZE: u32 = 150000;
-
+#[derive(Clone)]
 pub struct Mempool {
     // key is txid, and value is mining fee / bytes
     pub pool: BTreeSet<(u64,Tx)>,
+    // the outputs spent by txs in the pool and the txid spending each, a second tx spending one conflicts
+    pub spent: HashMap<([u8;32], u32), [u8;32]>,
+}
 
+impl Default for Mempool {
+    fn default() -> Mempool { Mempool::new() }
 }
 
 impl Mempool {
     pub fn new() -> Mempool {
-        Mempool { pool: BTreeSet::new()}
+        Mempool { pool: BTreeSet::new(), spent: HashMap::new() }
+    }
+
+    pub fn is_spent(&self, txid: &[u8;32], vout: u32) -> bool { self.spent.contains_key(&(*txid, vout)) }
+
+    fn insert(&mut self, fee_per_byte: u64, tx: Tx) {
+        tx.inputs.iter().for_each(|input| {
+            self.spent.insert((input.txid, input.vout), tx.txid);
+        });
+        self.pool.insert((fee_per_byte, tx));
     }
 
-    pub fn add_tx(&mut self, tx: Tx, chain: &Blockchain, utxos: &Vec<(u64, [u8;32])>) {
-        // if mempool has space, simply add tx to pool
-        if self.get_size() + tx.get_size() < MAX_MEMPOOL_SIZE {
-            self.verify(tx,chain,utxos);
+    fn remove(&mut self, pair: &(u64, Tx)) {
+        if self.pool.remove(pair) {
+            pair.1.inputs.iter().for_each(|input| {
+                self.spent.remove(&(input.txid, input.vout));
+            });
         }
-        // otherwise, if transaction would add higher fees to mempool, we add it
-        else {
+    }
+
+    // drops the txs the block mined and the ones that spend the same outputs, which can never be mined now
+    pub fn remove_mined(&mut self, block: &Block) {
+        let spent: HashSet<([u8;32], u32)> = block.transactions.iter().filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.inputs.iter().map(|input| (input.txid, input.vout))).collect();
+        let to_remove: Vec<(u64, Tx)> = self.pool.iter()
+            .filter(|(_, tx)| tx.inputs.iter().any(|input| spent.contains(&(input.txid, input.vout))))
+            .cloned().collect();
+        to_remove.iter().for_each(|pair| self.remove(pair));
+    }
+
+    pub fn add_tx(&mut self, tx: Tx, chain: &Blockchain, utxos: &[Utxo]) -> Result<(), MempoolError> {
+        // an invalid tx never gets to push others out, whatever fee it claims
+        let fee_per_byte = self.verify(&tx, chain, utxos)?;
+        // if the pool is full, the transaction has to pay more than the ones it pushes out
+        if self.get_size() + tx.get_size() >= chain.params.max_mempool_size {
             let size = tx.get_size();
             let mut sum = 0;
             let mut insertion_index= 0;
@@ -33,56 +63,226 @@ impl Mempool {
                 }
                 else {
                     // only replaces transactions if the new transaction fees are higher than the ones it is replacing
-                    if tx.calc_mining_fee_per_byte(chain) > *mfpb {
+                    if fee_per_byte > *mfpb {
                         insertion_index = index + 1;
                     }
                     break;
                 }
             }
             // if the transaction fees are less than the minimum, they are not added to the chain
-            if insertion_index > 0 {
-                let to_remove: Vec<(u64,Tx)> = self.pool.iter().enumerate().filter(|(index,(_,_))|*index<insertion_index)
-                    .map(|(index,pair)| pair.clone()).collect();
-                for (pair) in to_remove.iter(){
-                    self.pool.remove(pair);
-                }
-                self.verify(tx,chain,utxos);
+            if insertion_index == 0 {
+                return Err(MempoolError::FeeTooLow);
+            }
+            let to_remove: Vec<(u64,Tx)> = self.pool.iter().enumerate().filter(|(index,(_,_))|*index<insertion_index)
+                .map(|(_,pair)| pair.clone()).collect();
+            for pair in to_remove.iter(){
+                self.remove(pair);
             }
         }
+        self.insert(fee_per_byte, tx);
+        Ok(())
     }
 
-    fn verify(&mut self,tx: Tx, chain: &Blockchain, utxos: &Vec<(u64, [u8;32])>) {
-        let mut unspent_txids = vec![];
-        utxos.iter().for_each(|(_,tx)| unspent_txids.push(*tx));
-        if tx.inputs.iter().any(|input| !unspent_txids.contains(&input.txid)){
-            println!("I should totaly handle this erorr");
+    // the fee per byte of a tx that could go in the pool
+    fn verify(&self, tx: &Tx, chain: &Blockchain, utxos: &[Utxo]) -> Result<u64, MempoolError> {
+        let spent: Vec<Option<&Utxo>> = tx.inputs.iter().map(|input| utxos.iter().find(|utxo| utxo.txid == input.txid && utxo.vout == input.vout)).collect();
+        // the tx could be mined in the next block at the earliest
+        let spend_height = chain.get_height() + 1;
+        if !tx.check_structure() {
+            Err(MempoolError::InvalidTransaction)
+        }
+        else if spent.iter().any(|utxo| utxo.is_none()){
+            Err(MempoolError::MissingInputs)
+        }
+        // the first tx seen spending an output keeps it, there's no replacement
+        else if tx.inputs.iter().any(|input| self.is_spent(&input.txid, input.vout)) {
+            Err(MempoolError::Conflict)
+        }
+        else if spent.iter().flatten().any(|utxo| !utxo.is_mature(spend_height, chain.params.coinbase_maturity)) {
+            Err(MempoolError::ImmatureCoinbase)
+        }
+        else if !chain.check_locks(tx, spend_height, &spent.iter().flatten().map(|utxo| utxo.height).collect::<Vec<u32>>()) {
+            Err(MempoolError::TimeLocked)
+        }
+        else if !chain.check_scripts(tx) {
+            Err(MempoolError::InvalidScript)
         }
         else {
-            self.pool.insert((tx.calc_mining_fee_per_byte(chain), tx));
+            // outputs spending more than the inputs would underflow the fee
+            tx.calc_mining_fee_per_byte(chain).ok_or(MempoolError::InvalidTransaction)
         }
     }
-    pub fn calc_valid_tx_pool_and_fees(&mut self, chain: &Blockchain) -> (Vec<Tx>,u64) {
+    // the txs stay in the pool until the block mining them is connected, a rejected block loses none of them
+    pub fn calc_valid_tx_pool_and_fees(&self, chain: &Blockchain) -> (Vec<Tx>,u64) {
         let mut total_fees: u64 = 0;
         let mut transactions = vec![];
         let mut tx_pool_size: u32 = 0;
-        let mut removal_pairs = vec![];
 
-        self.pool.iter().rev().for_each(|(fee,ptx)|{
+        self.pool.iter().rev().for_each(|(_,ptx)|{
             // for each transaction in pool, we calculate mining fees
-            if ptx.get_size() + tx_pool_size + 228 < block::MAX_BLOCK_SIZE {
-                transactions.push(ptx.clone());
-                tx_pool_size += ptx.get_size();
-                total_fees += ptx.calc_mining_fee(chain);
-                removal_pairs.push((*fee,ptx.clone()));
+            if ptx.get_size() + tx_pool_size + 228 < chain.params.max_block_size {
+                // a tx whose inputs are no longer unspent can't be mined, it's skipped
+                let amounts: Option<Vec<u64>> = ptx.inputs.iter().map(|input| chain.coins.get(&(input.txid, input.vout)).map(|coin| coin.output.amount)).collect();
+                if let Some(mining_fee) = amounts.and_then(|amounts| ptx.fee_from(amounts)) {
+                    transactions.push(ptx.clone());
+                    tx_pool_size += ptx.get_size();
+                    total_fees += mining_fee;
+                }
             }
         });
-        for pair in removal_pairs.iter(){
-            self.pool.remove(pair);
-        }
         (transactions,total_fees)
     }
 
     pub fn get_size(&self) -> u32 { self.pool.iter().map(|(_,tx)|tx.get_size()).sum() }
 
 
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+pub enum MempoolError {
+    // malformed, or its outputs spend more than its inputs
+    InvalidTransaction,
+    MissingInputs,
+    ImmatureCoinbase,
+    TimeLocked,
+    InvalidScript,
+    FeeTooLow,
+    // spends an output a tx already in the pool spends
+    Conflict,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::chain_params::ChainParams;
+    use crate::clock::MockClock;
+    use crate::fixtures::{funded_chain, mine, miner, params, utxos_of};
+    use crate::input::{Input, SEQUENCE_FINAL};
+    use crate::output::Output;
+    use crate::wallet::Wallet;
+
+    fn signed_tx(wallet: &Wallet, utxo: &Utxo, amount: u64) -> Tx { locked_tx(wallet, utxo, amount, 0, SEQUENCE_FINAL) }
+
+    // the locks change the sighash, so they're set before signing
+    fn locked_tx(wallet: &Wallet, utxo: &Utxo, amount: u64, lock_time: u32, sequence: u32) -> Tx {
+        let input = Input { txid: utxo.txid, vout: utxo.vout, witness: vec![], sequence: SEQUENCE_FINAL };
+        let mut tx = Tx { txid: [0; 32], inputs: vec![input], outputs: vec![Output::to_address(amount, Wallet::new().address())], lock_time: 0 };
+        tx.set_sequence(0, sequence);
+        if lock_time != 0 {
+            tx.set_lock_time(lock_time);
+        }
+        tx.inputs[0].witness = vec![wallet.sign(&tx)];
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        tx
+    }
+
+    #[test]
+    fn rejects_outputs_spending_more_than_the_inputs() {
+        let (chain, wallet, _) = funded_chain();
+        let coinbase = utxos_of(&chain, &wallet)[0];
+        let mut pool = Mempool::new();
+        let tx = signed_tx(&wallet, &coinbase, coinbase.amount + 1);
+        assert!(matches!(pool.add_tx(tx, &chain, &[coinbase]), Err(MempoolError::InvalidTransaction)));
+        assert!(pool.pool.is_empty());
+    }
+
+    #[test]
+    fn doesnt_evict_for_a_tx_it_rejects() {
+        let (mut chain, wallet, _) = funded_chain();
+        let coinbase = utxos_of(&chain, &wallet)[0];
+        let mut pool = Mempool::new();
+        let tx = signed_tx(&wallet, &coinbase, coinbase.amount - 100);
+        chain.params.max_mempool_size = tx.get_size() + 1;
+        pool.add_tx(tx, &chain, &[coinbase]).unwrap();
+        // pays more than the tx in the pool, but isn't signed by the owner of the coin
+        let forged = signed_tx(&Wallet::new(), &coinbase, coinbase.amount - 10000);
+        assert!(pool.add_tx(forged, &chain, &[coinbase]).is_err());
+        assert_eq!(pool.pool.len(), 1);
+    }
+
+    #[test]
+    fn accepts_a_tx_paying_a_fee() {
+        let (chain, wallet, _) = funded_chain();
+        let coinbase = utxos_of(&chain, &wallet)[0];
+        let mut pool = Mempool::new();
+        pool.add_tx(signed_tx(&wallet, &coinbase, coinbase.amount - 100), &chain, &[coinbase]).unwrap();
+        let (transactions, fees) = pool.calc_valid_tx_pool_and_fees(&chain);
+        assert_eq!((transactions.len(), fees), (1, 100));
+    }
+
+    #[test]
+    fn rejects_a_second_tx_spending_the_same_output() {
+        let (chain, wallet, _) = funded_chain();
+        let coinbase = utxos_of(&chain, &wallet)[0];
+        let mut pool = Mempool::new();
+        pool.add_tx(signed_tx(&wallet, &coinbase, coinbase.amount - 100), &chain, &[coinbase]).unwrap();
+        let conflicting = signed_tx(&wallet, &coinbase, coinbase.amount - 1000);
+        assert!(matches!(pool.add_tx(conflicting, &chain, &[coinbase]), Err(MempoolError::Conflict)));
+        assert_eq!(pool.pool.len(), 1);
+    }
+
+    #[test]
+    fn drops_txs_conflicting_with_a_mined_block() {
+        let (mut chain, wallet, miner) = funded_chain();
+        let coinbase = utxos_of(&chain, &wallet)[0];
+        let mut pool = Mempool::new();
+        pool.add_tx(signed_tx(&wallet, &coinbase, coinbase.amount - 100), &chain, &[coinbase]).unwrap();
+        // another node mined a different spend of the same coinbase
+        let block = miner.build_block(2, chain.get_current_hash(), vec![signed_tx(&wallet, &coinbase, coinbase.amount - 1000)], 1000, &chain);
+        chain.connect_block(block.clone()).unwrap();
+        pool.remove_mined(&block);
+        assert!(pool.pool.is_empty());
+        assert!(!pool.is_spent(&coinbase.txid, coinbase.vout));
+    }
+
+    #[test]
+    fn rejects_txs_until_their_locks_are_over() {
+        let wallet = Wallet::new();
+        let clock = Arc::new(MockClock::new(params().genesis_time));
+        let mut chain = Blockchain::with_clock(params(), clock.clone());
+        let miner = miner(wallet.address());
+        // every block comes ten minutes after the one before
+        let mine = |chain: &mut Blockchain, count: u32| (0..count).for_each(|_| {
+            clock.advance(600);
+            chain.connect_block(miner.build_block(chain.get_height() + 1, chain.get_current_hash(), vec![], 0, chain)).unwrap();
+        });
+        mine(&mut chain, 4);
+        let mut coins = utxos_of(&chain, &wallet);
+        coins.sort_by_key(|coin| coin.height);
+        // a height and a time lock, then relative ones of three blocks and 1024 seconds on the two newest coins
+        let txs = [
+            locked_tx(&wallet, &coins[0], 1000, chain.get_height() + 1, SEQUENCE_FINAL),
+            locked_tx(&wallet, &coins[1], 1000, chain.median_time_past() as u32 + 1200, SEQUENCE_FINAL),
+            locked_tx(&wallet, &coins[3], 1000, 0, Input::relative_height_lock(3)),
+            locked_tx(&wallet, &coins[2], 1000, 0, Input::relative_time_lock(1024)),
+        ];
+        let mut pool = Mempool::new();
+        for tx in &txs {
+            assert!(matches!(pool.add_tx(tx.clone(), &chain, &coins), Err(MempoolError::TimeLocked)));
+        }
+        mine(&mut chain, 8);
+        for tx in txs {
+            pool.add_tx(tx, &chain, &coins).unwrap();
+        }
+        assert_eq!(pool.pool.len(), 4);
+    }
+
+    #[test]
+    fn accepts_a_coinbase_spend_once_it_matures() {
+        let wallet = Wallet::new();
+        let mut chain = Blockchain::new(ChainParams { coinbase_maturity: 3, ..params() });
+        mine(&mut chain, &miner(wallet.address()), vec![]).unwrap();
+        let other = miner(Wallet::new().address());
+        mine(&mut chain, &other, vec![]).unwrap();
+        let coinbase = utxos_of(&chain, &wallet)[0];
+        let tx = signed_tx(&wallet, &coinbase, coinbase.amount - 100);
+        // the next block is only two blocks past the coinbase
+        let mut pool = Mempool::new();
+        assert!(matches!(pool.add_tx(tx.clone(), &chain, &[coinbase]), Err(MempoolError::ImmatureCoinbase)));
+        assert!(pool.pool.is_empty());
+        mine(&mut chain, &other, vec![]).unwrap();
+        pool.add_tx(tx, &chain, &[coinbase]).unwrap();
+        assert_eq!(pool.pool.len(), 1);
+    }
+}
@@ -1,10 +1,10 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 use rand::random;
 
-use block::Block;
-
-use crate::block;
+use crate::block::{hash_to_u64, Block, BlockHeader};
 use crate::blockchain::Blockchain;
 use crate::input::Input;
 use crate::mempool::Mempool;
@@ -14,59 +14,83 @@ use crate::transactions::Tx;
 pub struct Miner {
     pub address: [u8;32],
     pub threads: u8,
+    // free form bytes committed to in every coinbase this miner creates
+    pub extra_data: Vec<u8>,
 }
 impl Miner {
 
-    pub fn generate_candidate_block(&self, index: u32, previous_hash: [u8;32], target: u64, pool: &mut Mempool, chain: &Blockchain) -> Block {
-        let (hash,nonce) = Miner::gen_valid_hash(index,previous_hash, target);
-        let (mut transactions, fees) = pool.calc_valid_tx_pool_and_fees(&chain);
-        transactions.insert(0,self.generate_coinbase(fees));
-        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let mut candidate =Block { index, hash, previous_hash, time, target, nonce, transactions };
-        candidate
+    pub fn generate_candidate_block(&self, index: u32, previous_hash: [u8;32], pool: &Mempool, chain: &Blockchain) -> Block {
+        let (transactions, fees) = pool.calc_valid_tx_pool_and_fees(chain);
+        self.build_block(index, previous_hash, transactions, fees, chain)
+    }
+    // mines a block paying the subsidy and fees to the miner on top of the given transactions
+    pub fn build_block(&self, index: u32, previous_hash: [u8;32], mut transactions: Vec<Tx>, fees: u64, chain: &Blockchain) -> Block {
+        let target = chain.params.target;
+        transactions.insert(0,self.generate_coinbase(index, 0, chain.params.subsidy(index), fees));
+        let merkle_root = Block::calc_merkle_root(&transactions);
+        // a clock behind the median of recent blocks still has to produce a valid timestamp
+        let time = chain.clock.now().max(chain.median_time_past() + 1);
+        let header = BlockHeader { index, hash: [0; 32], previous_hash, merkle_root, time, target, nonce: 0 };
+        let (hash,nonce) = self.gen_valid_hash(header);
+        Block { index, hash, previous_hash, merkle_root, time, target, nonce, transactions }
     }
-    pub fn generate_coinbase(&self, fees: u64) -> Tx {
-        const REWARD: u64 = 5000000;
+    pub fn generate_coinbase(&self, height: u32, extra_nonce: u64, reward: u64, fees: u64) -> Tx {
         let mut inputs = vec![];
         let mut outputs = vec![];
-        // inputs aren't important to coinbase Tx, however random signature given to prevent duplicate txid hash
-        let mut signature: [u8; 64] = [0; 64];
-        signature.iter_mut().for_each(|elm| *elm = random());
-
-
-        let coinbase_input = Input { txid: [0; 32], signature,};
-        let coinbase_output = Output { amount: REWARD+fees, address: self.address };
+        // committing to the height keeps coinbase txids unique, even when the same miner earns the same amount twice
+        let coinbase_input = Input::coinbase(height, extra_nonce, &self.extra_data);
+        let coinbase_output = Output::to_address(reward+fees, self.address);
 
 
         inputs.push(coinbase_input);
         outputs.push(coinbase_output);
-        let txid = Tx::generate_txid(&inputs, &outputs);
-        Tx { txid, inputs, outputs }
+        let txid = Tx::generate_txid(&inputs, &outputs, 0);
+        Tx { txid, inputs, outputs, lock_time: 0 }
     }
 
-    fn gen_valid_hash(index: u32, previous_hash: [u8;32], target: u64) -> ([u8;32],u64) {
-        let (mut hash, mut nonce) = Miner::gen_hash_nonce(index,previous_hash);
-        while Miner::h2_u64(hash) > target {
-            (hash, nonce) = Miner::gen_hash_nonce(index,previous_hash);
+    fn gen_valid_hash(&self, header: BlockHeader) -> ([u8;32],u64) {
+        if self.threads <= 1 {
+            let (mut hash, mut nonce) = Miner::gen_hash_nonce(header);
+            while hash_to_u64(hash) > header.target {
+                (hash, nonce) = Miner::gen_hash_nonce(header);
+            }
+            return (hash, nonce);
         }
-
-        (hash, nonce)
+        // every thread tries random nonces until one of them finds a valid hash
+        let found = AtomicBool::new(false);
+        let result = Mutex::new(None);
+        thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| {
+                    while !found.load(Ordering::Relaxed) {
+                        let (hash, nonce) = Miner::gen_hash_nonce(header);
+                        if hash_to_u64(hash) <= header.target && !found.swap(true, Ordering::Relaxed) {
+                            *result.lock().unwrap() = Some((hash, nonce));
+                        }
+                    }
+                });
+            }
+        });
+        result.into_inner().unwrap().unwrap()
     }
 
-    fn gen_hash_nonce(index: u32, previous_hash: [u8;32]) -> ([u8;32],u64) {
-        let mut hasher = blake3::Hasher::new();
-        let nonce: u64 = random();
-        hasher.update(&index.to_be_bytes());
-        hasher.update(&previous_hash);
-        hasher.update(&nonce.to_be_bytes());
-        (*hasher.finalize().as_bytes(),nonce)
+    fn gen_hash_nonce(mut header: BlockHeader) -> ([u8;32],u64) {
+        header.nonce = random();
+        (header.calc_hash(),header.nonce)
     }
+}
 
-    fn h2_u64(hash: [u8; 32]) -> u64 {
-        let mut value: u64 = 0;
-        for i in 0..8 {
-            value |= (hash[i] as u64) << (8 * (7 - i)); // Shifting from the most significant byte to the least
-        }
-        value
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::miner;
+
+    #[test]
+    fn gives_coinbases_of_different_heights_different_txids() {
+        let miner = miner([1; 32]);
+        let first = miner.generate_coinbase(1, 0, 5000, 0);
+        let second = miner.generate_coinbase(2, 0, 5000, 0);
+        assert_eq!(first.outputs[0].script_pubkey, second.outputs[0].script_pubkey);
+        assert_ne!(first.txid, second.txid);
+        assert_eq!(second.txid, miner.generate_coinbase(2, 0, 5000, 0).txid);
     }
 }
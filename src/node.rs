@@ -0,0 +1,279 @@
+use std::sync::{Mutex, MutexGuard};
+
+use crate::block::{Block, BlockError};
+use crate::blockchain::Blockchain;
+use crate::chain_params::ChainParams;
+use crate::global_utxos::{GlobalUtxos, Utxo};
+use crate::htlc::Htlc;
+use crate::index::AddressEvent;
+use crate::mempool::{Mempool, MempoolError};
+use crate::miner::Miner;
+use crate::output::Output;
+use crate::pst::Pst;
+use crate::script;
+use crate::snapshot::{SnapshotError, UtxoSnapshot};
+use crate::transactions::{Tx, TxError};
+use crate::wallet::Wallet;
+
+// everything a running node owns, kept together so it can be shared with the rpc server
+pub struct Node {
+    pub chain: Blockchain,
+    pub pool: Mempool,
+    pub utxos: GlobalUtxos,
+    pub wallets: Vec<Wallet>,
+}
+
+impl Node {
+    pub fn new(params: ChainParams) -> Node {
+        Node {
+            chain: Blockchain::new(params),
+            pool: Mempool::new(),
+            utxos: GlobalUtxos::new(),
+            wallets: vec![Wallet::new()],
+        }
+    }
+
+    // validates from the height of the snapshot on, the history before it can be checked later with snapshot::validate_history
+    pub fn from_snapshot(params: ChainParams, snapshot: &UtxoSnapshot) -> Result<Node, SnapshotError> {
+        Ok(Node {
+            chain: Blockchain::from_snapshot(params, snapshot)?,
+            pool: Mempool::new(),
+            utxos: GlobalUtxos::from_coins(&snapshot.coins, snapshot.height()),
+            wallets: vec![Wallet::new()],
+        })
+    }
+
+    // our own blocks are validated like any other, a pool tx that slipped through fails here instead of forking us off
+    pub fn mine_block(&mut self, address: [u8;32], threads: u8) -> Result<[u8;32], BlockError> {
+        let miner = Miner { address, threads, extra_data: Vec::new() };
+        let block = miner.generate_candidate_block(self.chain.get_height() + 1, self.chain.get_current_hash(), &self.pool, &self.chain);
+        let hash = block.hash;
+        self.submit_block(block)?;
+        Ok(hash)
+    }
+
+    // a block from elsewhere or our own, txs it mined leave the pool
+    pub fn submit_block(&mut self, block: Block) -> Result<(), BlockError> {
+        self.chain.connect_block(block)?;
+        self.utxos.find_utxos(&self.chain);
+        self.pool.remove_mined(self.chain.chain.last().unwrap());
+        Ok(())
+    }
+
+    // follows a longer branch, txs of the blocks it replaced go back to the pool if they're still valid
+    pub fn reorganize(&mut self, branch: Vec<Block>) -> Result<(), BlockError> {
+        let connected = branch.len();
+        let disconnected = self.chain.reorganize(branch)?;
+        // the utxo set is rebuilt from the chain, which only moves forward otherwise
+        self.utxos = GlobalUtxos::from_coins(self.chain.coins.values(), self.chain.get_height());
+        for block in &self.chain.chain[self.chain.chain.len() - connected..] {
+            self.pool.remove_mined(block);
+        }
+        for tx in disconnected.into_iter().rev().flat_map(|block| block.transactions.into_iter().skip(1)) {
+            let _ = self.submit_tx(tx);
+        }
+        Ok(())
+    }
+
+    pub fn submit_tx(&mut self, tx: Tx) -> Result<(), MempoolError> {
+        let spent = self.utxos.find_spent_utxos(&tx);
+        self.pool.add_tx(tx, &self.chain, &spent)
+    }
+
+    pub fn new_address(&mut self) -> [u8;32] {
+        let wallet = Wallet::new();
+        let address = wallet.address();
+        self.wallets.push(wallet);
+        address
+    }
+
+    // returns the (spendable, immature) balance summed over every wallet
+    pub fn get_balances(&mut self) -> (u64, u64) {
+        let spend_height = self.chain.get_height() + 1;
+        let maturity = self.chain.params.coinbase_maturity;
+        self.wallets.iter_mut().map(|wallet| {
+            wallet.calc_balance(&spendable_utxos(&self.utxos, &self.pool, &wallet.script_pubkey()), spend_height, maturity);
+            (wallet.get_balance(), wallet.get_immature_balance())
+        }).fold((0, 0), |(spendable, immature), (wallet_spendable, wallet_immature)| (spendable + wallet_spendable, immature + wallet_immature))
+    }
+
+    pub fn get_balance(&mut self) -> u64 { self.get_balances().0 }
+
+    // every tx that changed the wallet, with what it changed in total, newest first
+    // payments between wallets of the node cancel out, so they show up with the fee they paid
+    // None if the address index is disabled
+    pub fn get_history(&self, skip: usize, count: usize) -> Option<(usize, Vec<AddressEvent>)> {
+        let mut events: Vec<AddressEvent> = vec![];
+        for wallet in &self.wallets {
+            events.extend(self.chain.index.address_history(&wallet.script_pubkey(), 0, usize::MAX)?.1);
+        }
+        events.sort_by_key(|event| event.height);
+        let mut history: Vec<AddressEvent> = vec![];
+        for event in events {
+            match history.iter_mut().rev().take_while(|known| known.height == event.height).find(|known| known.txid == event.txid) {
+                Some(known) => known.delta += event.delta,
+                None => history.push(event),
+            }
+        }
+        Some((history.len(), history.into_iter().rev().skip(skip).take(count).collect()))
+    }
+
+    // an unsigned payment funded by the first wallet that can cover it, hinting which wallet has to sign
+    // lock_time and sequence, SEQUENCE_FINAL or a relative lock built by Input, keep it from being mined too early
+    pub fn create_pst(&mut self, address: [u8;32], amount: u64, mining_fee: u64, lock_time: u32, sequence: u32) -> Result<Pst, TxError> {
+        let total = amount.checked_add(mining_fee).ok_or(TxError::InsufficientBalance)?;
+        self.get_balance();
+        let (index, wallet) = self.wallets.iter_mut().enumerate().find(|(_, wallet)| wallet.get_balance() >= total)
+            .ok_or(TxError::InsufficientBalance)?;
+        let utxos = spendable_utxos(&self.utxos, &self.pool, &wallet.script_pubkey());
+        let mut pst = wallet.create_pst(vec![Output::to_address(amount, address)], mining_fee, &utxos, self.chain.get_height() + 1, self.chain.params.coinbase_maturity)?;
+        // the locks change the sighash, nobody has signed yet
+        (0..pst.tx.inputs.len()).for_each(|input| pst.tx.set_sequence(input, sequence));
+        if lock_time != 0 {
+            pst.tx.set_lock_time(lock_time);
+        }
+        let key = wallet.address();
+        (0..pst.inputs.len()).for_each(|input| pst.add_hint(input, key, &format!("wallet/{}", index)));
+        Ok(pst)
+    }
+
+    // an unsigned payment out of the coins locked to a multisig script, the change goes back to the script
+    // each co-signer adds a signature with sign_pst, once enough have the pst can be finalized
+    pub fn create_multisig_pst(&self, script_pubkey: &[u8], address: [u8;32], amount: u64, mining_fee: u64) -> Result<Pst, TxError> {
+        if script::parse_multisig(script_pubkey).is_none() {
+            return Err(TxError::Malformed);
+        }
+        let total = amount.checked_add(mining_fee).ok_or(TxError::InsufficientBalance)?;
+        let spend_height = self.chain.get_height() + 1;
+        let mut sum_of_inputs: u64 = 0;
+        // takes utxos until they cover the amount and fee
+        let utxos: Vec<Utxo> = spendable_utxos(&self.utxos, &self.pool, script_pubkey).into_iter()
+            .filter(|utxo| utxo.is_mature(spend_height, self.chain.params.coinbase_maturity))
+            .take_while(|utxo| {
+                let needed = sum_of_inputs < total;
+                sum_of_inputs += utxo.amount;
+                needed
+            }).collect();
+        let sum_of_inputs: u64 = utxos.iter().map(|utxo| utxo.amount).sum();
+        if sum_of_inputs < total {
+            return Err(TxError::InsufficientBalance);
+        }
+        let mut outputs = vec![Output::to_address(amount, address)];
+        if sum_of_inputs > total {
+            outputs.push(Output { amount: sum_of_inputs - total, script_pubkey: script_pubkey.to_vec() });
+        }
+        let spent = utxos.iter().map(|utxo| Output { amount: utxo.amount, script_pubkey: script_pubkey.to_vec() }).collect();
+        Pst::new(Wallet::create_multisig_tx(&utxos, outputs), spent).map_err(|_| TxError::Malformed)
+    }
+
+    // signs with every wallet of the node, returning how many signatures were added
+    pub fn sign_pst(&self, pst: &mut Pst) -> usize { self.wallets.iter().map(|wallet| pst.sign(wallet)).sum() }
+
+    pub fn send_to_address(&mut self, address: [u8;32], amount: u64, mining_fee: u64) -> Result<Tx, TxError> {
+        self.send_to_script(script::pay_to_pubkey(&address), amount, mining_fee)
+    }
+
+    // pays from the first wallet that can cover the amount and fee on its own
+    pub fn send_to_script(&mut self, script_pubkey: Vec<u8>, amount: u64, mining_fee: u64) -> Result<Tx, TxError> {
+        let total = amount.checked_add(mining_fee).ok_or(TxError::InsufficientBalance)?;
+        self.get_balance();
+        let wallet = self.wallets.iter_mut().find(|wallet| wallet.get_balance() >= total)
+            .ok_or(TxError::InsufficientBalance)?;
+        let utxos = spendable_utxos(&self.utxos, &self.pool, &wallet.script_pubkey());
+        wallet.send_to_script(amount, mining_fee, script_pubkey, &utxos, self.chain.get_height() + 1, self.chain.params.coinbase_maturity)
+    }
+
+    // locks amount in an htlc to the recipient, the wallet paying for it is the sender that can refund it
+    pub fn create_htlc(&mut self, recipient: [u8;32], preimage_hash: [u8;32], timeout: u32, amount: u64, mining_fee: u64) -> Result<(Htlc, Tx), TxError> {
+        let total = amount.checked_add(mining_fee).ok_or(TxError::InsufficientBalance)?;
+        self.get_balance();
+        let wallet = self.wallets.iter_mut().find(|wallet| wallet.get_balance() >= total)
+            .ok_or(TxError::InsufficientBalance)?;
+        let htlc = Htlc { preimage_hash, recipient, sender: wallet.address(), timeout };
+        let utxos = spendable_utxos(&self.utxos, &self.pool, &wallet.script_pubkey());
+        let tx = wallet.create_htlc(&htlc, amount, mining_fee, &utxos, self.chain.get_height() + 1, self.chain.params.coinbase_maturity)?;
+        Ok((htlc, tx))
+    }
+
+    // the terms and coin of an unspent htlc output
+    pub fn find_htlc(&self, txid: &[u8;32], vout: u32) -> Option<(Htlc, Utxo)> {
+        let coin = self.chain.coins.get(&(*txid, vout))?;
+        let htlc = Htlc::parse(&coin.output.script_pubkey)?;
+        Some((htlc, Utxo { amount: coin.output.amount, txid: coin.txid, vout: coin.vout, height: coin.height, coinbase: coin.coinbase }))
+    }
+
+    pub fn claim_htlc(&self, htlc: &Htlc, utxo: &Utxo, preimage: &[u8], mining_fee: u64) -> Result<Tx, TxError> {
+        self.wallet_of(&htlc.recipient)?.claim_htlc(htlc, utxo, preimage, mining_fee)
+    }
+
+    pub fn refund_htlc(&self, htlc: &Htlc, utxo: &Utxo, mining_fee: u64) -> Result<Tx, TxError> {
+        self.wallet_of(&htlc.sender)?.refund_htlc(htlc, utxo, mining_fee)
+    }
+
+    fn wallet_of(&self, address: &[u8;32]) -> Result<&Wallet, TxError> {
+        self.wallets.iter().find(|wallet| wallet.address() == *address).ok_or(TxError::NotASigner)
+    }
+}
+
+// the confirmed outputs of the script that no tx in the pool spends yet, what wallets pick coins from
+fn spendable_utxos(utxos: &GlobalUtxos, pool: &Mempool, script_pubkey: &[u8]) -> Vec<Utxo> {
+    utxos.get_utxos(script_pubkey).into_iter().flatten().filter(|utxo| !pool.is_spent(&utxo.txid, utxo.vout)).copied().collect()
+}
+
+// the node shared between the servers, one a handler panicked while holding may be half updated and isn't served any more
+pub fn lock(node: &Mutex<Node>) -> MutexGuard<'_, Node> { node.lock().expect("a panic left the node half updated") }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{funded_node, params, miner, mine};
+
+    #[test]
+    fn doesnt_pick_coins_a_pool_tx_already_spends() {
+        let (mut node, address) = funded_node();
+        let balance = node.get_balance();
+        let tx = node.send_to_address(Wallet::new().address(), 1000, 10).unwrap();
+        node.submit_tx(tx).unwrap();
+        // the only coin is spent by the pool, its change isn't confirmed yet
+        assert_eq!(node.get_balance(), 0);
+        assert!(matches!(node.send_to_address(Wallet::new().address(), 1000, 10), Err(TxError::InsufficientBalance)));
+        node.mine_block(address, 1).unwrap();
+        assert!(node.pool.pool.is_empty());
+        assert_eq!(node.get_balance(), 2 * balance - 1000);
+    }
+
+    #[test]
+    fn keeps_the_pool_when_a_mined_block_is_rejected() {
+        let (mut node, address) = funded_node();
+        let tx = node.send_to_address(Wallet::new().address(), 1000, 10).unwrap();
+        node.submit_tx(tx.clone()).unwrap();
+        // a second spend of the same coin slipped past the pool's checks
+        let mut double_spend = tx.clone();
+        double_spend.outputs[0].amount -= 1;
+        double_spend.txid = Tx::generate_txid(&double_spend.inputs, &double_spend.outputs, double_spend.lock_time);
+        node.pool.pool.insert((1, double_spend.clone()));
+        assert!(node.mine_block(address, 1).is_err());
+        assert_eq!(node.pool.pool.len(), 2);
+        node.pool.pool.remove(&(1, double_spend));
+        node.mine_block(address, 1).unwrap();
+        assert!(node.pool.pool.is_empty());
+    }
+
+    #[test]
+    fn puts_txs_of_replaced_blocks_back_in_the_pool() {
+        let (mut node, address) = funded_node();
+        let mut other = Blockchain::new(params());
+        other.connect_block(node.chain.chain[1].clone()).unwrap();
+        let tx = node.send_to_address(Wallet::new().address(), 1000, 10).unwrap();
+        node.submit_tx(tx.clone()).unwrap();
+        node.mine_block(address, 1).unwrap();
+        assert!(node.pool.pool.is_empty());
+        // another miner's branch, one block longer and without the payment
+        let miner = miner(Wallet::new().address());
+        (2..=3).for_each(|_| mine(&mut other, &miner, vec![]).unwrap());
+        node.reorganize(other.chain[2..].to_vec()).unwrap();
+        assert_eq!(node.chain.get_current_hash(), other.get_current_hash());
+        assert!(node.pool.pool.iter().any(|(_, pooled)| pooled.txid == tx.txid));
+        assert!(node.pool.is_spent(&tx.inputs[0].txid, tx.inputs[0].vout));
+    }
+}
@@ -1,5 +1,18 @@
+use crate::script;
+
 #[derive(Clone, Hash)]
 pub struct Output {
     pub amount: u64,
-    pub address: [u8;32],
-}
\ No newline at end of file
+    // conditions the witness of the spending input has to satisfy
+    pub script_pubkey: Vec<u8>,
+}
+
+impl Output {
+    // wallets pay straight to the key an address stands for
+    pub fn to_address(amount: u64, address: [u8;32]) -> Output {
+        Output { amount, script_pubkey: script::pay_to_pubkey(&address) }
+    }
+
+    // the address of a pay to pubkey output, other scripts don't have one
+    pub fn address(&self) -> Option<[u8;32]> { script::pubkey_from_p2pk(&self.script_pubkey) }
+}
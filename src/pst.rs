@@ -0,0 +1,257 @@
+use crate::output::Output;
+use crate::script::{self, ScriptError};
+use crate::transactions::{take, take_vec, Tx, TxError};
+use crate::wallet::Wallet;
+
+const PST_MAGIC: [u8;4] = *b"pst\xff";
+
+// a partially signed tx, the unsigned tx plus what every signer needs to know about its inputs
+// signatures only cover the sighash, so they can be made in any order and in different places
+#[derive(Clone)]
+pub struct Pst {
+    pub tx: Tx,
+    pub inputs: Vec<PstInput>,
+}
+
+#[derive(Clone)]
+pub struct PstInput {
+    // the output being spent, so an offline signer can check amounts and scripts without the chain
+    pub spent: Output,
+    // keys expected to sign, each with a free form hint telling its owner where the key is kept
+    pub derivation_hints: Vec<([u8;32], String)>,
+    pub signatures: Vec<([u8;32], Vec<u8>)>,
+    // set by finalize once enough signatures are collected
+    pub final_witness: Option<Vec<Vec<u8>>>,
+}
+
+impl Pst {
+    // spent lists the output each input of the tx spends, in input order
+    pub fn new(mut tx: Tx, spent: Vec<Output>) -> Result<Pst, PstError> {
+        if tx.inputs.len() != spent.len() {
+            return Err(PstError::Mismatch);
+        }
+        tx.inputs.iter_mut().for_each(|input| input.witness.clear());
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        let inputs = spent.into_iter()
+            .map(|spent| PstInput { spent, derivation_hints: vec![], signatures: vec![], final_witness: None }).collect();
+        Ok(Pst { tx, inputs })
+    }
+
+    // None when the outputs spend more than the inputs provide, or the amounts of a deserialized pst overflow
+    pub fn fee(&self) -> Option<u64> { self.tx.fee_from(self.inputs.iter().map(|input| input.spent.amount)) }
+
+    pub fn add_hint(&mut self, input: usize, key: [u8;32], hint: &str) {
+        let hints = &mut self.inputs[input].derivation_hints;
+        if !hints.iter().any(|(known, _)| *known == key) {
+            hints.push((key, hint.to_string()));
+        }
+    }
+
+    // signs every input the wallet holds a key for, returning how many it signed
+    pub fn sign(&mut self, wallet: &Wallet) -> usize {
+        let key = wallet.address();
+        let signature = wallet.sign(&self.tx);
+        let mut signed = 0;
+        for input in self.inputs.iter_mut().filter(|input| can_sign(&input.spent.script_pubkey, &key)) {
+            if !input.signatures.iter().any(|(signer, _)| *signer == key) {
+                input.signatures.push((key, signature.clone()));
+            }
+            signed += 1;
+        }
+        signed
+    }
+
+    // merges the hints and signatures another signer added to a copy of the same tx
+    pub fn combine(&mut self, other: &Pst) -> Result<(), PstError> {
+        if self.tx.sighash() != other.tx.sighash() || self.inputs.len() != other.inputs.len() {
+            return Err(PstError::Mismatch);
+        }
+        for (index, theirs) in other.inputs.iter().enumerate() {
+            theirs.derivation_hints.iter().for_each(|(key, hint)| self.add_hint(index, *key, hint));
+            let ours = &mut self.inputs[index];
+            theirs.signatures.iter().for_each(|(key, signature)| {
+                if !ours.signatures.iter().any(|(signer, _)| signer == key) {
+                    ours.signatures.push((*key, signature.clone()));
+                }
+            });
+            if ours.final_witness.is_none() {
+                ours.final_witness = theirs.final_witness.clone();
+            }
+        }
+        Ok(())
+    }
+
+    // builds the witness of every input from the collected signatures, fails if any input is still short of them
+    pub fn finalize(&mut self) -> Result<(), PstError> {
+        let sighash = self.tx.sighash();
+        let witnesses = self.inputs.iter().map(|input| match &input.final_witness {
+            Some(witness) => Ok(witness.clone()),
+            None => build_witness(input, &sighash),
+        }).collect::<Result<Vec<_>, _>>()?;
+        self.inputs.iter_mut().zip(witnesses).for_each(|(input, witness)| input.final_witness = Some(witness));
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool { self.inputs.iter().all(|input| input.final_witness.is_some()) }
+
+    // the signed tx, checked against the scripts it spends before it's handed out
+    pub fn extract(&self) -> Result<Tx, PstError> {
+        let mut tx = self.tx.clone();
+        for (input, pst_input) in tx.inputs.iter_mut().zip(&self.inputs) {
+            input.witness = pst_input.final_witness.clone().ok_or(PstError::NotFinalized)?;
+        }
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        for (index, pst_input) in self.inputs.iter().enumerate() {
+            script::verify(&tx.inputs[index].witness, &pst_input.spent.script_pubkey, &tx, index).map_err(PstError::InvalidScript)?;
+        }
+        Ok(tx)
+    }
+
+    // magic bytes and the length prefixed unsigned tx, then the metadata of each input in input order
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = PST_MAGIC.to_vec();
+        let tx = self.tx.serialize();
+        bytes.extend_from_slice(&(tx.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&tx);
+        self.inputs.iter().for_each(|input| {
+            bytes.extend_from_slice(&input.spent.amount.to_be_bytes());
+            push_vec(&mut bytes, &input.spent.script_pubkey);
+            bytes.extend_from_slice(&(input.derivation_hints.len() as u16).to_be_bytes());
+            input.derivation_hints.iter().for_each(|(key, hint)| {
+                bytes.extend_from_slice(key);
+                push_vec(&mut bytes, hint.as_bytes());
+            });
+            bytes.extend_from_slice(&(input.signatures.len() as u16).to_be_bytes());
+            input.signatures.iter().for_each(|(key, signature)| {
+                bytes.extend_from_slice(key);
+                push_vec(&mut bytes, signature);
+            });
+            match &input.final_witness {
+                Some(witness) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(&(witness.len() as u16).to_be_bytes());
+                    witness.iter().for_each(|item| push_vec(&mut bytes, item));
+                }
+                None => bytes.push(0),
+            }
+        });
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Pst, PstError> {
+        let mut reader = bytes;
+        if take::<4>(&mut reader)? != PST_MAGIC {
+            return Err(PstError::Malformed);
+        }
+        let tx_len = u32::from_be_bytes(take(&mut reader)?) as usize;
+        if reader.len() < tx_len {
+            return Err(PstError::Malformed);
+        }
+        let (tx_bytes, rest) = reader.split_at(tx_len);
+        reader = rest;
+        let tx = Tx::deserialize(tx_bytes)?;
+        let mut inputs = vec![];
+        for _ in 0..tx.inputs.len() {
+            let spent = Output { amount: u64::from_be_bytes(take(&mut reader)?), script_pubkey: take_vec(&mut reader)? };
+            let mut derivation_hints = vec![];
+            for _ in 0..u16::from_be_bytes(take(&mut reader)?) {
+                let key = take(&mut reader)?;
+                let hint = String::from_utf8(take_vec(&mut reader)?).map_err(|_| PstError::Malformed)?;
+                derivation_hints.push((key, hint));
+            }
+            let mut signatures = vec![];
+            for _ in 0..u16::from_be_bytes(take(&mut reader)?) {
+                signatures.push((take(&mut reader)?, take_vec(&mut reader)?));
+            }
+            let final_witness = match take::<1>(&mut reader)? {
+                [0] => None,
+                [1] => {
+                    let mut witness = vec![];
+                    for _ in 0..u16::from_be_bytes(take(&mut reader)?) {
+                        witness.push(take_vec(&mut reader)?);
+                    }
+                    Some(witness)
+                }
+                _ => return Err(PstError::Malformed),
+            };
+            inputs.push(PstInput { spent, derivation_hints, signatures, final_witness });
+        }
+        if !reader.is_empty() || tx.inputs.iter().any(|input| !input.witness.is_empty()) {
+            return Err(PstError::Malformed);
+        }
+        Ok(Pst { tx, inputs })
+    }
+}
+
+// whether a signature from the key counts towards spending the script
+pub fn can_sign(script_pubkey: &[u8], key: &[u8;32]) -> bool {
+    script::pubkey_from_p2pk(script_pubkey) == Some(*key)
+        || script_pubkey == script::pay_to_pubkey_hash(&script::hash(key))
+        || script::parse_multisig(script_pubkey).is_some_and(|(_, keys)| keys.contains(key))
+}
+
+fn build_witness(input: &PstInput, sighash: &[u8;32]) -> Result<Vec<Vec<u8>>, PstError> {
+    let script_pubkey = &input.spent.script_pubkey;
+    // bad signatures are left out rather than failing the whole input
+    let valid: Vec<&([u8;32], Vec<u8>)> = input.signatures.iter()
+        .filter(|(key, signature)| can_sign(script_pubkey, key) && script::check_signature(signature, key, sighash)).collect();
+    if let Some((required, keys)) = script::parse_multisig(script_pubkey) {
+        // multisig wants its signatures in the order of the keys
+        let witness: Vec<Vec<u8>> = keys.iter()
+            .filter_map(|key| valid.iter().find(|(signer, _)| signer == key).map(|(_, signature)| signature.clone()))
+            .take(required).collect();
+        return if witness.len() == required { Ok(witness) } else { Err(PstError::MissingSignatures) };
+    }
+    let signed = valid.first().ok_or(PstError::MissingSignatures);
+    if script::pubkey_from_p2pk(script_pubkey).is_some() {
+        Ok(vec![signed?.1.clone()])
+    } else if script::is_pay_to_pubkey_hash(script_pubkey) {
+        let (key, signature) = signed?;
+        Ok(vec![signature.clone(), key.to_vec()])
+    } else {
+        Err(PstError::UnsupportedScript)
+    }
+}
+
+fn push_vec(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+#[derive(Debug)]
+pub enum PstError {
+    Malformed,
+    // the psts being combined, or the tx and its spent outputs, don't belong together
+    Mismatch,
+    MissingSignatures,
+    UnsupportedScript,
+    NotFinalized,
+    InvalidScript(ScriptError),
+}
+
+impl From<TxError> for PstError {
+    fn from(_: TxError) -> Self { PstError::Malformed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{Input, SEQUENCE_FINAL};
+
+    fn pst(spent: &[u64], outputs: &[u64]) -> Pst {
+        let inputs = (0..spent.len()).map(|vout| Input { txid: [1; 32], vout: vout as u32, witness: vec![], sequence: SEQUENCE_FINAL }).collect();
+        let outputs = outputs.iter().map(|amount| Output::to_address(*amount, [2; 32])).collect();
+        let tx = Tx { txid: [0; 32], inputs, outputs, lock_time: 0 };
+        Pst::new(tx, spent.iter().map(|amount| Output::to_address(*amount, [3; 32])).collect()).unwrap()
+    }
+
+    #[test]
+    fn fee_is_none_when_amounts_overflow_or_outputs_spend_more() {
+        assert_eq!(pst(&[600, 500], &[1000]).fee(), Some(100));
+        assert_eq!(pst(&[500], &[1000]).fee(), None);
+        assert_eq!(pst(&[u64::MAX, 1], &[1000]).fee(), None);
+        assert_eq!(pst(&[u64::MAX], &[u64::MAX, 1]).fee(), None);
+        // a round trip doesn't change what the fee is
+        assert_eq!(Pst::deserialize(&pst(&[u64::MAX, 1], &[1]).serialize()).unwrap().fee(), None);
+    }
+}
@@ -0,0 +1,738 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::address;
+use crate::block::{Block, BlockError};
+use crate::blockchain::Blockchain;
+use crate::global_utxos::Utxo;
+use crate::htlc::Htlc;
+use crate::json::BlockJson;
+use crate::index::AddressEvent;
+use crate::input::{Input, SEQUENCE_FINAL};
+use crate::mempool::MempoolError;
+use crate::node::{self, Node};
+use crate::pst::{Pst, PstError};
+use crate::script;
+use crate::snapshot::UtxoSnapshot;
+use crate::transactions::{Tx, TxError};
+use crate::util::{from_hex, to_base64, to_hex};
+
+pub const DEFAULT_MINING_FEE: u64 = 10;
+// events returned by the history calls when no count is given
+pub const DEFAULT_PAGE_SIZE: usize = 10;
+const MAX_REQUEST_BYTES: usize = 1 << 20;
+
+// standard json-rpc 2.0 error codes, followed by application specific ones
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const NOT_FOUND: i64 = -5;
+const WALLET_ERROR: i64 = -6;
+const INVALID_ADDRESS: i64 = -5;
+const VERIFY_ERROR: i64 = -25;
+const MISC_ERROR: i64 = -1;
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> RpcError { RpcError { code, message: message.to_string() } }
+}
+
+// the user of the cookie file, its password is random and changes every time the node starts
+pub const COOKIE_USER: &str = "__cookie__";
+
+// writes a fresh cookie only the user running the node can read, clients prove they can read it too
+pub fn write_cookie(path: &str) -> io::Result<String> {
+    let cookie = format!("{}:{}", COOKIE_USER, to_hex(&rand::random::<[u8;32]>()));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(cookie.as_bytes())?;
+    Ok(cookie)
+}
+
+// the Authorization header of a client holding the cookie
+pub fn authorization(cookie: &str) -> String { format!("Basic {}", to_base64(cookie.as_bytes())) }
+
+// serves json-rpc over http on localhost only, one thread per connection
+// takes a bound listener like the explorer, requests have to authenticate with the cookie
+pub fn serve(node: Arc<Mutex<Node>>, listener: TcpListener, cookie: &str) {
+    let authorization = Arc::new(authorization(cookie));
+    for stream in listener.incoming().flatten() {
+        let node = Arc::clone(&node);
+        let authorization = Arc::clone(&authorization);
+        thread::spawn(move || handle_connection(stream, &node, &authorization));
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, node: &Mutex<Node>, authorization: &str) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let is_post = line.starts_with("POST ");
+    let mut content_length = 0;
+    let (mut authorized, mut is_json, mut has_origin) = (false, false, false);
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorized = value == authorization;
+            } else if name.eq_ignore_ascii_case("content-type") {
+                is_json = value.split(';').next().is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case("application/json"));
+            } else if name.eq_ignore_ascii_case("origin") {
+                has_origin = true;
+            }
+        }
+    }
+    if !is_post {
+        return write_response(&mut stream, "405 Method Not Allowed", "");
+    }
+    if !authorized {
+        return write_response(&mut stream, "401 Unauthorized", "");
+    }
+    // browsers send an origin with every cross-site request and can't send json without asking first
+    if has_origin {
+        return write_response(&mut stream, "403 Forbidden", "");
+    }
+    if !is_json {
+        return write_response(&mut stream, "415 Unsupported Media Type", "");
+    }
+    if content_length > MAX_REQUEST_BYTES {
+        return write_response(&mut stream, "413 Payload Too Large", "");
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    match handle_request(&body, node) {
+        Some(response) => write_response(&mut stream, "200 OK", &response.to_string()),
+        // requests made only of notifications get no body back
+        None => write_response(&mut stream, "204 No Content", ""),
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()
+}
+
+pub fn handle_request(body: &[u8], node: &Mutex<Node>) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, "Parse error"))),
+    };
+    match request {
+        Value::Array(batch) if batch.is_empty() => Some(error_response(Value::Null, RpcError::new(INVALID_REQUEST, "Invalid Request"))),
+        Value::Array(batch) => {
+            let responses: Vec<Value> = batch.iter().filter_map(|request| handle_single(request, node)).collect();
+            if responses.is_empty() { None } else { Some(Value::Array(responses)) }
+        }
+        request => handle_single(&request, node),
+    }
+}
+
+fn handle_single(request: &Value, node: &Mutex<Node>) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(Value::as_str);
+    if request.get("jsonrpc").and_then(Value::as_str) != Some("2.0") || method.is_none() {
+        return Some(error_response(id.unwrap_or(Value::Null), RpcError::new(INVALID_REQUEST, "Invalid Request")));
+    }
+    let params = match request.get("params") {
+        None => &[][..],
+        Some(Value::Array(params)) => params.as_slice(),
+        Some(_) => return id.map(|id| error_response(id, RpcError::new(INVALID_PARAMS, "params must be an array"))),
+    };
+    let result = call(method.unwrap(), params, &mut node::lock(node));
+    // requests without an id are notifications, which are executed but never answered
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => error_response(id, error),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": { "code": error.code, "message": error.message }, "id": id })
+}
+
+pub fn call(method: &str, params: &[Value], node: &mut Node) -> Result<Value, RpcError> {
+    match method {
+        "getblockcount" => Ok(json!(node.chain.get_height())),
+        "getblockhash" => {
+            let height = u64_param(params, 0)?;
+            let block = node.chain.chain.get(height as usize).ok_or(RpcError::new(INVALID_PARAMS, "Block height out of range"))?;
+            Ok(json!(to_hex(&block.hash)))
+        }
+        "getblockchaininfo" => Ok(json!({
+            "chain": node.chain.params.name,
+            "blocks": node.chain.get_height(),
+            "bestblockhash": to_hex(&node.chain.get_current_hash()),
+            "mediantime": node.chain.median_time_past(),
+            "target": format!("{:016x}", node.chain.params.target),
+            "size": node.chain.chain.iter().map(|block| block.get_size() as u64).sum::<u64>(),
+            "supply": node.chain.params.total_supply_at(node.chain.get_height()),
+            "subsidy": node.chain.params.subsidy(node.chain.get_height() + 1),
+            "pruned": node.chain.params.prunes(),
+            "pruneheight": node.chain.pruned_height,
+            "snapshot": node.chain.snapshot.as_ref().map(|base| json!({
+                "height": base.height,
+                "hash": to_hex(&base.hash),
+                "validated": base.validated,
+            })),
+        })),
+        "getblock" => {
+            let block = match params.first() {
+                Some(Value::String(hash)) => node.chain.get_block_by_hash(&hash_param(hash)?),
+                Some(Value::Number(height)) => height.as_u64().and_then(|height| node.chain.chain.get(height as usize)),
+                _ => return Err(RpcError::new(INVALID_PARAMS, "expected a block hash or height")),
+            }.ok_or(RpcError::new(NOT_FOUND, "Block not found"))?;
+            if !block.has_body() {
+                return Err(RpcError::new(MISC_ERROR, "Block not available, only its header is kept"));
+            }
+            Ok(block_to_json(block, optional_bool(params, 1, false)?, &node.chain))
+        }
+        "getrawtransaction" => {
+            let txid = hash_param(string_param(params, 0)?)?;
+            let verbose = optional_bool(params, 1, false)?;
+            let (tx, height) = match node.chain.find_tx(&txid) {
+                Some((tx, height)) => (tx, Some(height)),
+                None => (node.pool.pool.iter().map(|(_, tx)| tx).find(|tx| tx.txid == txid)
+                    .ok_or(RpcError::new(NOT_FOUND, "No such transaction"))?, None),
+            };
+            if !verbose {
+                return Ok(json!(to_hex(&tx.serialize())));
+            }
+            let mut result = json!(tx.to_json(&node.chain));
+            result["blockheight"] = json!(height);
+            result["confirmations"] = json!(height.map(|height| node.chain.get_height() - height + 1).unwrap_or(0));
+            Ok(result)
+        }
+        "sendrawtransaction" => {
+            let bytes = from_hex(string_param(params, 0)?).ok_or(RpcError::new(INVALID_PARAMS, "invalid hex"))?;
+            let tx = Tx::deserialize(&bytes).map_err(|_| RpcError::new(VERIFY_ERROR, "TX decode failed"))?;
+            let txid = tx.txid;
+            node.submit_tx(tx).map_err(mempool_error)?;
+            Ok(json!(to_hex(&txid)))
+        }
+        // a block in the json form getblock returns with verbose set
+        "submitblock" => {
+            let json: BlockJson = serde_json::from_value(params.first().cloned().unwrap_or_default())
+                .map_err(|error| RpcError { code: INVALID_PARAMS, message: format!("Invalid block: {}", error) })?;
+            let block = Block::from_json(&json).map_err(|error| RpcError { code: VERIFY_ERROR, message: format!("Block decode failed: {:?}", error) })?;
+            let hash = block.hash;
+            node.submit_block(block).map_err(|error: BlockError| RpcError { code: VERIFY_ERROR, message: format!("Block rejected: {}", error) })?;
+            Ok(json!(to_hex(&hash)))
+        }
+        // the commitment is the same on every node with the same utxo set, however it got there
+        "gettxoutsetinfo" => Ok(json!({
+            "height": node.chain.get_height(),
+            "bestblock": to_hex(&node.chain.get_current_hash()),
+            "txouts": node.chain.utxo_commitment.coins,
+            "total_amount": node.chain.utxo_commitment.amount,
+            "commitment": to_hex(&node.chain.utxo_commitment.hash()),
+        })),
+        // writes the utxo set at the height to a file on the node's side, the tip if no height is given
+        "dumpsnapshot" => {
+            let path = string_param(params, 0)?;
+            let height = match params.get(1) {
+                Some(_) => u64_param(params, 1)?.min(u32::MAX as u64) as u32,
+                None => node.chain.get_height(),
+            };
+            let snapshot = UtxoSnapshot::from_chain(&node.chain, height)
+                .map_err(|_| RpcError::new(INVALID_PARAMS, "Snapshot height not available"))?;
+            snapshot.save(path).map_err(|error| RpcError { code: MISC_ERROR, message: format!("Could not write snapshot: {}", error) })?;
+            Ok(json!({
+                "height": snapshot.height(),
+                "blockhash": to_hex(&snapshot.block_hash()),
+                "hash": to_hex(&snapshot.hash()),
+                "coins": snapshot.coins.len(),
+                "path": path,
+            }))
+        }
+        "getmempoolinfo" => Ok(json!({
+            "size": node.pool.pool.len(),
+            "bytes": node.pool.get_size(),
+            "maxmempool": node.chain.params.max_mempool_size,
+        })),
+        "getrawmempool" => {
+            let verbose = optional_bool(params, 0, false)?;
+            // highest fee per byte first, the order a miner would take them in
+            let transactions: Vec<Value> = node.pool.pool.iter().rev().map(|(_, tx)| if verbose {
+                json!({ "txid": to_hex(&tx.txid), "size": tx.get_size(), "fee": tx.checked_mining_fee(&node.chain) })
+            } else {
+                json!(to_hex(&tx.txid))
+            }).collect();
+            Ok(json!(transactions))
+        }
+        "getbalance" => Ok(json!(node.get_balance())),
+        "getbalances" => {
+            let (spendable, immature) = node.get_balances();
+            Ok(json!({ "trusted": spendable, "immature": immature }))
+        }
+        "getnewaddress" => {
+            let address = node.new_address();
+            Ok(json!(address::encode(&node.chain.params.address_prefix, &address)))
+        }
+        "listaddresses" => {
+            let prefix = &node.chain.params.address_prefix;
+            Ok(json!(node.wallets.iter().map(|wallet| address::encode(prefix, &wallet.address())).collect::<Vec<String>>()))
+        }
+        // an explorer style address page, the balance comes from the utxo set and the history from the address index
+        "getaddresshistory" => {
+            let address = address_param(params, 0, &node.chain.params.address_prefix)?;
+            let (skip, count) = page_params(params, 1)?;
+            let script_pubkey = script::pay_to_pubkey(&address);
+            let (total, events) = node.chain.index.address_history(&script_pubkey, skip, count).ok_or_else(address_index_disabled)?;
+            let balance: u64 = node.utxos.get_utxos(&script_pubkey).map(|utxos| utxos.iter().map(|utxo| utxo.amount).sum()).unwrap_or(0);
+            Ok(json!({ "address": string_param(params, 0)?, "balance": balance, "total": total, "events": events_to_json(&events) }))
+        }
+        "listtransactions" => {
+            let (skip, count) = page_params(params, 0)?;
+            let (total, events) = node.get_history(skip, count).ok_or_else(address_index_disabled)?;
+            Ok(json!({ "total": total, "events": events_to_json(&events) }))
+        }
+        "sendtoaddress" => {
+            let address = address_param(params, 0, &node.chain.params.address_prefix)?;
+            let amount = u64_param(params, 1)?;
+            let fee = match params.get(2) {
+                Some(_) => u64_param(params, 2)?,
+                None => DEFAULT_MINING_FEE,
+            };
+            let tx = node.send_to_address(address, amount, fee).map_err(wallet_error)?;
+            let txid = tx.txid;
+            node.submit_tx(tx).map_err(mempool_error)?;
+            Ok(json!(to_hex(&txid)))
+        }
+        // the script locking coins to required of the addresses, signatures have to come in the order the addresses are given
+        "createmultisig" => {
+            let required = u64_param(params, 0)?;
+            let addresses = params.get(1).and_then(Value::as_array).ok_or(RpcError::new(INVALID_PARAMS, "expected an array of addresses"))?;
+            let keys = (0..addresses.len()).map(|index| address_param(addresses, index, &node.chain.params.address_prefix)).collect::<Result<Vec<[u8;32]>, RpcError>>()?;
+            if required == 0 || required > keys.len() as u64 || keys.len() > script::MAX_MULTISIG_KEYS {
+                return Err(RpcError { code: INVALID_PARAMS, message: format!("required must be between 1 and the number of addresses, at most {}", script::MAX_MULTISIG_KEYS) });
+            }
+            Ok(json!({ "script": to_hex(&script::multisig(required as u32, &keys)), "required": required, "keys": keys.len() }))
+        }
+        "sendtomultisig" => {
+            let script_pubkey = multisig_param(params, 0)?;
+            let amount = u64_param(params, 1)?;
+            let fee = match params.get(2) {
+                Some(_) => u64_param(params, 2)?,
+                None => DEFAULT_MINING_FEE,
+            };
+            let tx = node.send_to_script(script_pubkey, amount, fee).map_err(wallet_error)?;
+            let txid = tx.txid;
+            node.submit_tx(tx).map_err(mempool_error)?;
+            Ok(json!(to_hex(&txid)))
+        }
+        // spends coins locked to the script, the pst goes around the co-signers with signpst before finalizepst
+        "createmultisigpst" => {
+            let script_pubkey = multisig_param(params, 0)?;
+            let address = address_param(params, 1, &node.chain.params.address_prefix)?;
+            let amount = u64_param(params, 2)?;
+            let fee = match params.get(3) {
+                Some(_) => u64_param(params, 3)?,
+                None => DEFAULT_MINING_FEE,
+            };
+            let pst = node.create_multisig_pst(&script_pubkey, address, amount, fee).map_err(wallet_error)?;
+            Ok(json!(to_hex(&pst.serialize())))
+        }
+        // locks coins the address can claim with the preimage of the hash, the paying wallet can take them back after the timeout
+        "createhtlc" => {
+            let recipient = address_param(params, 0, &node.chain.params.address_prefix)?;
+            let preimage_hash = hash_param(string_param(params, 1)?)?;
+            let timeout = u32::try_from(u64_param(params, 2)?).map_err(|_| RpcError::new(INVALID_PARAMS, "timeout out of range"))?;
+            let amount = u64_param(params, 3)?;
+            let fee = match params.get(4) {
+                Some(_) => u64_param(params, 4)?,
+                None => DEFAULT_MINING_FEE,
+            };
+            let (htlc, tx) = node.create_htlc(recipient, preimage_hash, timeout, amount, fee).map_err(wallet_error)?;
+            let txid = tx.txid;
+            let script_pubkey = htlc.script_pubkey();
+            let vout = tx.outputs.iter().position(|output| output.script_pubkey == script_pubkey).unwrap_or_default();
+            node.submit_tx(tx).map_err(mempool_error)?;
+            Ok(json!({ "txid": to_hex(&txid), "vout": vout, "script": to_hex(&script_pubkey) }))
+        }
+        "claimhtlc" => {
+            let (htlc, utxo) = htlc_param(params, 0, node)?;
+            let preimage = from_hex(string_param(params, 2)?).ok_or(RpcError::new(INVALID_PARAMS, "expected the preimage as hex"))?;
+            let fee = match params.get(3) {
+                Some(_) => u64_param(params, 3)?,
+                None => DEFAULT_MINING_FEE,
+            };
+            let tx = node.claim_htlc(&htlc, &utxo, &preimage, fee).map_err(wallet_error)?;
+            let txid = tx.txid;
+            node.submit_tx(tx).map_err(mempool_error)?;
+            Ok(json!(to_hex(&txid)))
+        }
+        // the refund is only accepted once the timeout has passed
+        "refundhtlc" => {
+            let (htlc, utxo) = htlc_param(params, 0, node)?;
+            let fee = match params.get(2) {
+                Some(_) => u64_param(params, 2)?,
+                None => DEFAULT_MINING_FEE,
+            };
+            let tx = node.refund_htlc(&htlc, &utxo, fee).map_err(wallet_error)?;
+            let txid = tx.txid;
+            node.submit_tx(tx).map_err(mempool_error)?;
+            Ok(json!(to_hex(&txid)))
+        }
+        "createpst" => {
+            let address = address_param(params, 0, &node.chain.params.address_prefix)?;
+            let amount = u64_param(params, 1)?;
+            let fee = match params.get(2) {
+                Some(_) => u64_param(params, 2)?,
+                None => DEFAULT_MINING_FEE,
+            };
+            let (lock_time, sequence) = lock_params(params, 3)?;
+            let pst = node.create_pst(address, amount, fee, lock_time, sequence).map_err(wallet_error)?;
+            Ok(json!(to_hex(&pst.serialize())))
+        }
+        "decodepst" => Ok(pst_to_json(&pst_param(params, 0)?, &node.chain)),
+        "signpst" => {
+            let mut pst = pst_param(params, 0)?;
+            let signed = node.sign_pst(&mut pst);
+            Ok(json!({ "pst": to_hex(&pst.serialize()), "signed": signed }))
+        }
+        "combinepst" => {
+            let psts = params.first().and_then(Value::as_array).ok_or(RpcError::new(INVALID_PARAMS, "expected an array of psts"))?;
+            let mut combined = pst_param(psts, 0)?;
+            for index in 1..psts.len() {
+                combined.combine(&pst_param(psts, index)?).map_err(pst_error)?;
+            }
+            Ok(json!(to_hex(&combined.serialize())))
+        }
+        // returns the signed tx once every input has enough signatures, otherwise the pst as far as it got
+        "finalizepst" => {
+            let mut pst = pst_param(params, 0)?;
+            match pst.finalize() {
+                Ok(()) => {
+                    let tx = pst.extract().map_err(pst_error)?;
+                    Ok(json!({ "hex": to_hex(&tx.serialize()), "complete": true }))
+                }
+                Err(PstError::MissingSignatures) => Ok(json!({ "pst": to_hex(&pst.serialize()), "complete": false })),
+                Err(error) => Err(pst_error(error)),
+            }
+        }
+        "generate" => {
+            let blocks = u64_param(params, 0)?;
+            let address = match params.get(1) {
+                Some(Value::Null) | None => node.wallets[0].address(),
+                Some(_) => address_param(params, 1, &node.chain.params.address_prefix)?,
+            };
+            let threads = match params.get(2) {
+                Some(_) => u64_param(params, 2)?.clamp(1, u8::MAX as u64) as u8,
+                None => 1,
+            };
+            let hashes = (0..blocks).map(|_| node.mine_block(address, threads).map(|hash| to_hex(&hash)))
+                .collect::<Result<Vec<String>, BlockError>>()
+                .map_err(|error| RpcError { code: MISC_ERROR, message: format!("Mined an invalid block: {}", error) })?;
+            Ok(json!(hashes))
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+fn mempool_error(error: MempoolError) -> RpcError {
+    match error {
+        MempoolError::InvalidTransaction => RpcError::new(VERIFY_ERROR, "Invalid transaction"),
+        MempoolError::MissingInputs => RpcError::new(VERIFY_ERROR, "Missing inputs"),
+        MempoolError::ImmatureCoinbase => RpcError::new(VERIFY_ERROR, "Spends immature coinbase"),
+        MempoolError::TimeLocked => RpcError::new(VERIFY_ERROR, "Non-final transaction"),
+        MempoolError::InvalidScript => RpcError::new(VERIFY_ERROR, "Script verification failed"),
+        MempoolError::FeeTooLow => RpcError::new(VERIFY_ERROR, "Mempool full and fee too low"),
+        MempoolError::Conflict => RpcError::new(VERIFY_ERROR, "Conflicts with a mempool transaction"),
+    }
+}
+
+fn wallet_error(error: TxError) -> RpcError {
+    match error {
+        TxError::InsufficientBalance => RpcError::new(WALLET_ERROR, "Insufficient funds"),
+        TxError::Malformed => RpcError::new(WALLET_ERROR, "Could not build transaction"),
+        TxError::NotASigner => RpcError::new(WALLET_ERROR, "No wallet holds the key to spend this output"),
+        TxError::BadPreimage => RpcError::new(INVALID_PARAMS, "Preimage doesn't match the hash"),
+    }
+}
+
+fn pst_error(error: PstError) -> RpcError {
+    match error {
+        PstError::Malformed => RpcError::new(INVALID_PARAMS, "PST decode failed"),
+        PstError::Mismatch => RpcError::new(INVALID_PARAMS, "PSTs are not for the same transaction"),
+        PstError::MissingSignatures => RpcError::new(VERIFY_ERROR, "Missing signatures"),
+        PstError::UnsupportedScript => RpcError::new(VERIFY_ERROR, "Unsupported script"),
+        PstError::NotFinalized => RpcError::new(VERIFY_ERROR, "PST is not finalized"),
+        PstError::InvalidScript(error) => RpcError { code: VERIFY_ERROR, message: format!("Script verification failed: {:?}", error) },
+    }
+}
+
+fn address_index_disabled() -> RpcError { RpcError::new(MISC_ERROR, "Address index is disabled, set address_index in the config") }
+
+// how many events to skip and how many to return, starting at params[index]
+fn page_params(params: &[Value], index: usize) -> Result<(usize, usize), RpcError> {
+    let skip = match params.get(index) {
+        Some(_) => u64_param(params, index)? as usize,
+        None => 0,
+    };
+    let count = match params.get(index + 1) {
+        Some(_) => u64_param(params, index + 1)? as usize,
+        None => DEFAULT_PAGE_SIZE,
+    };
+    Ok((skip, count))
+}
+
+fn events_to_json(events: &[AddressEvent]) -> Value {
+    json!(events.iter().map(|event| json!({ "txid": to_hex(&event.txid), "height": event.height, "delta": event.delta })).collect::<Vec<Value>>())
+}
+
+fn pst_param(params: &[Value], index: usize) -> Result<Pst, RpcError> {
+    let bytes = from_hex(string_param(params, index)?).ok_or(RpcError::new(INVALID_PARAMS, "invalid hex"))?;
+    Pst::deserialize(&bytes).map_err(pst_error)
+}
+
+// an optional object of a locktime, a height or unix time, and a relative lock of either after_blocks or after_seconds
+// returns the lock time and the sequence of every input, 0 and SEQUENCE_FINAL when there are no locks
+fn lock_params(params: &[Value], index: usize) -> Result<(u32, u32), RpcError> {
+    let locks = match params.get(index) {
+        None => return Ok((0, SEQUENCE_FINAL)),
+        Some(locks) if locks.is_object() => locks,
+        Some(_) => return Err(RpcError::new(INVALID_PARAMS, "expected an object of locks")),
+    };
+    let number = |name: &str, max: u64| locks[name].as_u64().filter(|value| *value <= max)
+        .ok_or(RpcError { code: INVALID_PARAMS, message: format!("{} must be an integer of at most {}", name, max) });
+    let lock_time = match locks.get("locktime") {
+        Some(_) => number("locktime", u32::MAX as u64)? as u32,
+        None => 0,
+    };
+    let sequence = match (locks.get("after_blocks"), locks.get("after_seconds")) {
+        (Some(_), Some(_)) => return Err(RpcError::new(INVALID_PARAMS, "after_blocks and after_seconds can't be combined")),
+        (Some(_), None) => Input::relative_height_lock(number("after_blocks", u16::MAX as u64)? as u16),
+        (None, Some(_)) => Input::relative_time_lock(number("after_seconds", u32::MAX as u64)? as u32),
+        (None, None) => SEQUENCE_FINAL,
+    };
+    Ok((lock_time, sequence))
+}
+
+// a script built by createmultisig, as hex
+fn multisig_param(params: &[Value], index: usize) -> Result<Vec<u8>, RpcError> {
+    from_hex(string_param(params, index)?).filter(|script| script::parse_multisig(script).is_some())
+        .ok_or(RpcError::new(INVALID_PARAMS, "expected a multisig script as hex"))
+}
+
+// an unspent htlc output given as txid and vout
+fn htlc_param(params: &[Value], index: usize, node: &Node) -> Result<(Htlc, Utxo), RpcError> {
+    let txid = hash_param(string_param(params, index)?)?;
+    let vout = u32::try_from(u64_param(params, index + 1)?).map_err(|_| RpcError::new(INVALID_PARAMS, "vout out of range"))?;
+    node.find_htlc(&txid, vout).ok_or(RpcError::new(NOT_FOUND, "No unspent htlc output"))
+}
+
+fn string_param(params: &[Value], index: usize) -> Result<&str, RpcError> {
+    params.get(index).and_then(Value::as_str).ok_or(RpcError::new(INVALID_PARAMS, "expected a string parameter"))
+}
+
+fn u64_param(params: &[Value], index: usize) -> Result<u64, RpcError> {
+    params.get(index).and_then(Value::as_u64).ok_or(RpcError::new(INVALID_PARAMS, "expected an integer parameter"))
+}
+
+fn optional_bool(params: &[Value], index: usize, default: bool) -> Result<bool, RpcError> {
+    match params.get(index) {
+        None => Ok(default),
+        Some(value) => value.as_bool().ok_or(RpcError::new(INVALID_PARAMS, "expected a boolean parameter")),
+    }
+}
+
+fn address_param(params: &[Value], index: usize, prefix: &str) -> Result<[u8;32], RpcError> {
+    address::decode(prefix, string_param(params, index)?)
+        .map_err(|error| RpcError { code: INVALID_ADDRESS, message: format!("Invalid address: {}", error) })
+}
+
+fn hash_param(hex: &str) -> Result<[u8;32], RpcError> {
+    from_hex(hex).and_then(|bytes| bytes.try_into().ok()).ok_or(RpcError::new(INVALID_PARAMS, "expected a 32 byte hex string"))
+}
+
+// without verbose the txs of the block are only listed by txid
+pub fn block_to_json(block: &Block, verbose: bool, chain: &Blockchain) -> Value {
+    if !verbose {
+        let mut result = json!(BlockJson { tx: vec![], ..block.to_json(chain) });
+        result["tx"] = json!(block.transactions.iter().map(|tx| to_hex(&tx.txid)).collect::<Vec<String>>());
+        return result;
+    }
+    json!(block.to_json(chain))
+}
+
+pub fn pst_to_json(pst: &Pst, chain: &Blockchain) -> Value {
+    let prefix = &chain.params.address_prefix;
+    let inputs: Vec<Value> = pst.inputs.iter().map(|input| {
+        let hints: Vec<Value> = input.derivation_hints.iter()
+            .map(|(key, hint)| json!({ "address": address::encode(prefix, key), "hint": hint })).collect();
+        let signers: Vec<String> = input.signatures.iter().map(|(key, _)| address::encode(prefix, key)).collect();
+        json!({
+            "amount": input.spent.amount,
+            "script": to_hex(&input.spent.script_pubkey),
+            "asm": script::to_asm(&input.spent.script_pubkey),
+            "hints": hints,
+            "signers": signers,
+            "final": input.final_witness.is_some(),
+        })
+    }).collect();
+    json!({ "tx": pst.tx.to_json(chain), "inputs": inputs, "fee": pst.fee(), "complete": pst.is_complete() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{funded_node, params};
+    use std::thread;
+    use crate::chain_params::ChainParams;
+    use crate::wallet::Wallet;
+
+    fn request(method: &str, params: Value) -> Vec<u8> {
+        json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }).to_string().into_bytes()
+    }
+
+    // the status line of a post with the headers to the server
+    fn post(port: u16, headers: &str, body: &[u8]) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(stream, "POST / HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n", headers, body.len()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn only_serves_json_requests_authenticated_with_the_cookie() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let cookie = format!("{}:secret", COOKIE_USER);
+        let node = Arc::new(Mutex::new(Node::new(ChainParams::regtest())));
+        let server_cookie = cookie.clone();
+        thread::spawn(move || serve(node, listener, &server_cookie));
+        let body = request("getblockcount", json!([]));
+        let json = "Content-Type: application/json\r\n";
+        let auth = format!("Authorization: {}\r\n", authorization(&cookie));
+        assert_eq!(post(port, json, &body), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(post(port, &format!("{}Authorization: {}\r\n", json, authorization("__cookie__:guess")), &body), "HTTP/1.1 401 Unauthorized");
+        // what a web page can send without the browser asking the server first
+        assert_eq!(post(port, &format!("{}Content-Type: text/plain\r\n", auth), &body), "HTTP/1.1 415 Unsupported Media Type");
+        assert_eq!(post(port, &format!("{}{}Origin: http://example.com\r\n", auth, json), &body), "HTTP/1.1 403 Forbidden");
+        assert_eq!(post(port, &format!("{}Content-Type: application/json; charset=utf-8\r\n", auth), &body), "HTTP/1.1 200 OK");
+    }
+
+    #[test]
+    fn answers_amounts_overflowing_with_the_fee_with_an_error() {
+        let mut node = Node::new(params());
+        call("generate", &[json!(1)], &mut node).unwrap();
+        let address = json!(address::encode(&node.chain.params.address_prefix, &Wallet::new().address()));
+        let hash = json!(to_hex(&[0; 32]));
+        for (method, params) in [
+            ("sendtoaddress", vec![address.clone(), json!(u64::MAX), json!(1)]),
+            ("createpst", vec![address.clone(), json!(u64::MAX), json!(1)]),
+            ("createhtlc", vec![address, hash, json!(100), json!(u64::MAX), json!(1)]),
+        ] {
+            assert_eq!(call(method, &params, &mut node).unwrap_err().code, WALLET_ERROR);
+        }
+    }
+
+    #[test]
+    fn rejects_a_tx_spending_more_than_its_inputs() {
+        let (mut node, address) = funded_node();
+        let mut tx = node.send_to_address(address, 1000, DEFAULT_MINING_FEE).unwrap();
+        // the fee and then some go to the output, signed again so only the amounts are wrong
+        tx.outputs[0].amount += 2 * DEFAULT_MINING_FEE;
+        let signature = node.wallets[0].sign(&tx);
+        tx.inputs.iter_mut().for_each(|input| input.witness = vec![signature.clone()]);
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        let node = Mutex::new(node);
+        let response = handle_request(&request("sendrawtransaction", json!([to_hex(&tx.serialize())])), &node).unwrap();
+        assert_eq!(response["error"]["code"], json!(VERIFY_ERROR));
+        let response = handle_request(&request("getmempoolinfo", json!([])), &node).unwrap();
+        assert_eq!(response["result"]["size"], json!(0));
+    }
+
+    #[test]
+    fn spends_a_2_of_3_multisig_signed_with_the_first_and_last_key() {
+        let mut node = Node::new(params());
+        let prefix = node.chain.params.address_prefix.clone();
+        call("generate", &[json!(1)], &mut node).unwrap();
+        // the node holds the first and last key, the middle one never signs
+        let first = call("getnewaddress", &[], &mut node).unwrap();
+        let last = call("getnewaddress", &[], &mut node).unwrap();
+        let middle = json!(address::encode(&prefix, &Wallet::new().address()));
+        let multisig = call("createmultisig", &[json!(2), json!([first, middle, last])], &mut node).unwrap();
+        let script_hex = multisig["script"].clone();
+        call("sendtomultisig", &[script_hex.clone(), json!(5000)], &mut node).unwrap();
+        call("generate", &[json!(1)], &mut node).unwrap();
+        let payee = Wallet::new().address();
+        let pst = call("createmultisigpst", &[script_hex.clone(), json!(address::encode(&prefix, &payee)), json!(3000)], &mut node).unwrap();
+        let signed = call("signpst", &[pst], &mut node).unwrap();
+        assert_eq!(signed["signed"], json!(2));
+        let finalized = call("finalizepst", &[signed["pst"].clone()], &mut node).unwrap();
+        assert_eq!(finalized["complete"], json!(true));
+        call("sendrawtransaction", &[finalized["hex"].clone()], &mut node).unwrap();
+        call("generate", &[json!(1)], &mut node).unwrap();
+        assert!(node.pool.pool.is_empty());
+        let paid: Vec<u64> = node.utxos.get_utxos(&script::pay_to_pubkey(&payee)).into_iter().flatten().map(|utxo| utxo.amount).collect();
+        assert_eq!(paid, vec![3000]);
+        let script_pubkey = from_hex(script_hex.as_str().unwrap()).unwrap();
+        let change: Vec<u64> = node.utxos.get_utxos(&script_pubkey).into_iter().flatten().map(|utxo| utxo.amount).collect();
+        assert_eq!(change, vec![2000 - DEFAULT_MINING_FEE]);
+    }
+
+    #[test]
+    fn refuses_a_multisig_needing_more_signatures_than_keys() {
+        let mut node = Node::new(ChainParams::regtest());
+        let address = call("getnewaddress", &[], &mut node).unwrap();
+        assert_eq!(call("createmultisig", &[json!(2), json!([address])], &mut node).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(call("createmultisig", &[json!(0), json!([address])], &mut node).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(call("sendtomultisig", &[json!(to_hex(&script::pay_to_pubkey(&[1; 32]))), json!(1000)], &mut node).unwrap_err().code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn creates_psts_locked_until_a_height_or_for_some_blocks() {
+        let mut node = Node::new(params());
+        call("generate", &[json!(1)], &mut node).unwrap();
+        let address = json!(address::encode(&node.chain.params.address_prefix, &Wallet::new().address()));
+        let pst = call("createpst", &[address.clone(), json!(1000), json!(10), json!({ "locktime": 5, "after_blocks": 3 })], &mut node).unwrap();
+        let pst = pst_param(&[pst], 0).unwrap();
+        assert_eq!(pst.tx.lock_time, 5);
+        assert!(pst.tx.inputs.iter().all(|input| input.sequence == Input::relative_height_lock(3)));
+        let both = json!({ "after_blocks": 3, "after_seconds": 512 });
+        assert_eq!(call("createpst", &[address.clone(), json!(1000), json!(10), both], &mut node).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(call("createpst", &[address, json!(1000), json!(10), json!({ "after_blocks": 70000 })], &mut node).unwrap_err().code, INVALID_PARAMS);
+    }
+
+    #[test]
+    fn claims_an_htlc_with_its_preimage_only() {
+        let mut node = Node::new(params());
+        call("generate", &[json!(1)], &mut node).unwrap();
+        let recipient = call("getnewaddress", &[], &mut node).unwrap();
+        let hash = json!(to_hex(&script::hash(b"secret")));
+        let htlc = call("createhtlc", &[recipient, hash, json!(100), json!(5000)], &mut node).unwrap();
+        call("generate", &[json!(1)], &mut node).unwrap();
+        let (txid, vout) = (htlc["txid"].clone(), htlc["vout"].clone());
+        assert_eq!(call("claimhtlc", &[txid.clone(), vout.clone(), json!(to_hex(b"guess"))], &mut node).unwrap_err().code, INVALID_PARAMS);
+        // the timeout hasn't passed yet
+        assert!(call("refundhtlc", &[txid.clone(), vout.clone()], &mut node).is_err());
+        call("claimhtlc", &[txid.clone(), vout.clone(), json!(to_hex(b"secret"))], &mut node).unwrap();
+        call("generate", &[json!(1)], &mut node).unwrap();
+        assert_eq!(call("claimhtlc", &[txid, vout, json!(to_hex(b"secret"))], &mut node).unwrap_err().code, NOT_FOUND);
+        let claimed: u64 = node.utxos.get_utxos(&node.wallets[1].script_pubkey()).into_iter().flatten().map(|utxo| utxo.amount).sum();
+        assert_eq!(claimed, 5000 - DEFAULT_MINING_FEE);
+    }
+}
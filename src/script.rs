@@ -0,0 +1,579 @@
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::input::SEQUENCE_FINAL;
+use crate::transactions::{Tx, LOCKTIME_THRESHOLD};
+
+// opcodes 0x01 to 0x4b push that many of the following bytes
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+// OP_1 to OP_16 push the numbers 1 to 16
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+// replaces the top item with its blake3 hash
+pub const OP_HASH: u8 = 0xa8;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+
+// resource limits, so a single input can't make validation arbitrarily expensive
+pub const MAX_SCRIPT_SIZE: usize = 10000;
+pub const MAX_ELEMENT_SIZE: usize = 520;
+pub const MAX_STACK_SIZE: usize = 1000;
+// counts every opcode that isn't a push, plus every key a multisig checks
+pub const MAX_OPS: usize = 201;
+pub const MAX_MULTISIG_KEYS: usize = 20;
+// numbers are unsigned little endian, at most 4 bytes so any lock time fits
+pub const MAX_NUMBER_BYTES: usize = 4;
+
+// decides whether a well formed signature over the sighash is valid
+// the flag is false when the answer has to be the real one right away, a multisig with fewer signatures than keys
+// moves on to its next key depending on it, so assuming the signature valid would pair the rest with the wrong keys
+pub type SignatureChecker<'a> = dyn FnMut(&VerifyingKey, &Signature, &[u8;32], bool) -> bool + 'a;
+
+pub fn hash(data: &[u8]) -> [u8;32] { *blake3::hash(data).as_bytes() }
+
+// locks an output to a single public key, spent with a witness of just the signature
+pub fn pay_to_pubkey(key: &[u8;32]) -> Vec<u8> {
+    let mut script = vec![];
+    push_data(&mut script, key);
+    script.push(OP_CHECKSIG);
+    script
+}
+
+// locks an output to the hash of a public key, spent with a witness of the signature and the key
+pub fn pay_to_pubkey_hash(key_hash: &[u8;32]) -> Vec<u8> {
+    let mut script = vec![OP_DUP, OP_HASH];
+    push_data(&mut script, key_hash);
+    script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]);
+    script
+}
+
+// requires signatures from `required` of the keys, given in the same order as the keys
+pub fn multisig(required: u32, keys: &[[u8;32]]) -> Vec<u8> {
+    let mut script = vec![];
+    push_number(&mut script, required);
+    keys.iter().for_each(|key| push_data(&mut script, key));
+    push_number(&mut script, keys.len() as u32);
+    script.push(OP_CHECKMULTISIG);
+    script
+}
+
+// the key a pay to pubkey script is locked to
+pub fn pubkey_from_p2pk(script: &[u8]) -> Option<[u8;32]> {
+    if script.len() == 34 && script[0] == 32 && script[33] == OP_CHECKSIG {
+        script[1..33].try_into().ok()
+    } else {
+        None
+    }
+}
+
+pub fn is_pay_to_pubkey_hash(script: &[u8]) -> bool {
+    script.len() == 37 && script[..3] == [OP_DUP, OP_HASH, 32] && script[35..] == [OP_EQUALVERIFY, OP_CHECKSIG]
+}
+
+// the required signature count and keys of a script built by multisig
+pub fn parse_multisig(script: &[u8]) -> Option<(usize, Vec<[u8;32]>)> {
+    let items = instructions(script)?;
+    let [required, keys @ .., key_count, (OP_CHECKMULTISIG, _)] = items.as_slice() else {
+        return None;
+    };
+    let (required, key_count) = (read_number(*required)? as usize, read_number(*key_count)? as usize);
+    let keys: Vec<[u8;32]> = keys.iter().map(|(_, key)| (*key).try_into().ok()).collect::<Option<_>>()?;
+    if keys.len() != key_count || required > key_count {
+        return None;
+    }
+    Some((required, keys))
+}
+
+// splits a script into its opcodes and the data they push, None if a push runs past the end
+pub fn instructions(script: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut items = vec![];
+    let mut position = 0;
+    while position < script.len() {
+        let (opcode, data, next) = read_instruction(script, position).ok()?;
+        items.push((opcode, data));
+        position = next;
+    }
+    Some(items)
+}
+
+// the number an instruction written by push_number pushes
+pub fn read_number((opcode, data): (u8, &[u8])) -> Option<u32> {
+    match opcode {
+        OP_0 => Some(0),
+        OP_1..=OP_16 => Some((opcode - OP_1 + 1) as u32),
+        1..=OP_PUSHDATA2 => decode_number(data).ok(),
+        _ => None,
+    }
+}
+
+// appends the shortest push of the data
+pub fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    if data.len() < OP_PUSHDATA1 as usize {
+        script.push(data.len() as u8);
+    } else if data.len() <= u8::MAX as usize {
+        script.extend_from_slice(&[OP_PUSHDATA1, data.len() as u8]);
+    } else {
+        script.push(OP_PUSHDATA2);
+        script.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    }
+    script.extend_from_slice(data);
+}
+
+pub fn push_number(script: &mut Vec<u8>, number: u32) {
+    match number {
+        0 => script.push(OP_0),
+        1..=16 => script.push(OP_1 + number as u8 - 1),
+        _ => push_data(script, &encode_number(number)),
+    }
+}
+
+pub fn encode_number(number: u32) -> Vec<u8> {
+    let mut bytes = number.to_le_bytes().to_vec();
+    while bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    bytes
+}
+
+pub fn decode_number(bytes: &[u8]) -> Result<u32, ScriptError> {
+    if bytes.len() > MAX_NUMBER_BYTES {
+        return Err(ScriptError::NumberTooLarge);
+    }
+    Ok(bytes.iter().rev().fold(0, |number, byte| number << 8 | *byte as u32))
+}
+
+// any non zero byte makes an item true
+pub fn cast_to_bool(item: &[u8]) -> bool { item.iter().any(|byte| *byte != 0) }
+
+// human readable form of a script, pushes are shown as hex
+pub fn to_asm(script: &[u8]) -> String {
+    let mut words = vec![];
+    let mut position = 0;
+    while position < script.len() {
+        match read_instruction(script, position) {
+            Ok((opcode, data, next)) => {
+                words.push(match opcode {
+                    OP_0 => "0".to_string(),
+                    1..=OP_PUSHDATA2 => data.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                    OP_1..=OP_16 => (opcode - OP_1 + 1).to_string(),
+                    _ => opcode_name(opcode).to_string(),
+                });
+                position = next;
+            }
+            Err(_) => {
+                words.push("[error]".to_string());
+                break;
+            }
+        }
+    }
+    words.join(" ")
+}
+
+fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        OP_IF => "OP_IF",
+        OP_NOTIF => "OP_NOTIF",
+        OP_ELSE => "OP_ELSE",
+        OP_ENDIF => "OP_ENDIF",
+        OP_VERIFY => "OP_VERIFY",
+        OP_RETURN => "OP_RETURN",
+        OP_DROP => "OP_DROP",
+        OP_DUP => "OP_DUP",
+        OP_EQUAL => "OP_EQUAL",
+        OP_EQUALVERIFY => "OP_EQUALVERIFY",
+        OP_HASH => "OP_HASH",
+        OP_CHECKSIG => "OP_CHECKSIG",
+        OP_CHECKSIGVERIFY => "OP_CHECKSIGVERIFY",
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+        OP_CHECKMULTISIGVERIFY => "OP_CHECKMULTISIGVERIFY",
+        OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY",
+        _ => "OP_UNKNOWN",
+    }
+}
+
+// returns the opcode at position, the data it pushes and the position of the next instruction
+fn read_instruction(script: &[u8], position: usize) -> Result<(u8, &[u8], usize), ScriptError> {
+    let opcode = script[position];
+    let (length, start) = match opcode {
+        1..=0x4b => (opcode as usize, position + 1),
+        OP_PUSHDATA1 => (*script.get(position + 1).ok_or(ScriptError::BadPush)? as usize, position + 2),
+        OP_PUSHDATA2 => {
+            let length = script.get(position + 1..position + 3).ok_or(ScriptError::BadPush)?;
+            (u16::from_le_bytes(length.try_into().unwrap()) as usize, position + 3)
+        }
+        _ => return Ok((opcode, &[], position + 1)),
+    };
+    let data = script.get(start..start + length).ok_or(ScriptError::BadPush)?;
+    Ok((opcode, data, start + length))
+}
+
+// runs the witness of input input_index of tx against the script of the output it spends
+// the script has to leave exactly one true item on the stack
+pub fn verify(witness: &[Vec<u8>], script_pubkey: &[u8], tx: &Tx, input_index: usize) -> Result<(), ScriptError> {
+    verify_with(witness, script_pubkey, tx, input_index, &mut |key, signature, sighash, _| verify_signature(key, signature, sighash))
+}
+
+// like verify, but every well formed signature is handed to checker instead of being verified here
+// lets block validation assume signatures valid while running the scripts and check them all at once afterwards
+pub fn verify_with(witness: &[Vec<u8>], script_pubkey: &[u8], tx: &Tx, input_index: usize, checker: &mut SignatureChecker) -> Result<(), ScriptError> {
+    if script_pubkey.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+    if witness.len() > MAX_STACK_SIZE {
+        return Err(ScriptError::StackSize);
+    }
+    if witness.iter().any(|item| item.len() > MAX_ELEMENT_SIZE) {
+        return Err(ScriptError::PushSize);
+    }
+    let mut stack = witness.to_vec();
+    execute(script_pubkey, &mut stack, tx, input_index, &tx.sighash(), checker)?;
+    match stack.as_slice() {
+        [top] if cast_to_bool(top) => Ok(()),
+        [_] | [] => Err(ScriptError::EvalFalse),
+        _ => Err(ScriptError::CleanStack),
+    }
+}
+
+fn execute(script: &[u8], stack: &mut Vec<Vec<u8>>, tx: &Tx, input_index: usize, sighash: &[u8;32], checker: &mut SignatureChecker) -> Result<(), ScriptError> {
+    // one entry per open if, telling whether the branch currently being read is taken
+    let mut branches: Vec<bool> = vec![];
+    let mut ops = 0;
+    let mut position = 0;
+    while position < script.len() {
+        let (opcode, data, next) = read_instruction(script, position)?;
+        position = next;
+        if data.len() > MAX_ELEMENT_SIZE {
+            return Err(ScriptError::PushSize);
+        }
+        if opcode > OP_16 {
+            ops += 1;
+            if ops > MAX_OPS {
+                return Err(ScriptError::OpCount);
+            }
+        }
+        let executing = branches.iter().all(|taken| *taken);
+        // conditionals still have to be tracked inside skipped branches to find where they end
+        if !executing && !matches!(opcode, OP_IF | OP_NOTIF | OP_ELSE | OP_ENDIF) {
+            continue;
+        }
+        match opcode {
+            OP_0..=OP_PUSHDATA2 => stack.push(data.to_vec()),
+            OP_1..=OP_16 => stack.push(encode_number((opcode - OP_1 + 1) as u32)),
+            OP_IF | OP_NOTIF => {
+                let taken = executing && cast_to_bool(&pop(stack)?) == (opcode == OP_IF);
+                branches.push(taken);
+            }
+            OP_ELSE => {
+                let taken = branches.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                *taken = !*taken;
+            }
+            OP_ENDIF => {
+                branches.pop().ok_or(ScriptError::UnbalancedConditional)?;
+            }
+            OP_VERIFY => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(ScriptError::Verify);
+                }
+            }
+            OP_RETURN => return Err(ScriptError::OpReturn),
+            OP_DROP => {
+                pop(stack)?;
+            }
+            OP_DUP => {
+                let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                stack.push(top);
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let equal = pop(stack)? == pop(stack)?;
+                finish_check(stack, equal, opcode == OP_EQUALVERIFY, ScriptError::EqualVerify)?;
+            }
+            OP_HASH => {
+                let top = pop(stack)?;
+                stack.push(hash(&top).to_vec());
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = check_with(&signature, &key, sighash, true, checker);
+                finish_check(stack, valid, opcode == OP_CHECKSIGVERIFY, ScriptError::CheckSigVerify)?;
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                let key_count = decode_number(&pop(stack)?)? as usize;
+                if key_count > MAX_MULTISIG_KEYS {
+                    return Err(ScriptError::PubkeyCount);
+                }
+                ops += key_count;
+                if ops > MAX_OPS {
+                    return Err(ScriptError::OpCount);
+                }
+                let mut keys = pop_many(stack, key_count)?;
+                let signature_count = decode_number(&pop(stack)?)? as usize;
+                if signature_count > key_count {
+                    return Err(ScriptError::SigCount);
+                }
+                let mut signatures = pop_many(stack, signature_count)?;
+                // items come off the stack last first, put them back in script order
+                keys.reverse();
+                signatures.reverse();
+                // each signature has to match a key after the one the previous signature matched
+                // with as many signatures as keys each one can only go with its own key, so its check can wait
+                let deferrable = signature_count == key_count;
+                let mut remaining_keys = keys.iter();
+                let valid = signatures.iter().all(|signature| remaining_keys.any(|key| check_with(signature, key, sighash, deferrable, &mut *checker)));
+                finish_check(stack, valid, opcode == OP_CHECKMULTISIGVERIFY, ScriptError::CheckMultiSigVerify)?;
+            }
+            OP_CHECKLOCKTIMEVERIFY => {
+                let lock_time = decode_number(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+                check_lock_time(lock_time, tx, input_index)?;
+            }
+            _ => return Err(ScriptError::BadOpcode(opcode)),
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+    if !branches.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> { stack.pop().ok_or(ScriptError::StackUnderflow) }
+
+fn pop_many(stack: &mut Vec<Vec<u8>>, count: usize) -> Result<Vec<Vec<u8>>, ScriptError> {
+    (0..count).map(|_| pop(stack)).collect()
+}
+
+// the verify variants fail the script right away, the others push the result
+fn finish_check(stack: &mut Vec<Vec<u8>>, result: bool, verify: bool, error: ScriptError) -> Result<(), ScriptError> {
+    if verify {
+        return if result { Ok(()) } else { Err(error) };
+    }
+    stack.push(if result { vec![1] } else { vec![] });
+    Ok(())
+}
+
+// malformed keys and signatures are simply invalid, so an empty signature can stand in for a missing one
+pub fn check_signature(signature: &[u8], key: &[u8], sighash: &[u8;32]) -> bool {
+    check_with(signature, key, sighash, false, &mut |key, signature, sighash, _| verify_signature(key, signature, sighash))
+}
+
+// every way of checking a signature, alone or in a batch, has to accept exactly what this does
+pub fn verify_signature(key: &VerifyingKey, signature: &Signature, sighash: &[u8;32]) -> bool {
+    has_prime_order_points(key, signature) && key.verify_strict(sighash, signature).is_ok()
+}
+
+// with the key and R in the prime order subgroup, a batch, where torsion can cancel out by chance, accepts the same signatures a single check does
+pub fn has_prime_order_points(key: &VerifyingKey, signature: &Signature) -> bool {
+    is_prime_order_point(key.as_bytes()) && is_prime_order_point(signature.r_bytes())
+}
+
+fn check_with(signature: &[u8], key: &[u8], sighash: &[u8;32], deferrable: bool, checker: &mut SignatureChecker) -> bool {
+    let (Ok(signature), Ok(key)) = (<[u8;64]>::try_from(signature), <[u8;32]>::try_from(key)) else {
+        return false;
+    };
+    VerifyingKey::from_bytes(&key).map(|key| checker(&key, &Signature::from_bytes(&signature), sighash, deferrable)).unwrap_or(false)
+}
+
+// a canonically encoded point that isn't of small order and has no small order component
+fn is_prime_order_point(bytes: &[u8;32]) -> bool {
+    CompressedEdwardsY(*bytes).decompress()
+        .is_some_and(|point| point.compress().to_bytes() == *bytes && !point.is_small_order() && point.is_torsion_free())
+}
+
+// the tx lock time has to be the same kind of lock and at least as late, and the input can't opt out of it
+fn check_lock_time(lock_time: u32, tx: &Tx, input_index: usize) -> Result<(), ScriptError> {
+    let same_kind = (lock_time < LOCKTIME_THRESHOLD) == (tx.lock_time < LOCKTIME_THRESHOLD);
+    if !same_kind || lock_time > tx.lock_time || tx.inputs[input_index].sequence == SEQUENCE_FINAL {
+        return Err(ScriptError::UnsatisfiedLockTime);
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScriptError {
+    ScriptSize,
+    PushSize,
+    StackSize,
+    OpCount,
+    BadPush,
+    BadOpcode(u8),
+    StackUnderflow,
+    UnbalancedConditional,
+    NumberTooLarge,
+    PubkeyCount,
+    SigCount,
+    Verify,
+    EqualVerify,
+    CheckSigVerify,
+    CheckMultiSigVerify,
+    OpReturn,
+    UnsatisfiedLockTime,
+    EvalFalse,
+    CleanStack,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Input;
+    use crate::output::Output;
+    use crate::wallet::Wallet;
+
+    // a tx locked to height 100 whose only input doesn't opt out of it
+    fn tx() -> Tx {
+        let input = Input { txid: [1; 32], vout: 0, witness: vec![], sequence: SEQUENCE_FINAL - 1 };
+        Tx { txid: [0; 32], inputs: vec![input], outputs: vec![Output::to_address(1, [2; 32])], lock_time: 100 }
+    }
+
+    fn run(script: &[u8], witness: &[Vec<u8>]) -> Result<(), ScriptError> { verify(witness, script, &tx(), 0) }
+
+    #[test]
+    fn leaves_exactly_one_true_item() {
+        assert_eq!(run(&[OP_1], &[]), Ok(()));
+        assert_eq!(run(&[OP_0], &[]), Err(ScriptError::EvalFalse));
+        assert_eq!(run(&[], &[]), Err(ScriptError::EvalFalse));
+        assert_eq!(run(&[OP_1, OP_1], &[]), Err(ScriptError::CleanStack));
+        // a negative zero is still false
+        assert_eq!(run(&[], &[vec![0, 0]]), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn takes_the_branch_the_condition_picks() {
+        let script = [OP_IF, OP_1, OP_ELSE, OP_0, OP_ENDIF];
+        assert_eq!(run(&script, &[vec![1]]), Ok(()));
+        assert_eq!(run(&script, &[vec![]]), Err(ScriptError::EvalFalse));
+        assert_eq!(run(&[OP_NOTIF, OP_1, OP_ENDIF], &[vec![]]), Ok(()));
+        // a skipped branch may hold anything, even an opcode that would fail the script
+        assert_eq!(run(&[OP_0, OP_IF, OP_RETURN, 0xff, OP_ENDIF, OP_1], &[]), Ok(()));
+        assert_eq!(run(&[OP_1, OP_IF, OP_1], &[]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(&[OP_1, OP_ENDIF], &[]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(&[OP_ELSE, OP_1], &[]), Err(ScriptError::UnbalancedConditional));
+        assert_eq!(run(&[OP_IF, OP_1, OP_ENDIF], &[]), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn fails_on_verify_return_and_stack_underflow() {
+        assert_eq!(run(&[OP_0, OP_VERIFY, OP_1], &[]), Err(ScriptError::Verify));
+        assert_eq!(run(&[OP_1, OP_VERIFY, OP_1], &[]), Ok(()));
+        assert_eq!(run(&[OP_RETURN, OP_1], &[]), Err(ScriptError::OpReturn));
+        assert_eq!(run(&[OP_DROP, OP_1], &[]), Err(ScriptError::StackUnderflow));
+        assert_eq!(run(&[OP_DUP], &[]), Err(ScriptError::StackUnderflow));
+        assert_eq!(run(&[OP_DUP, OP_DROP], &[vec![1]]), Ok(()));
+    }
+
+    #[test]
+    fn compares_and_hashes_items() {
+        assert_eq!(run(&[OP_EQUAL], &[vec![7], vec![7]]), Ok(()));
+        assert_eq!(run(&[OP_EQUAL], &[vec![7], vec![8]]), Err(ScriptError::EvalFalse));
+        assert_eq!(run(&[OP_EQUALVERIFY, OP_1], &[vec![7], vec![8]]), Err(ScriptError::EqualVerify));
+        // anyone who knows the preimage of the hash can spend
+        let mut hashlock = vec![OP_HASH];
+        push_data(&mut hashlock, &hash(b"secret"));
+        hashlock.push(OP_EQUAL);
+        assert_eq!(run(&hashlock, &[b"secret".to_vec()]), Ok(()));
+        assert_eq!(run(&hashlock, &[b"guess".to_vec()]), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn checks_signatures_over_the_sighash() {
+        let wallet = Wallet::new();
+        let witness = vec![wallet.sign(&tx())];
+        assert_eq!(run(&pay_to_pubkey(&wallet.address()), &witness), Ok(()));
+        assert_eq!(run(&pay_to_pubkey(&Wallet::new().address()), &witness), Err(ScriptError::EvalFalse));
+        // a malformed signature is just false
+        assert_eq!(run(&pay_to_pubkey(&wallet.address()), &[vec![1; 10]]), Err(ScriptError::EvalFalse));
+        let mut script = pay_to_pubkey(&Wallet::new().address());
+        *script.last_mut().unwrap() = OP_CHECKSIGVERIFY;
+        script.push(OP_1);
+        assert_eq!(run(&script, &witness), Err(ScriptError::CheckSigVerify));
+        let key_hash = pay_to_pubkey_hash(&hash(&wallet.address()));
+        assert_eq!(run(&key_hash, &[witness[0].clone(), wallet.address().to_vec()]), Ok(()));
+    }
+
+    #[test]
+    fn limits_multisig_keys_and_signatures() {
+        let wallets = [Wallet::new(), Wallet::new()];
+        let keys: Vec<[u8;32]> = wallets.iter().map(Wallet::address).collect();
+        let signatures: Vec<Vec<u8>> = wallets.iter().map(|wallet| wallet.sign(&tx())).collect();
+        assert_eq!(run(&multisig(2, &keys), &signatures), Ok(()));
+        assert_eq!(run(&multisig(1, &keys), &signatures[1..]), Ok(()));
+        assert_eq!(run(&multisig(2, &keys), &[signatures[1].clone(), signatures[0].clone()]), Err(ScriptError::EvalFalse));
+        let mut too_many_signatures = vec![OP_16];
+        too_many_signatures.extend(multisig(1, &keys)[1..].iter());
+        assert_eq!(run(&too_many_signatures, &[]), Err(ScriptError::SigCount));
+        let too_many_keys = multisig(1, &vec![[1; 32]; MAX_MULTISIG_KEYS + 1]);
+        assert_eq!(run(&too_many_keys, &[vec![]]), Err(ScriptError::PubkeyCount));
+        assert_eq!(run(&[OP_CHECKMULTISIG], &[vec![1; 5]]), Err(ScriptError::NumberTooLarge));
+        assert_eq!(run(&[OP_CHECKMULTISIG], &[vec![2]]), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn checks_the_lock_time_the_script_asks_for() {
+        let mut script = vec![];
+        push_number(&mut script, 100);
+        script.push(OP_CHECKLOCKTIMEVERIFY);
+        assert_eq!(run(&script, &[]), Ok(()));
+        let mut later = vec![];
+        push_number(&mut later, 101);
+        later.push(OP_CHECKLOCKTIMEVERIFY);
+        assert_eq!(run(&later, &[]), Err(ScriptError::UnsatisfiedLockTime));
+        // a time where the tx is locked to a height, and an input opting out of the lock time
+        let mut time = vec![];
+        push_number(&mut time, LOCKTIME_THRESHOLD);
+        time.push(OP_CHECKLOCKTIMEVERIFY);
+        assert_eq!(run(&time, &[]), Err(ScriptError::UnsatisfiedLockTime));
+        let mut final_tx = tx();
+        final_tx.inputs[0].sequence = SEQUENCE_FINAL;
+        assert_eq!(verify(&[], &script, &final_tx, 0), Err(ScriptError::UnsatisfiedLockTime));
+        assert_eq!(run(&[OP_CHECKLOCKTIMEVERIFY], &[]), Err(ScriptError::StackUnderflow));
+    }
+
+    #[test]
+    fn enforces_the_resource_limits() {
+        assert_eq!(run(&vec![OP_1; MAX_SCRIPT_SIZE + 1], &[]), Err(ScriptError::ScriptSize));
+        let mut big_push = vec![];
+        push_data(&mut big_push, &[1; MAX_ELEMENT_SIZE + 1]);
+        assert_eq!(run(&big_push, &[]), Err(ScriptError::PushSize));
+        assert_eq!(run(&[OP_1], &[vec![1; MAX_ELEMENT_SIZE + 1]]), Err(ScriptError::PushSize));
+        assert_eq!(run(&vec![OP_1; MAX_STACK_SIZE + 1], &[]), Err(ScriptError::StackSize));
+        assert_eq!(run(&[], &vec![vec![1]; MAX_STACK_SIZE + 1]), Err(ScriptError::StackSize));
+        // ops count in skipped branches too
+        let mut ops = vec![OP_0, OP_IF];
+        ops.extend([OP_DROP; MAX_OPS - 1]);
+        ops.extend([OP_ENDIF, OP_1]);
+        assert_eq!(run(&ops, &[]), Err(ScriptError::OpCount));
+        ops.remove(2);
+        assert_eq!(run(&ops, &[]), Ok(()));
+        // every key of a multisig counts as an op
+        let mut multisig_ops = vec![OP_DROP; MAX_OPS - MAX_MULTISIG_KEYS];
+        multisig_ops.extend(multisig(0, &vec![[1; 32]; MAX_MULTISIG_KEYS]));
+        assert_eq!(run(&multisig_ops, &vec![vec![]; MAX_OPS - MAX_MULTISIG_KEYS]), Err(ScriptError::OpCount));
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes_and_cut_off_pushes() {
+        for opcode in [0x4e, 0x4f, 0x50, OP_CHECKLOCKTIMEVERIFY + 1, 0xff] {
+            assert_eq!(run(&[OP_1, opcode], &[]), Err(ScriptError::BadOpcode(opcode)));
+        }
+        assert_eq!(run(&[5, 1, 2], &[]), Err(ScriptError::BadPush));
+        assert_eq!(run(&[OP_PUSHDATA1], &[]), Err(ScriptError::BadPush));
+        assert_eq!(run(&[OP_PUSHDATA2, 1], &[]), Err(ScriptError::BadPush));
+        assert_eq!(to_asm(&[OP_1, 5, 1, 2]), "1 [error]");
+    }
+}
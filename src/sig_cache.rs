@@ -0,0 +1,114 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::script;
+
+// signatures already found valid, so a tx checked on mempool admission isn't checked again when its block arrives
+// entries are hashes of (pubkey, sighash, signature), the oldest is dropped once capacity is reached
+pub struct SignatureCache {
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    set: HashSet<[u8;32]>,
+    order: VecDeque<[u8;32]>,
+}
+
+impl SignatureCache {
+    pub fn new(capacity: usize) -> SignatureCache {
+        SignatureCache { capacity, entries: Mutex::new(CacheEntries::default()) }
+    }
+
+    pub fn contains(&self, key: &VerifyingKey, signature: &Signature, sighash: &[u8;32]) -> bool {
+        self.entries.lock().unwrap().set.contains(&entry(key, signature, sighash))
+    }
+
+    pub fn insert(&self, key: &VerifyingKey, signature: &Signature, sighash: &[u8;32]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = entry(key, signature, sighash);
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.set.insert(entry) {
+            return;
+        }
+        entries.order.push_back(entry);
+        if entries.order.len() > self.capacity {
+            let oldest = entries.order.pop_front().unwrap();
+            entries.set.remove(&oldest);
+        }
+    }
+
+    // verifies through the cache, remembering signatures that turn out valid
+    pub fn verify(&self, key: &VerifyingKey, signature: &Signature, sighash: &[u8;32]) -> bool {
+        if self.contains(key, signature, sighash) {
+            return true;
+        }
+        let valid = script::verify_signature(key, signature, sighash);
+        if valid {
+            self.insert(key, signature, sighash);
+        }
+        valid
+    }
+
+    pub fn len(&self) -> usize { self.entries.lock().unwrap().set.len() }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+fn entry(key: &VerifyingKey, signature: &Signature, sighash: &[u8;32]) -> [u8;32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(key.as_bytes());
+    hasher.update(sighash);
+    hasher.update(&signature.to_bytes());
+    *hasher.finalize().as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use rand::rngs::OsRng;
+
+    fn signed(sighash: &[u8;32]) -> (VerifyingKey, Signature) {
+        let key = SigningKey::generate(&mut OsRng);
+        (key.verifying_key(), key.sign(sighash))
+    }
+
+    #[test]
+    fn evicts_the_oldest_entry() {
+        let cache = SignatureCache::new(2);
+        let entries: Vec<(VerifyingKey, Signature)> = (0..3).map(|_| signed(&[1; 32])).collect();
+        entries.iter().for_each(|(key, signature)| cache.insert(key, signature, &[1; 32]));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&entries[0].0, &entries[0].1, &[1; 32]));
+        assert!(entries[1..].iter().all(|(key, signature)| cache.contains(key, signature, &[1; 32])));
+    }
+
+    #[test]
+    fn a_hit_skips_verification() {
+        let cache = SignatureCache::new(10);
+        let (key, _) = signed(&[1; 32]);
+        let (_, other) = signed(&[1; 32]);
+        assert!(!cache.verify(&key, &other, &[1; 32]));
+        assert_eq!(cache.len(), 0);
+        // only a cache that doesn't verify again could accept a signature by another key
+        cache.insert(&key, &other, &[1; 32]);
+        assert!(cache.verify(&key, &other, &[1; 32]));
+    }
+
+    #[test]
+    fn a_different_sighash_misses() {
+        let cache = SignatureCache::new(10);
+        let (key, signature) = signed(&[1; 32]);
+        assert!(cache.verify(&key, &signature, &[1; 32]));
+        assert!(cache.contains(&key, &signature, &[1; 32]));
+        assert!(!cache.contains(&key, &signature, &[2; 32]));
+        assert!(!cache.verify(&key, &signature, &[2; 32]));
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::block::{BlockError, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::chain_params::ChainParams;
+use crate::commitment::UtxoCommitment;
+use crate::global_utxos::GlobalUtxos;
+use crate::node::{self, Node};
+use crate::output::Output;
+use crate::sync::{InitialBlockDownload, Peer, SyncError};
+use crate::transactions::{take, take_vec, Tx, TxError};
+
+const SNAPSHOT_MAGIC: [u8;4] = *b"utx\xff";
+const HEADER_BYTES: usize = 124;
+
+// an unspent output along with where it was created, everything validation needs to know about it
+#[derive(Clone)]
+pub struct Coin {
+    pub txid: [u8;32],
+    pub vout: u32,
+    pub output: Output,
+    pub height: u32,
+    pub coinbase: bool,
+}
+
+impl Coin {
+    // the outputs of a tx mined at height
+    pub fn created_by(tx: &Tx, height: u32) -> Vec<Coin> {
+        tx.outputs.iter().enumerate().map(|(vout, output)| {
+            Coin { txid: tx.txid, vout: vout as u32, output: output.clone(), height, coinbase: tx.is_coinbase() }
+        }).collect()
+    }
+
+    // the outpoint, amount, script, height and coinbase flag
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&self.txid);
+        bytes.extend_from_slice(&self.vout.to_be_bytes());
+        bytes.extend_from_slice(&self.output.amount.to_be_bytes());
+        bytes.extend_from_slice(&(self.output.script_pubkey.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.output.script_pubkey);
+        bytes.extend_from_slice(&self.height.to_be_bytes());
+        bytes.push(self.coinbase as u8);
+        bytes
+    }
+}
+
+// the utxo set at a height plus the headers leading up to it, enough for a fresh node to validate the blocks after it
+pub struct UtxoSnapshot {
+    // genesis first, the block the snapshot was taken at last
+    pub headers: Vec<BlockHeader>,
+    // sorted by outpoint, so the same utxo set always encodes the same way
+    pub coins: Vec<Coin>,
+}
+
+// what a chain loaded from a snapshot keeps of it, its coins go to the chain
+pub struct SnapshotBase {
+    pub height: u32,
+    pub hash: [u8;32],
+    // None until the blocks up to the snapshot have been replayed, then whether they led to the same utxo set
+    pub validated: Option<bool>,
+}
+
+impl UtxoSnapshot {
+    // rolls utxos, the set at the tip of the chain, back to height with the undo data of the blocks after it
+    // so the blocks after height need their bodies
+    pub fn from_chain(chain: &Blockchain, height: u32) -> Result<UtxoSnapshot, SnapshotError> {
+        if height > chain.get_height() || height < chain.pruned_height {
+            return Err(SnapshotError::Unavailable);
+        }
+        let mut coins = chain.coins.clone();
+        for block in chain.chain[height as usize + 1..].iter().rev() {
+            block.transactions.iter().flat_map(|tx| Coin::created_by(tx, block.index)).for_each(|coin| {
+                coins.remove(&(coin.txid, coin.vout));
+            });
+            chain.undo[block.index as usize].iter().for_each(|coin| {
+                coins.insert((coin.txid, coin.vout), coin.clone());
+            });
+        }
+        let mut coins: Vec<Coin> = coins.into_values().collect();
+        coins.sort_by_key(|coin| (coin.txid, coin.vout));
+        Ok(UtxoSnapshot { headers: chain.chain[..=height as usize].iter().map(|block| block.header()).collect(), coins })
+    }
+
+    pub fn height(&self) -> u32 { self.headers.last().unwrap().index }
+
+    pub fn block_hash(&self) -> [u8;32] { self.headers.last().unwrap().hash }
+
+    // the commitment to the coins, the same one a node that built the set block by block has at that height
+    pub fn hash(&self) -> [u8;32] { UtxoCommitment::hash_of(&self.coins) }
+
+    // the magic and the hash, the header count and headers, then the coin count and coins
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend_from_slice(&self.hash());
+        bytes.extend_from_slice(&(self.headers.len() as u32).to_be_bytes());
+        self.headers.iter().for_each(|header| bytes.extend_from_slice(&encode_header(header)));
+        bytes.extend_from_slice(&(self.coins.len() as u32).to_be_bytes());
+        self.coins.iter().for_each(|coin| bytes.extend_from_slice(&coin.serialize()));
+        bytes
+    }
+
+    // fails if the contents don't match the hash they were saved with
+    pub fn deserialize(bytes: &[u8]) -> Result<UtxoSnapshot, SnapshotError> {
+        let mut reader = bytes;
+        if take::<4>(&mut reader)? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::Malformed);
+        }
+        let hash: [u8;32] = take(&mut reader)?;
+        let mut headers = vec![];
+        for _ in 0..u32::from_be_bytes(take(&mut reader)?) {
+            headers.push(BlockHeader {
+                index: u32::from_be_bytes(take(&mut reader)?),
+                hash: take(&mut reader)?,
+                previous_hash: take(&mut reader)?,
+                merkle_root: take(&mut reader)?,
+                time: u64::from_be_bytes(take(&mut reader)?),
+                target: u64::from_be_bytes(take(&mut reader)?),
+                nonce: u64::from_be_bytes(take(&mut reader)?),
+            });
+        }
+        let mut coins = vec![];
+        for _ in 0..u32::from_be_bytes(take(&mut reader)?) {
+            coins.push(Coin {
+                txid: take(&mut reader)?,
+                vout: u32::from_be_bytes(take(&mut reader)?),
+                output: Output { amount: u64::from_be_bytes(take(&mut reader)?), script_pubkey: take_vec(&mut reader)? },
+                height: u32::from_be_bytes(take(&mut reader)?),
+                coinbase: take::<1>(&mut reader)?[0] != 0,
+            });
+        }
+        if !reader.is_empty() || headers.is_empty() {
+            return Err(SnapshotError::Malformed);
+        }
+        let snapshot = UtxoSnapshot { headers, coins };
+        if UtxoCommitment::from_coins(&snapshot.coins).is_none() {
+            return Err(SnapshotError::TooManyCoins);
+        }
+        if snapshot.hash() != hash {
+            return Err(SnapshotError::HashMismatch);
+        }
+        Ok(snapshot)
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> { fs::write(path, self.serialize()) }
+
+    pub fn load(path: &str) -> Result<UtxoSnapshot, SnapshotError> { UtxoSnapshot::deserialize(&fs::read(path)?) }
+}
+
+// downloads and connects the blocks up to the snapshot on a chain of its own, returning the commitment to the utxo set they lead to
+// headers are the ones the snapshot came with, genesis first
+pub fn replay_history(params: &ChainParams, headers: &[BlockHeader], peers: &[Arc<dyn Peer>]) -> Result<[u8;32], SyncError> {
+    let mut chain = Blockchain::new(params.clone());
+    let mut utxos = GlobalUtxos::new();
+    InitialBlockDownload::new().download_blocks(&mut chain, &mut utxos, &headers[1..], peers)?;
+    Ok(chain.utxo_commitment.hash())
+}
+
+// checks the snapshot the node was loaded from against the real chain, returning whether they matched
+// the node is only locked before and after the replay, so this can run on its own thread while the node keeps going
+pub fn validate_history(node: &Mutex<Node>, peers: &[Arc<dyn Peer>]) -> Result<bool, SyncError> {
+    let (params, headers, expected) = {
+        let node = node::lock(node);
+        // a node that wasn't loaded from a snapshot has nothing to check
+        let Some(base) = node.chain.snapshot.as_ref() else {
+            return Ok(true);
+        };
+        let headers: Vec<BlockHeader> = node.chain.chain[..=base.height as usize].iter().map(|block| block.header()).collect();
+        (node.chain.params.clone(), headers, base.hash)
+    };
+    let matches = replay_history(&params, &headers, peers)? == expected;
+    if let Some(base) = node::lock(node).chain.snapshot.as_mut() {
+        base.validated = Some(matches);
+    }
+    Ok(matches)
+}
+
+fn encode_header(header: &BlockHeader) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_BYTES);
+    bytes.extend_from_slice(&header.index.to_be_bytes());
+    bytes.extend_from_slice(&header.hash);
+    bytes.extend_from_slice(&header.previous_hash);
+    bytes.extend_from_slice(&header.merkle_root);
+    bytes.extend_from_slice(&header.time.to_be_bytes());
+    bytes.extend_from_slice(&header.target.to_be_bytes());
+    bytes.extend_from_slice(&header.nonce.to_be_bytes());
+    bytes
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Malformed,
+    // the contents don't match the hash the snapshot was saved with
+    HashMismatch,
+    // the params don't trust a snapshot with this hash
+    Untrusted,
+    // the headers don't start at our genesis block
+    WrongNetwork,
+    InvalidHeader(BlockError),
+    // the chain doesn't have the blocks to build a snapshot at that height
+    Unavailable,
+    // the coins add up to more than the chain can have mined
+    TooManyCoins,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "could not read snapshot: {}", error),
+            SnapshotError::Malformed => write!(f, "malformed snapshot"),
+            SnapshotError::HashMismatch => write!(f, "snapshot doesn't match its hash"),
+            SnapshotError::Untrusted => write!(f, "snapshot hash isn't trusted by the params"),
+            SnapshotError::WrongNetwork => write!(f, "snapshot is for another network"),
+            SnapshotError::InvalidHeader(error) => write!(f, "invalid header: {}", error),
+            SnapshotError::Unavailable => write!(f, "the chain doesn't have the blocks for a snapshot at that height"),
+            SnapshotError::TooManyCoins => write!(f, "snapshot coins add up to more than could have been mined"),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self { SnapshotError::Io(error) }
+}
+
+impl From<TxError> for SnapshotError {
+    fn from(_: TxError) -> Self { SnapshotError::Malformed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{mine, miner};
+
+    fn snapshot_of_a_chain(params: &ChainParams) -> (UtxoSnapshot, Blockchain) {
+        let mut chain = Blockchain::new(params.clone());
+        let miner = miner([1; 32]);
+        for _ in 0..3 {
+            mine(&mut chain, &miner, vec![]).unwrap();
+        }
+        (UtxoSnapshot::from_chain(&chain, chain.get_height()).unwrap(), chain)
+    }
+
+    // a node started from the snapshot, trusting whatever hash it has
+    fn trusting_node(params: &ChainParams, snapshot: &UtxoSnapshot) -> Mutex<Node> {
+        Mutex::new(Node::from_snapshot(ChainParams { snapshot_hash: Some(snapshot.hash()), ..params.clone() }, snapshot).unwrap())
+    }
+
+    #[test]
+    fn only_loads_the_snapshot_the_params_trust() {
+        let params = ChainParams::regtest();
+        let (snapshot, _) = snapshot_of_a_chain(&params);
+        assert!(matches!(Blockchain::from_snapshot(params.clone(), &snapshot), Err(SnapshotError::Untrusted)));
+        let mut other = snapshot.hash();
+        other[0] ^= 1;
+        assert!(matches!(Blockchain::from_snapshot(ChainParams { snapshot_hash: Some(other), ..params.clone() }, &snapshot), Err(SnapshotError::Untrusted)));
+        let chain = Blockchain::from_snapshot(ChainParams { snapshot_hash: Some(snapshot.hash()), ..params }, &snapshot).unwrap();
+        assert_eq!(chain.get_height(), 3);
+        assert_eq!(chain.utxo_commitment.hash(), snapshot.hash());
+    }
+
+    #[test]
+    fn rejects_coins_adding_up_past_the_supply() {
+        let params = ChainParams::regtest();
+        let (mut snapshot, _) = snapshot_of_a_chain(&params);
+        // the three coinbases are all that was mined
+        snapshot.coins[0].output.amount += 1;
+        let trusting = ChainParams { snapshot_hash: Some(snapshot.hash()), ..params };
+        assert!(matches!(Blockchain::from_snapshot(trusting, &snapshot), Err(SnapshotError::TooManyCoins)));
+        snapshot.coins[0].output.amount = u64::MAX;
+        assert!(matches!(UtxoSnapshot::deserialize(&snapshot.serialize()), Err(SnapshotError::TooManyCoins)));
+    }
+
+    #[test]
+    fn keeps_extending_a_snapshot_the_history_leads_to() {
+        let params = ChainParams::regtest();
+        let (snapshot, chain) = snapshot_of_a_chain(&params);
+        let node = trusting_node(&params, &snapshot);
+        assert!(validate_history(&node, &[Arc::new(chain)]).unwrap());
+        let mut node = node::lock(&node);
+        assert_eq!(node.chain.snapshot.as_ref().unwrap().validated, Some(true));
+        assert!(node.mine_block([1; 32], 1).is_ok());
+    }
+
+    #[test]
+    fn stops_extending_a_snapshot_the_history_doesnt_lead_to() {
+        let params = ChainParams::regtest();
+        let (mut snapshot, chain) = snapshot_of_a_chain(&params);
+        snapshot.coins[0].output.amount -= 1;
+        let node = trusting_node(&params, &snapshot);
+        assert!(node::lock(&node).mine_block([1; 32], 1).is_ok());
+        assert!(!validate_history(&node, &[Arc::new(chain)]).unwrap());
+        let mut node = node::lock(&node);
+        assert_eq!(node.chain.snapshot.as_ref().unwrap().validated, Some(false));
+        let height = node.chain.get_height();
+        assert!(matches!(node.mine_block([1; 32], 1), Err(BlockError::InvalidSnapshot)));
+        assert_eq!(node.chain.get_height(), height);
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::block::{Block, BlockError, BlockHeader};
+use crate::blockchain::{median_time, Blockchain, MEDIAN_TIME_SPAN};
+use crate::chain_params::ChainParams;
+use crate::compact_block::{BlockTxn, BlockTxnRequest, CompactBlock, CompactBlockError};
+use crate::global_utxos::GlobalUtxos;
+use crate::node::Node;
+
+pub const HEADERS_PER_REQUEST: usize = 2000;
+pub const DOWNLOAD_WINDOW: usize = 16;
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+// anything a node can download headers and blocks from
+pub trait Peer: Send + Sync {
+    fn get_headers(&self, locator: &[[u8;32]], max: usize) -> Vec<BlockHeader>;
+    fn get_block(&self, hash: &[u8;32]) -> Option<Block>;
+    fn get_block_transactions(&self, request: &BlockTxnRequest) -> Option<BlockTxn>;
+    fn get_compact_block(&self, hash: &[u8;32]) -> Option<CompactBlock>;
+}
+
+impl Peer for Blockchain {
+    fn get_headers(&self, locator: &[[u8;32]], max: usize) -> Vec<BlockHeader> { Blockchain::get_headers(self, locator, max) }
+
+    // blocks the chain only has the header of aren't served
+    fn get_block(&self, hash: &[u8;32]) -> Option<Block> {
+        Blockchain::get_block_by_hash(self, hash).filter(|block| block.has_body()).cloned()
+    }
+
+    fn get_block_transactions(&self, request: &BlockTxnRequest) -> Option<BlockTxn> {
+        let block = Blockchain::get_block_by_hash(self, &request.hash)?;
+        let transactions = request.indexes.iter().map(|index| block.transactions.get(*index).cloned()).collect::<Option<Vec<_>>>()?;
+        Some(BlockTxn { hash: request.hash, transactions })
+    }
+
+    fn get_compact_block(&self, hash: &[u8;32]) -> Option<CompactBlock> {
+        CompactBlock::from_block(Blockchain::get_block_by_hash(self, hash)?).ok()
+    }
+}
+
+// connects the blocks a peer has past our tip, each sent as a compact block and rebuilt from our mempool
+// a peer on a longer branch that forks below our tip is followed with a reorg, returns the number of blocks connected
+pub fn relay_blocks(node: &mut Node, peer: &dyn Peer) -> Result<u32, SyncError> {
+    let headers = peer.get_headers(&node.chain.get_locator(), HEADERS_PER_REQUEST);
+    let mut blocks = vec![];
+    for header in &headers {
+        let compact = peer.get_compact_block(&header.hash).filter(|compact| compact.header.hash == header.hash)
+            .ok_or(SyncError::Relay(CompactBlockError::Unavailable))?;
+        blocks.push(compact.download(&node.pool, peer).map_err(SyncError::Relay)?);
+    }
+    match headers.first() {
+        None => Ok(0),
+        Some(first) if first.previous_hash != node.chain.get_current_hash() => {
+            node.reorganize(blocks).map_err(SyncError::InvalidBlock)?;
+            Ok(headers.len() as u32)
+        }
+        Some(_) => {
+            for block in blocks {
+                node.submit_block(block).map_err(SyncError::InvalidBlock)?;
+            }
+            Ok(headers.len() as u32)
+        }
+    }
+}
+
+// position in the headers and hash of a block a worker should fetch
+type BlockRequest = (usize, [u8;32]);
+// id of the worker's peer, position in the headers and what the peer sent
+type BlockResult = (usize, usize, Option<Block>);
+
+pub struct InitialBlockDownload {
+    pub headers_per_request: usize,
+    // how many blocks past the next block to connect may be requested at once
+    pub window: usize,
+    // a peer that takes longer than this to deliver a block is dropped, and the block is requested elsewhere
+    pub stall_timeout: Duration,
+}
+
+impl Default for InitialBlockDownload {
+    fn default() -> InitialBlockDownload { InitialBlockDownload::new() }
+}
+
+impl InitialBlockDownload {
+    pub fn new() -> InitialBlockDownload {
+        InitialBlockDownload { headers_per_request: HEADERS_PER_REQUEST, window: DOWNLOAD_WINDOW, stall_timeout: STALL_TIMEOUT }
+    }
+
+    // catches the chain up with the best header chain offered by the peers, returns the number of blocks connected
+    // a best chain forking below our tip is only switched to once all of its blocks are in
+    pub fn run(&self, chain: &mut Blockchain, utxos: &mut GlobalUtxos, peers: &[Arc<dyn Peer>]) -> Result<u32, SyncError> {
+        let mut fork_height = chain.get_height();
+        let mut best_headers = vec![];
+        let mut header_peers = vec![];
+        let mut last_error = None;
+        for peer in peers {
+            match self.download_headers(chain, peer.as_ref()) {
+                Ok((fork, headers)) => {
+                    // every block takes the same work, so the best chain is the longest
+                    if fork + headers.len() as u32 > fork_height + best_headers.len() as u32 {
+                        fork_height = fork;
+                        best_headers = headers;
+                    }
+                    header_peers.push(Arc::clone(peer));
+                }
+                // peers that send invalid headers aren't trusted for blocks either
+                Err(error) => last_error = Some(error),
+            }
+        }
+        if header_peers.is_empty() {
+            return match last_error {
+                Some(error) => Err(SyncError::InvalidHeader(error)),
+                None => Err(SyncError::NoPeers),
+            };
+        }
+        if fork_height == chain.get_height() {
+            return self.download_blocks(chain, utxos, &best_headers, &header_peers);
+        }
+        let (block_sender, blocks) = mpsc::sync_channel::<Block>(best_headers.len());
+        self.fetch_blocks(&chain.params.clone(), &best_headers, &header_peers, block_sender)?;
+        let branch: Vec<Block> = blocks.into_iter().collect();
+        chain.reorganize(branch).map_err(SyncError::InvalidBlock)?;
+        *utxos = GlobalUtxos::from_coins(chain.coins.values(), chain.get_height());
+        Ok(best_headers.len() as u32)
+    }
+
+    // the headers a peer has past the block of ours its chain forks from, along with the height of that block
+    fn download_headers(&self, chain: &Blockchain, peer: &dyn Peer) -> Result<(u32, Vec<BlockHeader>), BlockError> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        let mut fork = chain.get_tip_header();
+        let mut times = vec![];
+        loop {
+            let mut locator = chain.get_locator();
+            if let Some(last) = headers.last() {
+                locator.insert(0, last.hash);
+            }
+            let batch = peer.get_headers(&locator, self.headers_per_request);
+            if batch.is_empty() {
+                break;
+            }
+            if headers.is_empty() {
+                // the peer answers from the first locator hash it knows, which needn't be our tip
+                fork = chain.get_block_by_hash(&batch[0].previous_hash).ok_or(BlockError::BadPreviousHash)?.header();
+                // timestamps up to the fork point, so each header can be checked against the median of the ones before it
+                let start = (fork.index as usize + 1).saturating_sub(MEDIAN_TIME_SPAN);
+                times = chain.chain[start..=fork.index as usize].iter().map(|block| block.time).collect();
+            }
+            for header in batch {
+                let previous = headers.last().copied().unwrap_or(fork);
+                header.validate(&previous, &chain.params)?;
+                header.validate_time(median_time(&times), chain.clock.now(), &chain.params)?;
+                times.push(header.time);
+                headers.push(header);
+            }
+        }
+        Ok((fork.index, headers))
+    }
+
+    // blocks are connected on their own thread, so checking the hashes and bodies of the next blocks overlaps with it
+    pub fn download_blocks(&self, chain: &mut Blockchain, utxos: &mut GlobalUtxos, headers: &[BlockHeader], peers: &[Arc<dyn Peer>]) -> Result<u32, SyncError> {
+        let params = chain.params.clone();
+        thread::scope(|scope| {
+            let (block_sender, blocks) = mpsc::sync_channel::<Block>(self.window);
+            let connector = scope.spawn(move || {
+                let mut connected = 0;
+                // blocks are connected strictly in order, so the utxo set is always built on a complete chain
+                for block in blocks {
+                    chain.connect_block(block)?;
+                    utxos.find_utxos(chain);
+                    connected += 1;
+                }
+                Ok(connected)
+            });
+            let fetched = self.fetch_blocks(&params, headers, peers, block_sender);
+            // a block that failed to connect is what stopped the download, so its error comes first
+            match connector.join().unwrap() {
+                Err(error) => Err(SyncError::InvalidBlock(error)),
+                Ok(connected) => fetched.map(|_| connected),
+            }
+        })
+    }
+
+    // hands the blocks of the headers to the sender in order, stops early if the receiving end is gone
+    fn fetch_blocks(&self, params: &ChainParams, headers: &[BlockHeader], peers: &[Arc<dyn Peer>], block_sender: SyncSender<Block>) -> Result<(), SyncError> {
+        // every peer gets a worker thread, so a slow peer never blocks requests to the others
+        // the workers stop once their requests are dropped, a stalled one is waited for before returning
+        thread::scope(|scope| {
+            let (result_sender, results) = mpsc::channel::<BlockResult>();
+            let mut requests = vec![];
+            for (id, peer) in peers.iter().enumerate() {
+                let (request_sender, request_receiver) = mpsc::channel::<BlockRequest>();
+                let result_sender = result_sender.clone();
+                scope.spawn(move || {
+                    for (position, hash) in request_receiver {
+                        if result_sender.send((id, position, peer.get_block(&hash))).is_err() {
+                            break;
+                        }
+                    }
+                });
+                requests.push(Some(request_sender));
+            }
+            self.request_blocks(params, headers, &mut requests, results, block_sender)
+        })
+    }
+
+    // hands out the blocks to the workers' requests and passes on what they get back in order
+    fn request_blocks(&self, params: &ChainParams, headers: &[BlockHeader], requests: &mut [Option<Sender<BlockRequest>>],
+        results: Receiver<BlockResult>, block_sender: SyncSender<Block>) -> Result<(), SyncError> {
+        let mut next = 0;
+        let mut next_new = 0;
+        let mut retry: VecDeque<usize> = VecDeque::new();
+        // position in headers -> (peer id, time requested)
+        let mut in_flight: HashMap<usize, (usize, Instant)> = HashMap::new();
+        let mut received: BTreeMap<usize, Block> = BTreeMap::new();
+
+        while next < headers.len() {
+            // hands out one block at a time to each idle peer, never past the end of the download window
+            for (id, request) in requests.iter_mut().enumerate() {
+                if request.is_none() || in_flight.values().any(|(peer, _)| *peer == id) {
+                    continue;
+                }
+                let position = match retry.pop_front() {
+                    Some(position) => position,
+                    None if next_new < headers.len() && next_new < next + self.window => {
+                        next_new += 1;
+                        next_new - 1
+                    }
+                    None => break,
+                };
+                if request.as_ref().unwrap().send((position, headers[position].hash)).is_ok() {
+                    in_flight.insert(position, (id, Instant::now()));
+                } else {
+                    *request = None;
+                    retry.push_front(position);
+                }
+            }
+            if in_flight.is_empty() {
+                return Err(SyncError::NoPeers);
+            }
+
+            match results.recv_timeout(self.stall_timeout / 4) {
+                Ok((id, position, block)) => {
+                    // late answers for blocks that were already handed to another peer are ignored
+                    if in_flight.get(&position).map(|(peer, _)| *peer) == Some(id) {
+                        in_flight.remove(&position);
+                        match block {
+                            Some(block) if Self::matches_header(&block, &headers[position], params) => {
+                                received.insert(position, block);
+                            }
+                            // a peer that doesn't have the block or sends the wrong one is dropped
+                            _ => {
+                                requests[id] = None;
+                                retry.push_front(position);
+                            }
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(SyncError::NoPeers),
+            }
+
+            let stalled: Vec<usize> = in_flight.iter()
+                .filter(|(_, (_, requested))| requested.elapsed() > self.stall_timeout)
+                .map(|(position, _)| *position).collect();
+            for position in stalled {
+                let (id, _) = in_flight.remove(&position).unwrap();
+                requests[id] = None;
+                retry.push_front(position);
+            }
+
+            while let Some(block) = received.remove(&next) {
+                if block_sender.send(block).is_err() {
+                    return Ok(());
+                }
+                next += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn matches_header(block: &Block, header: &BlockHeader, params: &ChainParams) -> bool {
+        block.hash == header.hash && block.header().calc_hash() == header.hash && block.validate_body(params).is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    InvalidHeader(BlockError),
+    InvalidBlock(BlockError),
+    NoPeers,
+    // a relayed block couldn't be rebuilt
+    Relay(CompactBlockError),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::InvalidHeader(error) => write!(f, "invalid header: {}", error),
+            SyncError::InvalidBlock(error) => write!(f, "invalid block: {}", error),
+            SyncError::NoPeers => write!(f, "no peer has the blocks"),
+            SyncError::Relay(error) => write!(f, "could not rebuild a relayed block: {:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::fixtures::{mine, miner, params};
+    use crate::wallet::Wallet;
+
+    // serves a chain, sleeping before the blocks of the heights in delays, and remembers what it was asked for
+    struct MockPeer {
+        chain: Blockchain,
+        delays: HashMap<u32, Duration>,
+        // sends headers that don't hash to what they claim
+        tamper: bool,
+        // height of each block asked for and when
+        requests: Mutex<Vec<(u32, Instant)>>,
+        served: Mutex<Vec<(u32, Instant)>>,
+    }
+
+    impl MockPeer {
+        fn new(chain: Blockchain) -> MockPeer {
+            MockPeer { chain, delays: HashMap::new(), tamper: false, requests: Mutex::new(vec![]), served: Mutex::new(vec![]) }
+        }
+
+        fn requested_heights(&self) -> Vec<u32> { self.requests.lock().unwrap().iter().map(|(height, _)| *height).collect() }
+    }
+
+    impl Peer for MockPeer {
+        fn get_headers(&self, locator: &[[u8;32]], max: usize) -> Vec<BlockHeader> {
+            let mut headers = self.chain.get_headers(locator, max);
+            if self.tamper {
+                headers.iter_mut().skip(1).for_each(|header| header.nonce += 1);
+            }
+            headers
+        }
+
+        fn get_block(&self, hash: &[u8;32]) -> Option<Block> {
+            let height = self.chain.get_block_by_hash(hash)?.index;
+            self.requests.lock().unwrap().push((height, Instant::now()));
+            if let Some(delay) = self.delays.get(&height) {
+                thread::sleep(*delay);
+            }
+            self.served.lock().unwrap().push((height, Instant::now()));
+            Peer::get_block(&self.chain, hash)
+        }
+
+        fn get_block_transactions(&self, request: &BlockTxnRequest) -> Option<BlockTxn> { Peer::get_block_transactions(&self.chain, request) }
+
+        fn get_compact_block(&self, hash: &[u8;32]) -> Option<CompactBlock> { Peer::get_compact_block(&self.chain, hash) }
+    }
+
+    // a chain of blocks paying a throwaway wallet
+    fn chain_of(blocks: u32) -> Blockchain {
+        let mut chain = Blockchain::new(params());
+        let miner = miner(Wallet::new().address());
+        for _ in 0..blocks {
+            mine(&mut chain, &miner, vec![]).unwrap();
+        }
+        chain
+    }
+
+    // peers that each have their own copy of the chain
+    fn peers_with(chain: &Blockchain, count: usize) -> Vec<MockPeer> {
+        (0..count).map(|_| {
+            let mut copy = Blockchain::new(params());
+            chain.chain[1..].iter().for_each(|block| copy.connect_block(block.clone()).unwrap());
+            MockPeer::new(copy)
+        }).collect()
+    }
+
+    fn sync(download: &InitialBlockDownload, peers: Vec<Arc<MockPeer>>) -> (Blockchain, GlobalUtxos, Result<u32, SyncError>) {
+        let mut chain = Blockchain::new(params());
+        let mut utxos = GlobalUtxos::new();
+        let peers: Vec<Arc<dyn Peer>> = peers.into_iter().map(|peer| peer as Arc<dyn Peer>).collect();
+        let result = download.run(&mut chain, &mut utxos, &peers);
+        (chain, utxos, result)
+    }
+
+    #[test]
+    fn rejects_a_header_chain_that_doesnt_hash() {
+        let source = chain_of(3);
+        let mut peers = peers_with(&source, 2);
+        peers[0].tamper = true;
+        let liar = Arc::new(peers.remove(0));
+        let (chain, _, result) = sync(&InitialBlockDownload::new(), vec![Arc::clone(&liar)]);
+        assert!(matches!(result, Err(SyncError::InvalidHeader(BlockError::BadHash))));
+        assert_eq!(chain.get_height(), 0);
+        // with an honest peer around, the blocks come from it alone
+        let (chain, _, result) = sync(&InitialBlockDownload::new(), vec![Arc::clone(&liar), Arc::new(peers.remove(0))]);
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(chain.get_current_hash(), source.get_current_hash());
+        assert!(liar.requested_heights().is_empty());
+    }
+
+    #[test]
+    fn shares_the_blocks_between_peers() {
+        let source = chain_of(8);
+        let peers: Vec<Arc<MockPeer>> = peers_with(&source, 2).into_iter().map(|mut peer| {
+            peer.delays = (1..=8).map(|height| (height, Duration::from_millis(20))).collect();
+            Arc::new(peer)
+        }).collect();
+        let (chain, utxos, result) = sync(&InitialBlockDownload::new(), peers.clone());
+        assert_eq!(result.unwrap(), 8);
+        assert_eq!(chain.get_current_hash(), source.get_current_hash());
+        assert_eq!(utxos.coins().len(), 8);
+        let (first, second) = (peers[0].requested_heights(), peers[1].requested_heights());
+        assert!(!first.is_empty() && !second.is_empty());
+        assert_eq!(first.len() + second.len(), 8);
+    }
+
+    #[test]
+    fn keeps_requests_within_the_window() {
+        let source = chain_of(12);
+        let peers: Vec<Arc<MockPeer>> = peers_with(&source, 4).into_iter().map(|mut peer| {
+            peer.delays.insert(1, Duration::from_millis(300));
+            Arc::new(peer)
+        }).collect();
+        let download = InitialBlockDownload { window: 4, ..InitialBlockDownload::new() };
+        let (chain, _, result) = sync(&download, peers.clone());
+        assert_eq!(result.unwrap(), 12);
+        assert_eq!(chain.get_current_hash(), source.get_current_hash());
+        // while the first block is held up nothing past the window is asked for, but the window is used up
+        let first_served = peers.iter().flat_map(|peer| peer.served.lock().unwrap().clone())
+            .find(|(height, _)| *height == 1).unwrap().1;
+        let early: Vec<u32> = peers.iter().flat_map(|peer| peer.requests.lock().unwrap().clone())
+            .filter(|(_, requested)| *requested < first_served).map(|(height, _)| height).collect();
+        assert_eq!(early.iter().max(), Some(&4));
+    }
+
+    #[test]
+    fn asks_another_peer_for_a_stalled_block() {
+        let source = chain_of(4);
+        let mut peers = peers_with(&source, 2);
+        peers[0].delays = (1..=4).map(|height| (height, Duration::from_millis(400))).collect();
+        let peers: Vec<Arc<MockPeer>> = peers.into_iter().map(Arc::new).collect();
+        let download = InitialBlockDownload { stall_timeout: Duration::from_millis(100), ..InitialBlockDownload::new() };
+        let (chain, _, result) = sync(&download, peers.clone());
+        assert_eq!(result.unwrap(), 4);
+        assert_eq!(chain.get_current_hash(), source.get_current_hash());
+        // the slow peer was asked for the first block only, and was dropped before it answered
+        assert_eq!(peers[0].requested_heights(), vec![1]);
+        let mut fast = peers[1].requested_heights();
+        fast.sort();
+        assert_eq!(fast, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn follows_a_longer_chain_forking_below_the_tip() {
+        let wallet = Wallet::new();
+        let mut chain = Blockchain::new(params());
+        mine(&mut chain, &miner(wallet.address()), vec![]).unwrap();
+        let mut utxos = GlobalUtxos::new();
+        utxos.find_utxos(&chain);
+        let source = chain_of(2);
+        let peers: Vec<Arc<dyn Peer>> = peers_with(&source, 1).into_iter().map(|peer| Arc::new(peer) as Arc<dyn Peer>).collect();
+        assert_eq!(InitialBlockDownload::new().run(&mut chain, &mut utxos, &peers).unwrap(), 2);
+        assert_eq!(chain.get_current_hash(), source.get_current_hash());
+        // the coinbase of the replaced block is gone from the utxos
+        assert!(utxos.get_utxos(&wallet.script_pubkey()).is_none_or(|coins| coins.is_empty()));
+        assert_eq!(utxos.coins().len(), 2);
+        assert_eq!(InitialBlockDownload::new().run(&mut chain, &mut utxos, &peers).unwrap(), 0);
+    }
+
+    #[test]
+    fn relays_new_blocks_as_compact_blocks() {
+        let mut source = Node::new(params());
+        let address = source.wallets[0].address();
+        source.mine_block(address, 1).unwrap();
+        let mut follower = Node::new(params());
+        assert_eq!(relay_blocks(&mut follower, &source.chain).unwrap(), 1);
+        // the follower saw the payment before it was mined, so only the coinbase travels in full
+        let tx = source.send_to_address(Wallet::new().address(), 1000, 10).unwrap();
+        source.submit_tx(tx.clone()).unwrap();
+        follower.submit_tx(tx).unwrap();
+        source.mine_block(address, 1).unwrap();
+        source.mine_block(address, 1).unwrap();
+        assert_eq!(relay_blocks(&mut follower, &source.chain).unwrap(), 2);
+        assert_eq!(follower.chain.get_current_hash(), source.chain.get_current_hash());
+        assert!(follower.pool.pool.is_empty());
+        assert_eq!(relay_blocks(&mut follower, &source.chain).unwrap(), 0);
+    }
+
+    #[test]
+    fn stops_at_a_block_the_peer_cant_announce() {
+        let mut source = Blockchain::new(params());
+        let miner = miner(Wallet::new().address());
+        let block = miner.build_block(1, source.get_current_hash(), vec![], 0, &source);
+        source.connect_block(block).unwrap();
+        source.chain[1].transactions.clear();
+        let mut follower = Node::new(params());
+        assert!(matches!(relay_blocks(&mut follower, &source), Err(SyncError::Relay(CompactBlockError::Unavailable))));
+        assert_eq!(follower.chain.get_height(), 0);
+    }
+
+    #[test]
+    fn follows_a_longer_branch_with_a_reorg() {
+        let mut source = Node::new(params());
+        let address = source.wallets[0].address();
+        source.mine_block(address, 1).unwrap();
+        let mut follower = Node::new(params());
+        relay_blocks(&mut follower, &source.chain).unwrap();
+        let own = follower.wallets[0].address();
+        follower.mine_block(own, 1).unwrap();
+        assert!(follower.get_balance() > 0);
+        source.mine_block(address, 1).unwrap();
+        source.mine_block(address, 1).unwrap();
+        assert_eq!(relay_blocks(&mut follower, &source.chain).unwrap(), 2);
+        assert_eq!(follower.chain.get_current_hash(), source.chain.get_current_hash());
+        // the coinbase of the block that was replaced is gone from the wallet
+        assert_eq!(follower.get_balance(), 0);
+    }
+}
@@ -1,95 +1,160 @@
 use std::cmp::Ordering;
-
-use blake3;
-use ed25519_dalek::{Signature, Verifier, VerifyingKey};
-use num_format::{Locale, ToFormattedString};
+use std::collections::HashSet;
 
 use crate::blockchain::Blockchain;
-use crate::input::Input;
+use crate::input::{Input, SEQUENCE_FINAL};
 use crate::output::Output;
 
-#[derive(Clone, Hash)]
+// lock times below this are block heights, anything else is a unix timestamp
+pub const LOCKTIME_THRESHOLD: u32 = 500000000;
+
+#[derive(Clone)]
 pub struct Tx {
     pub txid: [u8;32],
     pub inputs: Vec<Input>,
     pub outputs: Vec<Output>,
+    // the tx can't be mined before this height or median time past, 0 means no lock
+    pub lock_time: u32,
 }
 
 impl Tx {
-    pub fn generate_txid(inputs: &Vec<Input>, outputs: &Vec<Output>) -> [u8;32]{
-        let mut hasher = blake3::Hasher::new();
-        inputs.iter().for_each(|input|{
-            hasher.update(&input.txid);
-            hasher.update(&input.signature);
-            //hasher.update(&input.timestamp.to_be_bytes());
-        });
-        outputs.iter().for_each(|output|{
-            hasher.update(&output.amount.to_be_bytes());
-            hasher.update(&output.address);
-        });
+    // the txid covers the witnesses too, which keeps coinbase txids unique through their height commitment
+    pub fn generate_txid(inputs: &[Input], outputs: &[Output], lock_time: u32) -> [u8;32]{
+        *blake3::hash(&Tx::encode(inputs, outputs, lock_time, true)).as_bytes()
+    }
 
-        *hasher.finalize().as_bytes()
+    // the message every signature in the tx signs, it leaves out the witnesses so signing one input doesn't break the others
+    pub fn sighash(&self) -> [u8;32] {
+        *blake3::hash(&Tx::encode(&self.inputs, &self.outputs, self.lock_time, false)).as_bytes()
     }
 
-    pub fn print(&self) {
-        print!("------------------------------------------------------------\nTransaction ");
-        self.txid.iter().for_each(|hex| print!("{:02x}",hex));
-        for (index, input) in self.inputs.iter().enumerate(){
-            println!("\n\nInput {index}");
-            print!("Txid: ");
-            input.txid.iter().for_each(|hex|print!("{:02x}",hex));
-            print!("\nSignature: ");
-            input.signature.iter().for_each(|hex|print!("{:02x}",hex));
-        }
-        for (index, output) in self.outputs.iter().enumerate() {
-            println!("\n\nOutput {index}");
-            println!("Amount: {}",output.amount.to_formatted_string(&Locale::en));
-            print!("Address: ");
-            output.address.iter().for_each(|hex|print!("{:02x}",hex));
+    // locks the tx until the given height or timestamp, inputs that opted out of lock time are opted back in
+    // changes the sighash, so it has to be done before signing
+    pub fn set_lock_time(&mut self, lock_time: u32) {
+        self.lock_time = lock_time;
+        self.inputs.iter_mut().filter(|input| input.sequence == SEQUENCE_FINAL).for_each(|input| input.sequence = SEQUENCE_FINAL - 1);
+        self.txid = Tx::generate_txid(&self.inputs, &self.outputs, self.lock_time);
+    }
+
+    pub fn set_sequence(&mut self, input: usize, sequence: u32) {
+        self.inputs[input].sequence = sequence;
+        self.txid = Tx::generate_txid(&self.inputs, &self.outputs, self.lock_time);
+    }
+
+    // whether the lock time allows the tx into a block at this height, with the median time past of the block before it
+    pub fn is_final(&self, height: u32, median_time_past: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
         }
-        println!("\n\nEnd Transaction ");
-        self.txid.iter().for_each(|hex| print!("{:02x}",hex));
-        println!("\n------------------------------------------------------------");
+        let unlocked = if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time < height
+        } else {
+            (self.lock_time as u64) < median_time_past
+        };
+        // the lock time is ignored if every input opted out of it
+        unlocked || self.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    // a coinbase has a single input that doesn't spend anything
+    pub fn is_coinbase(&self) -> bool { self.inputs.len() == 1 && self.inputs[0].txid == [0; 32] }
+
+    // what can be checked without the chain: the tx spends and creates something, its txid matches its contents,
+    // it doesn't spend an output twice and its output amounts don't overflow
+    pub fn check_structure(&self) -> bool {
+        let mut outpoints = HashSet::new();
+        !self.inputs.is_empty() && !self.outputs.is_empty()
+            && self.txid == Tx::generate_txid(&self.inputs, &self.outputs, self.lock_time)
+            && self.inputs.iter().all(|input| outpoints.insert((input.txid, input.vout)))
+            && self.outputs.iter().try_fold(0u64, |sum, output| sum.checked_add(output.amount)).is_some()
     }
 
     pub fn get_size(&self) -> u32{
         const TXID_BYTES: u32 = 32;
-        // inputs are always 96 bytes ( 32 bytes for txid, and 64 bytes for signature)
-        let input_bytes: u32 = self.inputs.iter().map(|_|96).sum();
-        // outputs are always 40 bytes (8 bytes for amount, 32 bytes for address)
-        let output_bytes: u32 = self.outputs.iter().map(|_|40).sum();
-        TXID_BYTES + input_bytes + output_bytes
-    }
-
-    fn calc_sum_of_inputs(&self, chain: &Blockchain) -> u64{
-        self.inputs.iter().flat_map(|input| {
-            // for each input, we scan the chain for a corresponding output
-            chain.chain.iter().flat_map(|block| {
-                block.transactions.iter().flat_map(|btx| {
-                    btx.outputs.iter().map(|out| {
-                        // if the output matches the input, and the output is being sent to the correct address, it is the value used in input
-                        if btx.txid == input.txid && VerifyingKey::from_bytes(&out.address).unwrap().verify(&input.txid, &Signature::from_bytes(&input.signature)).is_ok() {
-                            out.amount
-                        } else { 0 }
-                    })
-                })
-            })
-        }).sum()
-    }
-
-    fn calc_sum_of_outputs(&self) -> u64{
-        self.outputs.iter().map(|out|out.amount).sum()
-    }
-
-    pub fn calc_mining_fee_per_byte(&self, chain: &Blockchain) -> u64 {
-        let fee = self.calc_sum_of_inputs(chain) - self.calc_sum_of_outputs();
+        // the counts of inputs and outputs and the lock time
+        const FIXED_BYTES: u32 = 8;
+        // inputs are 42 bytes (32 bytes for txid, 4 for vout, 2 for the witness item count and 4 for sequence), plus every witness item and its 2 byte length
+        let input_bytes: u32 = self.inputs.iter().map(|input| 42 + input.witness.iter().map(|item| 2 + item.len() as u32).sum::<u32>()).sum();
+        // outputs are 10 bytes (8 bytes for amount and 2 for the script length), plus the script
+        let output_bytes: u32 = self.outputs.iter().map(|output| 10 + output.script_pubkey.len() as u32).sum();
+        TXID_BYTES + FIXED_BYTES + input_bytes + output_bytes
+    }
+
+    // raw encoding is the input count and inputs, the output count and outputs, then the lock time
+    // the txid is recomputed on decode
+    pub fn serialize(&self) -> Vec<u8> { Tx::encode(&self.inputs, &self.outputs, self.lock_time, true) }
+
+    fn encode(inputs: &[Input], outputs: &[Output], lock_time: u32, with_witness: bool) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&(inputs.len() as u16).to_be_bytes());
+        inputs.iter().for_each(|input| {
+            bytes.extend_from_slice(&input.txid);
+            bytes.extend_from_slice(&input.vout.to_be_bytes());
+            if with_witness {
+                bytes.extend_from_slice(&(input.witness.len() as u16).to_be_bytes());
+                input.witness.iter().for_each(|item| {
+                    bytes.extend_from_slice(&(item.len() as u16).to_be_bytes());
+                    bytes.extend_from_slice(item);
+                });
+            }
+            bytes.extend_from_slice(&input.sequence.to_be_bytes());
+        });
+        bytes.extend_from_slice(&(outputs.len() as u16).to_be_bytes());
+        outputs.iter().for_each(|output| {
+            bytes.extend_from_slice(&output.amount.to_be_bytes());
+            bytes.extend_from_slice(&(output.script_pubkey.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&output.script_pubkey);
+        });
+        bytes.extend_from_slice(&lock_time.to_be_bytes());
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Tx, TxError> {
+        let mut reader = bytes;
+        let mut inputs = vec![];
+        for _ in 0..u16::from_be_bytes(take(&mut reader)?) {
+            let txid = take(&mut reader)?;
+            let vout = u32::from_be_bytes(take(&mut reader)?);
+            let mut witness = vec![];
+            for _ in 0..u16::from_be_bytes(take(&mut reader)?) {
+                witness.push(take_vec(&mut reader)?);
+            }
+            inputs.push(Input { txid, vout, witness, sequence: u32::from_be_bytes(take(&mut reader)?) });
+        }
+        let mut outputs = vec![];
+        for _ in 0..u16::from_be_bytes(take(&mut reader)?) {
+            outputs.push(Output { amount: u64::from_be_bytes(take(&mut reader)?), script_pubkey: take_vec(&mut reader)? });
+        }
+        let lock_time = u32::from_be_bytes(take(&mut reader)?);
+        if !reader.is_empty() {
+            return Err(TxError::Malformed);
+        }
+        let txid = Tx::generate_txid(&inputs, &outputs, lock_time);
+        Ok(Tx { txid, inputs, outputs, lock_time })
+    }
+
+    // the amounts of the outputs the inputs spend, None if the chain doesn't have one of them
+    fn find_input_amounts(&self, chain: &Blockchain) -> Option<Vec<u64>> {
+        // for each input, we scan the chain for the output it spends
+        self.inputs.iter().map(|input| chain.find_output(&input.txid, input.vout).map(|out| out.amount)).collect()
+    }
+
+    // None when the fee is unknown or the outputs spend more than the inputs provide
+    pub fn calc_mining_fee_per_byte(&self, chain: &Blockchain) -> Option<u64> {
+        let fee = self.checked_mining_fee(chain)?;
         let size = self.get_size();
 
-        fee << 16 / size as u64
+        Some(fee.checked_mul(1 << 16)? / size as u64)
     }
 
-    pub fn calc_mining_fee(&self, chain: &Blockchain) -> u64 {
-        self.calc_sum_of_inputs(chain) - self.calc_sum_of_outputs()
+    // the fee given the amounts of the outputs the inputs spend, None if the outputs spend more or a sum overflows
+    pub fn fee_from(&self, spent_amounts: impl IntoIterator<Item = u64>) -> Option<u64> {
+        let spent = spent_amounts.into_iter().try_fold(0u64, |sum, amount| sum.checked_add(amount))?;
+        spent.checked_sub(self.outputs.iter().try_fold(0u64, |sum, output| sum.checked_add(output.amount))?)
+    }
+
+    // None when an input is unknown or the outputs spend more than the inputs provide
+    pub fn checked_mining_fee(&self, chain: &Blockchain) -> Option<u64> {
+        self.fee_from(self.find_input_amounts(chain)?)
     }
 }
 
@@ -104,7 +169,7 @@ impl Eq for Tx {}
 
 impl PartialOrd for Tx {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.txid.cmp(&other.txid)) // Compare based on transaction ID
+        Some(self.cmp(other)) // Compare based on transaction ID
     }
 }
 
@@ -113,7 +178,32 @@ impl Ord for Tx {
         self.txid.cmp(&other.txid) // Compare based on transaction ID
     }
 }
+// reads the next N bytes of a raw encoding, advancing the reader past them
+pub fn take<const N: usize>(reader: &mut &[u8]) -> Result<[u8; N], TxError> {
+    if reader.len() < N {
+        return Err(TxError::Malformed);
+    }
+    let (bytes, rest) = reader.split_at(N);
+    *reader = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+// reads a 2 byte length followed by that many bytes
+pub fn take_vec(reader: &mut &[u8]) -> Result<Vec<u8>, TxError> {
+    let len = u16::from_be_bytes(take(reader)?) as usize;
+    if reader.len() < len {
+        return Err(TxError::Malformed);
+    }
+    let (bytes, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(bytes.to_vec())
+}
+
 #[derive(Debug)]
 pub enum TxError{
     InsufficientBalance,
+    Malformed,
+    // the wallet holds none of the keys the script is locked to
+    NotASigner,
+    BadPreimage,
 }
\ No newline at end of file
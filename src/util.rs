@@ -0,0 +1,49 @@
+// hex as txids, hashes and raw txs are shown and read back, lowercase without a prefix
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix also takes a sign, so "+1" would pass as a byte
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok())).collect()
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// standard padded base64, as http basic authentication sends credentials
+pub fn to_base64(bytes: &[u8]) -> String {
+    bytes.chunks(3).flat_map(|chunk| {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| group | (*byte as u32) << (16 - 8 * i));
+        (0..4).map(move |i| if i <= chunk.len() { BASE64[(group >> (18 - 6 * i) & 63) as usize] as char } else { '=' })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_rejects_bad_hex() {
+        assert_eq!(to_hex(&[0, 0xab, 0xff]), "00abff");
+        assert_eq!(from_hex("00abff"), Some(vec![0, 0xab, 0xff]));
+        assert_eq!(from_hex("00ABFF"), Some(vec![0, 0xab, 0xff]));
+        assert_eq!(from_hex("abc"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("é0"), None);
+        assert_eq!(from_hex("+1"), None);
+        assert_eq!(from_hex("-1"), None);
+    }
+
+    #[test]
+    fn encodes_base64_with_padding() {
+        assert_eq!(to_base64(b""), "");
+        assert_eq!(to_base64(b"f"), "Zg==");
+        assert_eq!(to_base64(b"fo"), "Zm8=");
+        assert_eq!(to_base64(b"foo"), "Zm9v");
+        assert_eq!(to_base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(to_base64(b"user:pass"), "dXNlcjpwYXNz");
+    }
+}
@@ -1,117 +1,229 @@
 use ed25519_dalek::{Signer, SigningKey, };
-use ed25519_dalek::ed25519::signature::Keypair;
-use ed25519_dalek::ed25519::SignatureEncoding;
 use rand::rngs::OsRng;
 
-use crate::input::Input;
+use crate::global_utxos::Utxo;
+use crate::htlc::Htlc;
+use crate::input::{Input, SEQUENCE_FINAL};
 use crate::output::Output;
+use crate::pst::Pst;
+use crate::script;
 use crate::transactions::{Tx, TxError};
 
 #[derive(Clone)]
 pub struct Wallet {
     signing_key: SigningKey,
-    utxos: Vec<(u64,[u8;32])>,
+    // only mature utxos are kept, since those are the only ones that can be spent
+    utxos: Vec<Utxo>,
     balance: u64,
+    immature_balance: u64,
+}
+
+impl Default for Wallet {
+    fn default() -> Wallet { Wallet::new() }
 }
 
 impl Wallet {
     pub fn new() -> Self {
         let mut csprng = OsRng;  // Initialize random number generator
         let signing_key = SigningKey::generate(&mut csprng);
-        Wallet { signing_key, utxos: Vec::new(), balance: 0}
+        Wallet { signing_key, utxos: Vec::new(), balance: 0, immature_balance: 0}
     }
 
     pub fn address(&self) -> [u8;32] { self.signing_key.verifying_key().to_bytes() }
 
+    // the script outputs paid to this wallet are locked with
+    pub fn script_pubkey(&self) -> Vec<u8> { script::pay_to_pubkey(&self.address()) }
+
     pub fn get_balance(&self) -> u64 { self.balance }
 
-    pub fn calc_balance(&mut self, updated_utxos: &Vec<(u64, [u8;32])>){
-        self.utxos = updated_utxos.clone();
-        self.balance = self.utxos.iter().map(|(amount,_)|*amount).sum();
+    pub fn get_immature_balance(&self) -> u64 { self.immature_balance }
+
+    // spend_height is the height of the next block, the earliest a new transaction could be mined in
+    pub fn calc_balance(&mut self, updated_utxos: &[Utxo], spend_height: u32, maturity: u32){
+        let (mature, immature): (Vec<Utxo>, Vec<Utxo>) = updated_utxos.iter().partition(|utxo| utxo.is_mature(spend_height, maturity));
+        self.utxos = mature;
+        self.balance = self.utxos.iter().map(|utxo|utxo.amount).sum();
+        self.immature_balance = immature.iter().map(|utxo|utxo.amount).sum();
     }
 
-    fn generate_signature(&self, txid: &[u8;32]) -> [u8;64] {
-        let message: &[u8] = txid;
-        self.signing_key.sign(message).to_bytes()
+    // signs the sighash of the tx, which every input of it shares
+    pub fn sign(&self, tx: &Tx) -> Vec<u8> {
+        let message: &[u8] = &tx.sighash();
+        self.signing_key.sign(message).to_bytes().to_vec()
     }
 
-    pub fn send_amount(&mut self, amount: u64, mining_fee: u64, address: [u8;32], updated_utxos: &Vec<(u64, [u8;32])>) -> Result<Tx,TxError> {
-        self.calc_balance(updated_utxos); // updates the wallets balance and finds correct utxos
-        if self.balance >= amount + mining_fee {
+    // every input spends one of our pay to pubkey outputs, so the signature alone is the witness
+    fn build_signed_tx(&self, inputs: Vec<Input>, outputs: Vec<Output>) -> Tx {
+        let mut tx = Tx { txid: [0; 32], inputs, outputs, lock_time: 0 };
+        let signature = self.sign(&tx);
+        tx.inputs.iter_mut().for_each(|input| input.witness = vec![signature.clone()]);
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        tx
+    }
+
+    // pays to any locking script, such as a shared multisig one
+    pub fn send_to_script(&mut self, amount: u64, mining_fee: u64, script_pubkey: Vec<u8>, updated_utxos: &[Utxo], spend_height: u32, maturity: u32) -> Result<Tx,TxError> {
+        self.calc_balance(updated_utxos, spend_height, maturity); // updates the wallets balance and finds correct utxos
+        let total = amount.checked_add(mining_fee).ok_or(TxError::InsufficientBalance)?;
+        if self.balance >= total {
             let mut inputs = vec![];
             let mut outputs = vec![];
             let mut sum = 0;
             let mut utxos_needed = 0;
             // calculates how many utxos are needed to have enough total value to complete the transaction
-            for (index, (tx_amount, _)) in self.utxos.iter().enumerate() {
-                sum += *tx_amount;
-                if sum >= amount + mining_fee {
+            for (index, utxo) in self.utxos.iter().enumerate() {
+                sum += utxo.amount;
+                if sum >= total {
                     utxos_needed = index + 1;
                     break;
                 }
             }
             // generates inputs for transaction sent to recipient address
-            for (_, (_,txid)) in self.utxos.iter().enumerate().filter(|(i,_)| *i < utxos_needed) {
+            for (_, utxo) in self.utxos.iter().enumerate().filter(|(i,_)| *i < utxos_needed) {
                 let transaction_input = Input {
-                    txid: *txid,
-                    signature: self.generate_signature(&txid),
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                    witness: vec![],
+                    sequence: SEQUENCE_FINAL,
                 };
                 inputs.push(transaction_input);
             }
 
-            outputs.push(Output { amount, address });
-            // generates change if any exist
-            if sum > amount {
-                outputs.push(Output { amount: sum - amount - mining_fee, address: self.address() });
+            outputs.push(Output { amount, script_pubkey });
+            // generates change if any exist, inputs covering exactly the amount and fee need none
+            if sum > total {
+                outputs.push(Output::to_address(sum - total, self.address()));
             }
-            let txid = Tx::generate_txid(&inputs, &outputs);
-            Ok(Tx { txid, inputs, outputs })
+            Ok(self.build_signed_tx(inputs, outputs))
         }
         else {
             Err(TxError::InsufficientBalance)
         }
     }
 
-    pub fn send_amounts(&mut self, amounts: Vec<u64>, mining_fee: u64, addresses: Vec<[u8;32]>, updated_utxos: &Vec<(u64, [u8;32])>) -> Result<Tx,TxError> {
-        self.calc_balance(updated_utxos);
-        let total_amount: u64 = amounts.iter().map(|amount|*amount).sum();
-        if self.balance >= total_amount + mining_fee && (amounts.len() == addresses.len()){
+    pub fn send_amounts(&mut self, amounts: Vec<u64>, mining_fee: u64, addresses: Vec<[u8;32]>, updated_utxos: &[Utxo], spend_height: u32, maturity: u32) -> Result<Tx,TxError> {
+        self.calc_balance(updated_utxos, spend_height, maturity);
+        let total = amounts.iter().try_fold(mining_fee, |sum, amount| sum.checked_add(*amount)).ok_or(TxError::InsufficientBalance)?;
+        if self.balance >= total && (amounts.len() == addresses.len()){
             let num_addresses = amounts.len();
             let mut inputs = vec![];
             let mut outputs = vec![];
             let mut utxos_needed = 0;
             let mut sum_of_inputs = 0;
             // calculates how many utxos are needed to have enough total value to complete the transaction
-            for (index, (tx_amount, _)) in self.utxos.iter().enumerate() {
-                sum_of_inputs += *tx_amount;
-                if sum_of_inputs >= total_amount + mining_fee {
+            for (index, utxo) in self.utxos.iter().enumerate() {
+                sum_of_inputs += utxo.amount;
+                if sum_of_inputs >= total {
                     utxos_needed = index + 1;
                     break;
                 }
             }
             // generates inputs for transaction sent to recipient address
-            for (_, (_,txid)) in self.utxos.iter().enumerate().filter(|(i,_)| *i < utxos_needed) {
+            for (_, utxo) in self.utxos.iter().enumerate().filter(|(i,_)| *i < utxos_needed) {
                 let transaction_input = Input {
-                    txid: *txid,
-                    signature: self.generate_signature(&txid),
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                    witness: vec![],
+                    sequence: SEQUENCE_FINAL,
                 };
                 inputs.push(transaction_input);
             }
             for i in 0..num_addresses{
-                outputs.push(Output { amount: amounts[i], address: addresses[i] });
+                outputs.push(Output::to_address(amounts[i], addresses[i]));
             }
             // final output is change back to sender, if change exists
-            if sum_of_inputs > total_amount + mining_fee {
-                outputs.push(Output { amount: sum_of_inputs - total_amount - mining_fee, address: self.address() });
+            if sum_of_inputs > total {
+                outputs.push(Output::to_address(sum_of_inputs - total, self.address()));
             }
-            // txid is simply hash of all inputs and outputs
-            let txid = Tx::generate_txid(&inputs, &outputs);
-            Ok(Tx { txid, inputs, outputs })
+            Ok(self.build_signed_tx(inputs, outputs))
 
         }
         else{
             Err(TxError::InsufficientBalance)
         }
     }
+
+    // funds the outputs from this wallet without signing, so the tx can be signed elsewhere
+    pub fn create_pst(&mut self, outputs: Vec<Output>, mining_fee: u64, updated_utxos: &[Utxo], spend_height: u32, maturity: u32) -> Result<Pst, TxError> {
+        self.calc_balance(updated_utxos, spend_height, maturity);
+        let total_amount = outputs.iter().try_fold(mining_fee, |sum, output| sum.checked_add(output.amount)).ok_or(TxError::InsufficientBalance)?;
+        let mut sum_of_inputs = 0;
+        // takes utxos until they cover the outputs and fee
+        let utxos: Vec<Utxo> = self.utxos.iter().take_while(|utxo| {
+            let needed = sum_of_inputs < total_amount;
+            sum_of_inputs += utxo.amount;
+            needed
+        }).copied().collect();
+        let sum_of_inputs: u64 = utxos.iter().map(|utxo| utxo.amount).sum();
+        if sum_of_inputs < total_amount {
+            return Err(TxError::InsufficientBalance);
+        }
+        let inputs: Vec<Input> = utxos.iter().map(|utxo| Input { txid: utxo.txid, vout: utxo.vout, witness: vec![], sequence: SEQUENCE_FINAL }).collect();
+        let mut outputs = outputs;
+        if sum_of_inputs > total_amount {
+            outputs.push(Output::to_address(sum_of_inputs - total_amount, self.address()));
+        }
+        let txid = Tx::generate_txid(&inputs, &outputs, 0);
+        let spent = utxos.iter().map(|utxo| Output { amount: utxo.amount, script_pubkey: self.script_pubkey() }).collect();
+        Pst::new(Tx { txid, inputs, outputs, lock_time: 0 }, spent).map_err(|_| TxError::Malformed)
+    }
+
+    // locks amount in an htlc, the wallet should be the sender so it can refund after the timeout
+    pub fn create_htlc(&mut self, htlc: &Htlc, amount: u64, mining_fee: u64, updated_utxos: &[Utxo], spend_height: u32, maturity: u32) -> Result<Tx,TxError> {
+        self.send_to_script(amount, mining_fee, htlc.script_pubkey(), updated_utxos, spend_height, maturity)
+    }
+
+    // spends an htlc output to ourselves by revealing the preimage
+    pub fn claim_htlc(&self, htlc: &Htlc, utxo: &Utxo, preimage: &[u8], mining_fee: u64) -> Result<Tx,TxError> {
+        if htlc.recipient != self.address() {
+            return Err(TxError::NotASigner);
+        }
+        if script::hash(preimage) != htlc.preimage_hash {
+            return Err(TxError::BadPreimage);
+        }
+        self.spend_htlc(utxo, mining_fee, 0, SEQUENCE_FINAL, |signature| vec![signature, preimage.to_vec(), vec![1]])
+    }
+
+    // takes an htlc output back once the timeout has passed, the tx can't be mined before then
+    pub fn refund_htlc(&self, htlc: &Htlc, utxo: &Utxo, mining_fee: u64) -> Result<Tx,TxError> {
+        if htlc.sender != self.address() {
+            return Err(TxError::NotASigner);
+        }
+        // the input must not opt out of the lock time, or OP_CHECKLOCKTIMEVERIFY fails
+        self.spend_htlc(utxo, mining_fee, htlc.timeout, SEQUENCE_FINAL - 1, |signature| vec![signature, vec![]])
+    }
+
+    fn spend_htlc(&self, utxo: &Utxo, mining_fee: u64, lock_time: u32, sequence: u32, witness: impl Fn(Vec<u8>) -> Vec<Vec<u8>>) -> Result<Tx,TxError> {
+        let amount = utxo.amount.checked_sub(mining_fee).ok_or(TxError::InsufficientBalance)?;
+        let inputs = vec![Input { txid: utxo.txid, vout: utxo.vout, witness: vec![], sequence }];
+        let outputs = vec![Output::to_address(amount, self.address())];
+        let mut tx = Tx { txid: [0; 32], inputs, outputs, lock_time };
+        tx.inputs[0].witness = witness(self.sign(&tx));
+        tx.txid = Tx::generate_txid(&tx.inputs, &tx.outputs, tx.lock_time);
+        Ok(tx)
+    }
+
+    // an unsigned tx spending outputs locked to a multisig script, to be passed around the co-signers
+    pub fn create_multisig_tx(utxos: &[Utxo], outputs: Vec<Output>) -> Tx {
+        let inputs: Vec<Input> = utxos.iter().map(|utxo| Input { txid: utxo.txid, vout: utxo.vout, witness: vec![], sequence: SEQUENCE_FINAL }).collect();
+        let txid = Tx::generate_txid(&inputs, &outputs, 0);
+        Tx { txid, inputs, outputs, lock_time: 0 }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_adds_change_left_over_after_the_fee() {
+        let mut wallet = Wallet::new();
+        let utxo = Utxo { amount: 1000, txid: [1; 32], vout: 0, height: 1, coinbase: false };
+        let exact = wallet.send_to_script(990, 10, script::pay_to_pubkey(&[2; 32]), &[utxo], 2, 1).unwrap();
+        assert_eq!(exact.outputs.len(), 1);
+        let htlc = Htlc { preimage_hash: [3; 32], recipient: [2; 32], sender: wallet.address(), timeout: 10 };
+        let change = wallet.create_htlc(&htlc, 980, 10, &[utxo], 2, 1).unwrap();
+        assert_eq!(change.outputs.iter().map(|output| output.amount).collect::<Vec<u64>>(), vec![980, 10]);
+        assert!(matches!(wallet.send_to_script(991, 10, vec![], &[utxo], 2, 1), Err(TxError::InsufficientBalance)));
+    }
+}